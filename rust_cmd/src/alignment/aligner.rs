@@ -0,0 +1,407 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use bio::alignment::pairwise::Aligner as PairwiseAligner;
+use ndarray::Ix3;
+use sigalign::{ReferenceBuilder, Reference as SigalignReference};
+use sigalign::algorithms::Local;
+use sigalign::results::{Alignment as SigalignAlignment, AlignmentOperation as SigalignOperation};
+
+use crate::alignment::alignment_matrix::{Alignment, AlignmentResult, AlignmentTag, AlignmentType};
+use crate::alignment::fasta_bit_encoding::{FASTA_N, FASTA_UNSET, FastaBase};
+use crate::alignment::integer_alignment::IntegerAffineScoring;
+use crate::alignment::scoring_functions::AffineScoring;
+use crate::alignment_functions::{align_two_strings_passed_matrix, bio_to_alignment_result, simplify_cigar_string};
+use crate::linked_alignment::{align_string_with_anchors, find_greedy_non_overlapping_segments};
+use crate::read_strategies::sequence_layout::SequenceLayout;
+use crate::reference::fasta_reference::{Reference, ReferenceManager};
+
+/// sigalign won't report local alignments shorter than this (or the read, if it's shorter)
+const SIGALIGN_MINIMUM_LENGTH: u32 = 50;
+
+/// the highest mismatch rate, in sigalign's penalty per aligned base, we'll accept for a sigalign local alignment
+const SIGALIGN_MAX_MISMATCH_RATE: f32 = 0.1;
+
+/// sigalign aligners keep their working buffers between reads, so each thread holds onto one for every set of
+/// penalties and minimum length it's seen, instead of building one per read and reference
+type SigalignSettings = (u32, u32, u32, u32);
+thread_local!(static SIGALIGN_ALIGNERS: RefCell<HashMap<SigalignSettings, sigalign::Aligner<Local>>> = RefCell::new(HashMap::new()));
+
+/// A read to reference aligner. Every aligner produces an `AlignmentResult` with the reference as the first sequence
+/// and the read as the second, so anything downstream of alignment doesn't need to know which one we used
+pub trait Aligner: Send + Sync {
+    /// Align the read to one reference with that reference's scoring. The alignment matrix is the thread's
+    /// preallocated matrix (of `alignment_type()`), which aligners that keep their own buffers can ignore. Returns None
+    /// if the aligner couldn't find an alignment
+    fn align(&self,
+             reference: &Reference,
+             read_name: &String,
+             read: &Vec<FastaBase>,
+             qual_sequence: Option<Vec<u8>>,
+             scoring: &AffineScoring,
+             alignment_mat: &mut Alignment<Ix3>,
+             max_indel: &usize) -> Option<AlignmentResult>;
+
+    /// the kind of matrix each thread should preallocate for this aligner
+    fn alignment_type(&self) -> AlignmentType;
+}
+
+/// the names the layout's `aligner` entry can take
+pub const ALIGNER_NAMES: [&str; 6] = ["affine", "integer_affine", "checkpointed_affine", "anchors", "sigalign", "bio"];
+
+/// Pick the aligner the layout's `aligner` entry asks for: our affine DP by default (_affine_), the integer kernels in
+/// `integer_alignment` (_integer_affine_ and _checkpointed_affine_), the anchor-based aligner (_anchors_),
+/// sigalign's local aligner (_sigalign_), or rust-bio's pairwise aligner (_bio_)
+pub fn aligner_for_layout(read_structure: &SequenceLayout, rm: &ReferenceManager) -> Box<dyn Aligner> {
+    match read_structure.aligner.as_deref() {
+        None | Some("affine") => Box::new(AffineAligner::new(AlignmentType::Affine)),
+        Some("integer_affine") => Box::new(AffineAligner::new(AlignmentType::IntegerAffine)),
+        Some("checkpointed_affine") => Box::new(AffineAligner::new(AlignmentType::CheckpointedAffine)),
+        Some("anchors") => Box::new(AnchoredAligner {}),
+        Some("sigalign") => Box::new(SigalignAligner::new(rm)),
+        Some("bio") => Box::new(BioPairwiseAligner {}),
+        Some(aligner) => panic!("Unknown aligner {} in the read structure, we support {}", aligner, ALIGNER_NAMES.join(", ")),
+    }
+}
+
+/// Our banded global affine aligner, with the f64 matrices or one of the integer kernels depending on the matrix type
+pub struct AffineAligner {
+    alignment_type: AlignmentType,
+}
+
+impl AffineAligner {
+    pub fn new(alignment_type: AlignmentType) -> AffineAligner {
+        AffineAligner { alignment_type }
+    }
+}
+
+impl Aligner for AffineAligner {
+    fn align(&self,
+             reference: &Reference,
+             read_name: &String,
+             read: &Vec<FastaBase>,
+             qual_sequence: Option<Vec<u8>>,
+             scoring: &AffineScoring,
+             alignment_mat: &mut Alignment<Ix3>,
+             max_indel: &usize) -> Option<AlignmentResult> {
+        let ref_name = String::from_utf8(reference.name.clone()).unwrap();
+        Some(align_two_strings_passed_matrix(&ref_name, read_name, &reference.sequence, read, qual_sequence, scoring, alignment_mat, max_indel))
+    }
+
+    fn alignment_type(&self) -> AlignmentType {
+        self.alignment_type
+    }
+}
+
+/// Find exact matches between the read and the reference with the reference's suffix table, and only run the affine DP
+/// on the stretches between them (see `align_string_with_anchors`). This is always a global alignment
+pub struct AnchoredAligner {}
+
+impl Aligner for AnchoredAligner {
+    fn align(&self,
+             reference: &Reference,
+             read_name: &String,
+             read: &Vec<FastaBase>,
+             qual_sequence: Option<Vec<u8>>,
+             scoring: &AffineScoring,
+             alignment_mat: &mut Alignment<Ix3>,
+             _max_indel: &usize) -> Option<AlignmentResult> {
+        let ref_name = String::from_utf8(reference.name.clone()).unwrap();
+        let anchors = find_greedy_non_overlapping_segments(&FastaBase::vec_u8(read), &reference.sequence_u8, &reference.suffix_table);
+
+        // the long read matrix doesn't have room for the segments between anchors, so those go to its integer kernel
+        let mut aln = align_string_with_anchors(read_name, &ref_name, read, &reference.sequence, &anchors, None, scoring, alignment_mat);
+        aln.read_quals = qual_sequence;
+        Some(aln)
+    }
+
+    fn alignment_type(&self) -> AlignmentType {
+        AlignmentType::Affine
+    }
+}
+
+/// sigalign's local aligner, which finds every alignment of the read above a minimum length and below a maximum
+/// penalty per base; we report the best of them by our affine score. sigalign works in penalties rather than scores,
+/// so we convert the affine scoring with the usual score to penalty transformation: a mismatch costs the match score
+/// minus the mismatch score, and each gap base costs half a match on top of the gap extension. sigalign doesn't see base
+/// qualities, so quality weighting only applies to the score we rank its alignments by
+pub struct SigalignAligner {
+    references: HashMap<Vec<u8>, SigalignReference>,
+}
+
+impl SigalignAligner {
+    /// sigalign searches against its own index, so we build one for each reference up front. sigalign only knows exact
+    /// bases, so every IUPAC degenerate base goes in as an (ignored) N
+    pub fn new(rm: &ReferenceManager) -> SigalignAligner {
+        SigalignAligner {
+            references: rm.references.values().map(|reference| {
+                let masked = FastaBase::vec_u8(&reference.sequence.iter().map(|b| if b.is_degenerate() { FASTA_N } else { *b }).collect::<Vec<FastaBase>>());
+                let index = ReferenceBuilder::new()
+                    .set_uppercase(true)
+                    .ignore_base(b'N')
+                    .add_target(&String::from_utf8(reference.name.clone()).unwrap(), &masked)
+                    .build()
+                    .expect("Unable to build a sigalign index for the reference");
+                (reference.name.clone(), index)
+            }).collect(),
+        }
+    }
+
+    /// the sigalign penalties for our scoring, doubled so half a match score stays a whole number
+    fn penalties(scoring: &AffineScoring) -> (u32, u32, u32) {
+        let mismatch = (2.0 * (scoring.match_score - scoring.mismatch_score)).round().max(1.0) as u32;
+        let gap_open = (-2.0 * scoring.gap_open).round().max(0.0) as u32;
+        let gap_extend = (scoring.match_score - 2.0 * scoring.gap_extend).round().max(1.0) as u32;
+        (mismatch, gap_open, gap_extend)
+    }
+
+    /// turn one of sigalign's alignments into an `AlignmentResult`. The read bases outside sigalign's local alignment
+    /// are soft-clipped, so the alignment keeps the full read and its qualities
+    fn to_alignment_result(alignment: &SigalignAlignment,
+                           reference: &Reference,
+                           read_name: &String,
+                           read: &Vec<FastaBase>,
+                           qual_sequence: Option<Vec<u8>>,
+                           scoring: &AffineScoring) -> AlignmentResult {
+        let mut ref_pos = alignment.position.target.0 as usize;
+        let mut read_pos = alignment.position.query.0 as usize;
+        let leading_clip = read_pos;
+        let trailing_clip = read.len() - alignment.position.query.1 as usize;
+
+        let mut reference_aligned = vec![FASTA_UNSET; leading_clip];
+        let mut read_aligned = read[0..leading_clip].to_vec();
+        let mut cigar = Vec::new();
+        let mut score = 0.0;
+
+        for operations in &alignment.operations {
+            let count = operations.count as usize;
+            match operations.operation {
+                SigalignOperation::Match | SigalignOperation::Subst => {
+                    reference_aligned.extend_from_slice(&reference.sequence[ref_pos..ref_pos + count]);
+                    read_aligned.extend_from_slice(&read[read_pos..read_pos + count]);
+                    score += (0..count).map(|i| scoring.quality_match_mismatch(&reference.sequence[ref_pos + i], &read[read_pos + i],
+                                                                             qual_sequence.as_ref().map(|quals| &quals[read_pos + i]))).sum::<f64>();
+                    ref_pos += count;
+                    read_pos += count;
+                    cigar.push(AlignmentTag::MatchMismatch(count));
+                }
+                // sigalign's insertions and deletions are relative to the reference (its target), like ours
+                SigalignOperation::Deletion => {
+                    reference_aligned.extend_from_slice(&reference.sequence[ref_pos..ref_pos + count]);
+                    read_aligned.extend(vec![FASTA_UNSET; count]);
+                    score += scoring.gap_open + scoring.gap_extend * count as f64;
+                    ref_pos += count;
+                    cigar.push(AlignmentTag::Del(count));
+                }
+                SigalignOperation::Insertion => {
+                    reference_aligned.extend(vec![FASTA_UNSET; count]);
+                    read_aligned.extend_from_slice(&read[read_pos..read_pos + count]);
+                    score += scoring.gap_open + scoring.gap_extend * count as f64;
+                    read_pos += count;
+                    cigar.push(AlignmentTag::Ins(count));
+                }
+            }
+        }
+
+        reference_aligned.extend(vec![FASTA_UNSET; trailing_clip]);
+        read_aligned.extend_from_slice(&read[read_pos..]);
+
+        let mut cigar_string = simplify_cigar_string(&cigar);
+        if leading_clip > 0 {
+            cigar_string.insert(0, AlignmentTag::SoftClip(leading_clip));
+        }
+        if trailing_clip > 0 {
+            cigar_string.push(AlignmentTag::SoftClip(trailing_clip));
+        }
+
+        AlignmentResult {
+            reference_name: String::from_utf8(reference.name.clone()).unwrap(),
+            read_name: read_name.clone(),
+            reference_aligned,
+            read_aligned,
+            read_quals: qual_sequence,
+            cigar_string,
+            path: vec![],
+            score,
+            reference_start: alignment.position.target.0 as usize,
+            read_start: alignment.position.query.0 as usize,
+            bounding_box: None,
+        }
+    }
+}
+
+impl Aligner for SigalignAligner {
+    fn align(&self,
+             reference: &Reference,
+             read_name: &String,
+             read: &Vec<FastaBase>,
+             qual_sequence: Option<Vec<u8>>,
+             scoring: &AffineScoring,
+             _alignment_mat: &mut Alignment<Ix3>,
+             _max_indel: &usize) -> Option<AlignmentResult> {
+        let index = self.references.get(&reference.name).expect("Reference missing from the sigalign indices");
+        let (mismatch, gap_open, gap_extend) = SigalignAligner::penalties(scoring);
+        let minimum_length = SIGALIGN_MINIMUM_LENGTH.min(read.len() as u32).max(1);
+
+        let result = SIGALIGN_ALIGNERS.with(|aligners| {
+            let mut aligners = aligners.borrow_mut();
+            let aligner = aligners.entry((mismatch, gap_open, gap_extend, minimum_length)).or_insert_with(|| {
+                let algorithm = Local::new(mismatch, gap_open, gap_extend, minimum_length, SIGALIGN_MAX_MISMATCH_RATE * mismatch as f32)
+                    .expect("Unable to create the sigalign aligner from our scoring");
+                sigalign::Aligner::new(algorithm)
+            });
+            aligner.align(&FastaBase::vec_u8(read), index)
+        });
+
+        result.0.iter()
+            .flat_map(|target| target.alignments.iter())
+            .map(|alignment| SigalignAligner::to_alignment_result(alignment, reference, read_name, read, qual_sequence.clone(), scoring))
+            .max_by(|a, b| a.score.total_cmp(&b.score))
+    }
+
+    fn alignment_type(&self) -> AlignmentType {
+        AlignmentType::External
+    }
+}
+
+/// rust-bio's global (or semi-global, if the matrix asks for it) pairwise aligner. rust-bio scores with integers, so we
+/// scale our scoring the same way as the integer kernel (and reject scoring no power of two makes whole), and it has no
+/// final gap multiplier or quality weighting
+pub struct BioPairwiseAligner {}
+
+impl Aligner for BioPairwiseAligner {
+    fn align(&self,
+             reference: &Reference,
+             read_name: &String,
+             read: &Vec<FastaBase>,
+             qual_sequence: Option<Vec<u8>>,
+             scoring: &AffineScoring,
+             alignment_mat: &mut Alignment<Ix3>,
+             _max_indel: &usize) -> Option<AlignmentResult> {
+        let scale = IntegerAffineScoring::from_affine(scoring)
+            .unwrap_or_else(|| panic!("rust-bio aligns with integer scores, and the scoring {:?} can't be scaled to integers", scoring))
+            .scale as f64;
+        let match_scoring = scoring.clone();
+        let match_fn = move |a: u8, b: u8| (match_scoring.match_mismatch(&FastaBase::from(a), &FastaBase::from(b)) * scale).round() as i32;
+
+        let read_u8 = FastaBase::vec_u8(read);
+        let mut aligner = PairwiseAligner::with_capacity(read.len(), reference.sequence.len(), (scoring.gap_open * scale).round() as i32, (scoring.gap_extend * scale).round() as i32, match_fn);
+        let alignment = if alignment_mat.is_semi_global {
+            aligner.semiglobal(&read_u8, &reference.sequence_u8)
+        } else {
+            aligner.global(&read_u8, &reference.sequence_u8)
+        };
+
+        let score = alignment.score as f64 / scale;
+        let mut aln = bio_to_alignment_result(read_name, &String::from_utf8(reference.name.clone()).unwrap(), alignment, &reference.sequence, read);
+        aln.score = score;
+        aln.read_quals = qual_sequence;
+        Some(aln)
+    }
+
+    fn alignment_type(&self) -> AlignmentType {
+        AlignmentType::External
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::alignment::alignment_matrix::create_scoring_record_3d;
+    use crate::read_strategies::sequence_layout::{AlignedReadOrientation, ReadPosition};
+
+    fn layout_with_aligner(aligner: &str) -> SequenceLayout {
+        SequenceLayout {
+            aligner: Some(aligner.to_string()),
+            merge: None,
+            reads: vec![ReadPosition::Read1 { chain_align: None, orientation: AlignedReadOrientation::Forward }],
+            known_strand: true,
+            references: BTreeMap::new(),
+            scoring: None,
+            reference_fasta: None,
+        }
+    }
+
+    #[test]
+    fn test_every_aligner_recovers_an_exact_read() {
+        let rm = ReferenceManager::from_fa_file(&"test_data/two_references_just_one.fa".to_string(), 8, 4);
+        let reference = rm.references.get(&0).unwrap();
+        let read = reference.sequence[20..220].to_vec();
+        let read_name = "read".to_string();
+
+        for name in ALIGNER_NAMES {
+            let aligner = aligner_for_layout(&layout_with_aligner(name), &rm);
+            let mut alignment_mat = create_scoring_record_3d(reference.sequence.len() + 1, read.len() + 1, aligner.alignment_type(), false);
+            let aln = aligner.align(reference, &read_name, &read, None, &AffineScoring::default_reference_alignment(), &mut alignment_mat, &read.len())
+                .expect(name);
+
+            assert_eq!(FastaBase::string(&aln.read_aligned).replace("-", ""), FastaBase::string(&read), "{}", name);
+            assert_eq!(aln.reference_name.as_bytes(), reference.name.as_slice(), "{}", name);
+            assert!(aln.cigar_string.contains(&AlignmentTag::MatchMismatch(200)), "{} gave {:?}", name, aln.cigar_string);
+        }
+    }
+
+    #[test]
+    fn test_anchored_aligner_long_read_matrix() {
+        let rm = ReferenceManager::from_fa_file(&"test_data/two_references_just_one.fa".to_string(), 8, 4);
+        let reference = rm.references.get(&0).unwrap();
+        let mut read = reference.sequence[20..120].to_vec();
+        read.extend(FastaBase::from_str("TTT"));
+        read.extend_from_slice(&reference.sequence[126..220]);
+        read.extend(FastaBase::from_str("GATTACA"));
+        let read_name = "read".to_string();
+        let scoring = AffineScoring::default_reference_alignment();
+        let aligner = aligner_for_layout(&layout_with_aligner("anchors"), &rm);
+
+        // the stretches between anchors go through the integer kernels for the buffer-free long read matrix, and come out the same
+        let mut alignment_mat = create_scoring_record_3d(reference.sequence.len() + 1, read.len() + 1, AlignmentType::Affine, false);
+        let expected = aligner.align(reference, &read_name, &read, None, &scoring, &mut alignment_mat, &read.len()).unwrap();
+        for alignment_type in [AlignmentType::CheckpointedAffine, AlignmentType::IntegerAffine] {
+            let mut long_read_mat = create_scoring_record_3d(0, 0, alignment_type, false);
+            let aln = aligner.align(reference, &read_name, &read, None, &scoring, &mut long_read_mat, &read.len()).unwrap();
+            assert_eq!(aln.cigar_string, expected.cigar_string);
+            assert_eq!(aln.score, expected.score);
+        }
+    }
+
+    #[test]
+    fn test_sigalign_soft_clips_unaligned_read_ends() {
+        let rm = ReferenceManager::from_fa_file(&"test_data/two_references_just_one.fa".to_string(), 8, 4);
+        let reference = rm.references.get(&0).unwrap();
+        let read_name = "read".to_string();
+
+        // the middle of the read comes from the reference, the ends from nowhere in particular
+        let mut read = FastaBase::from_str("CCCCCCCCCCCCCCCCCCCCCCCCCCCCCC");
+        read.extend_from_slice(&reference.sequence[20..220]);
+        read.extend(FastaBase::from_str("GGGGGGGGGGGGGGGGGGGGGGGGGGGGGG"));
+        let quals = vec![b'I'; read.len()];
+
+        let aligner = aligner_for_layout(&layout_with_aligner("sigalign"), &rm);
+        let mut alignment_mat = create_scoring_record_3d(0, 0, aligner.alignment_type(), false);
+        let aln = aligner.align(reference, &read_name, &read, Some(quals.clone()), &AffineScoring::default_reference_alignment(), &mut alignment_mat, &read.len())
+            .unwrap();
+
+        assert!(matches!(aln.cigar_string.first(), Some(AlignmentTag::SoftClip(_))), "{:?}", aln.cigar_string);
+        assert!(matches!(aln.cigar_string.last(), Some(AlignmentTag::SoftClip(_))), "{:?}", aln.cigar_string);
+        let read_bases: usize = aln.cigar_string.iter().map(|tag| match tag {
+            AlignmentTag::MatchMismatch(size) | AlignmentTag::Ins(size) | AlignmentTag::SoftClip(size) => *size,
+            _ => 0,
+        }).sum();
+        assert_eq!(read_bases, read.len());
+
+        // SEQ and QUAL both cover the whole read
+        let record = aln.to_sam_record(&0, &HashMap::new(), None);
+        assert_eq!(record.sequence().len(), read.len());
+        assert_eq!(record.quality_scores().len(), quals.len());
+        assert_eq!(aln.read_quals, Some(quals));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unknown_aligner() {
+        let rm = ReferenceManager::from_fa_file(&"test_data/two_references_just_one.fa".to_string(), 8, 4);
+        aligner_for_layout(&layout_with_aligner("blast"), &rm);
+    }
+}
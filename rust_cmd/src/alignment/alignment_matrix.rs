@@ -1,3 +1,4 @@
+use std::cell::RefCell;
 use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::fmt;
@@ -26,7 +27,7 @@ use noodles_sam::alignment::record::cigar::op::Kind;
 
 use crate::alignment_manager::simplify_cigar_string;
 use noodles_sam::alignment::record::cigar::Op as Op;
-use noodles_sam::alignment::record::Flags;
+use noodles_sam::alignment::record::{Flags, MappingQuality};
 use noodles_sam::alignment::record_buf::Cigar as CigarBuf;
 
 pub const MAX_NEG_SCORE: f64 = -100000.0;
@@ -77,7 +78,7 @@ impl From<u8> for AlignmentTag {
 impl From<Op> for AlignmentTag {
     fn from(value: Op) -> Self {
         match value.kind() {
-            Kind::Match => { AlignmentTag::MatchMismatch(value.len()) }
+            Kind::Match | Kind::SequenceMatch | Kind::SequenceMismatch => { AlignmentTag::MatchMismatch(value.len()) }
             Kind::Deletion => { AlignmentTag::Del(value.len()) }
             Kind::Insertion => { AlignmentTag::Ins(value.len()) }
             Kind::SoftClip => { AlignmentTag::SoftClip(value.len()) }
@@ -126,6 +127,12 @@ pub enum AlignmentType {
     InversionAwareSimple,
     AnchoredInversionAwareSimple,
     AnchoredAffineInversion,
+    /// affine alignment with the integer kernel in `integer_alignment`, which keeps its own compact buffers
+    IntegerAffine,
+    /// global affine alignment with the linear memory integer kernel, for reads too long for the full traceback
+    CheckpointedAffine,
+    /// an aligner with its own DP and buffers (sigalign, rust-bio), which doesn't use our matrices at all
+    External,
 }
 
 #[derive(Eq, PartialEq, Debug, Copy, Clone, Hash)]
@@ -217,14 +224,50 @@ pub struct Alignment<K> {
     pub traceback: Array<AlignmentDirection, K>,
     pub alignment_type: AlignmentType,
     pub is_local: bool,
+    /// semi-global (ends-free) alignment: the whole read has to align, but gaps before and after it in the reference
+    /// are free, and the alignment starts at `reference_start` instead of with leading deletions
+    pub is_semi_global: bool,
+    /// where indels that could sit at several offsets in a repeat with the same score go; `align_to_reference` applies
+    /// this to every aligner's result
+    pub gap_placement: GapPlacement,
+}
+
+/// Where to put an indel that could shift along a homopolymer or repeat without changing the alignment score. Picking
+/// one end consistently (like VCF normalization) gives identical edits identical CIGARs in every read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GapPlacement {
+    /// wherever the DP's tie-breaking put it
+    AsAligned,
+    /// as far towards the start of the reference as it can go
+    Left,
+    /// as far towards the end of the reference as it can go
+    Right,
+}
+
+impl GapPlacement {
+    pub fn from_name(name: &str) -> GapPlacement {
+        match name.to_lowercase().as_str() {
+            "none" | "as_aligned" => GapPlacement::AsAligned,
+            "left" => GapPlacement::Left,
+            "right" => GapPlacement::Right,
+            _ => panic!("Unknown gap placement {}; choose none, left, or right", name),
+        }
+    }
 }
 
 pub fn create_scoring_record_3d(hint_seq_a_len: usize, hint_seq_b_len: usize, alignment_type: AlignmentType, local_alignment: bool) -> Alignment<Ix3> {
+    // the integer kernels and external aligners don't use the score and traceback cubes, so don't allocate them
+    let (hint_seq_a_len, hint_seq_b_len) = match alignment_type {
+        AlignmentType::IntegerAffine | AlignmentType::CheckpointedAffine | AlignmentType::External => (0, 0),
+        _ => (hint_seq_a_len, hint_seq_b_len),
+    };
     Alignment {
         scores: Array::<f64, Ix3>::zeros((hint_seq_a_len, hint_seq_b_len, 3).f()),
         traceback: Array::<AlignmentDirection, Ix3>::zeros((hint_seq_a_len, hint_seq_b_len, 3).f()),
         alignment_type,
         is_local: local_alignment,
+        is_semi_global: false,
+        gap_placement: GapPlacement::AsAligned,
     }
 }
 
@@ -274,8 +317,8 @@ fn update_sub_vector3d(alignment: &mut Alignment<Ix3>,
     let mut still_updating = true;
     let mut update_count = 0;
 
-    while row_pos < alignment.scores.shape()[0] && col_pos < alignment.scores.shape()[1] && still_updating {
-        let updates = update_3d_score_local(alignment, sequence1, sequence2, scoring_function, row_pos, col_pos);
+    while row_pos <= sequence1.len() && col_pos <= sequence2.len() && still_updating {
+        let updates = update_3d_score_local(alignment, sequence1, sequence2, None, scoring_function, row_pos, col_pos);
 
         let any_update = updates.0 || updates.1 || updates.2;
         if any_update {
@@ -346,8 +389,8 @@ fn clean_and_find_next_best_match_3d(alignment: &mut Alignment<Ix3>,
     let mut still_updating_rows = true;
     let mut still_updating_cols = true;
     while (still_updating_rows || still_updating_cols) &&
-        current_row < alignment.scores.shape()[0] &&
-        current_col < alignment.scores.shape()[1] {
+        current_row <= sequence1.len() &&
+        current_col <= sequence2.len() {
         let row_update_count = update_sub_vector3d(alignment, sequence1, sequence2, scoring_function, current_row, current_col, true);
         let col_update_count = update_sub_vector3d(alignment, sequence1, sequence2, scoring_function, current_row, current_col, false);
         still_updating_rows = row_update_count > 0;
@@ -364,17 +407,22 @@ pub fn perform_affine_alignment(alignment: &mut Alignment<Ix3>,
                                 sequence2: &[FastaBase],
                                 scoring_function: &AffineScoring) {
     let new_bandwidth = max(sequence1.len(), sequence2.len());
-    perform_affine_alignment_bandwidth(alignment, sequence1, sequence2, scoring_function, &new_bandwidth)
+    perform_affine_alignment_bandwidth(alignment, sequence1, sequence2, None, scoring_function, &new_bandwidth)
 }
 
 
-/// Affine matrix dimensions are row,column,dimension, where dim 1 is match, dim 2 is deletion (relative to read, sequence2) and dim 3 is insertion
+/// Affine matrix dimensions are row,column,dimension, where dim 1 is match, dim 2 is deletion (relative to read, sequence2) and dim 3 is insertion.
+/// If the scoring weights by quality, `sequence2_quality` (FASTQ quality characters for the read) scales each match and mismatch
 pub fn perform_affine_alignment_bandwidth(alignment: &mut Alignment<Ix3>,
                                           sequence1: &[FastaBase],
                                           sequence2: &[FastaBase],
+                                          sequence2_quality: Option<&[u8]>,
                                           scoring_function: &AffineScoring,
                                           bandwidth: &usize) {
     assert_eq!(alignment.scores.shape()[2], 3);
+    if let Some(quality) = sequence2_quality {
+        assert_eq!(quality.len(), sequence2.len(), "The read has {} quality scores for {} bases", quality.len(), sequence2.len());
+    }
     assert!(alignment.scores.shape()[0] > sequence1.len(), "Asked to align sequence 1 with length {} in a matrix sized {} in that dimension, sequence {}", sequence1.len() + 1, alignment.scores.shape()[0], FastaBase::string_from_slice(sequence1));
     assert!(alignment.scores.shape()[1] > sequence2.len(), "Asked to align sequence 2 with length {} in a matrix sized {} in that dimension, sequence {}", sequence2.len() + 1, alignment.scores.shape()[1], FastaBase::string_from_slice(sequence2));
 
@@ -386,7 +434,12 @@ pub fn perform_affine_alignment_bandwidth(alignment: &mut Alignment<Ix3>,
     for x in 1..(sequence1.len() + 1) {
         alignment.scores[[x, 0, 0]] = MAX_NEG_SCORE;
         alignment.traceback[[x, 0, 0]] = AlignmentDirection::Up(1);
-        alignment.scores[[x, 0, 1]] = (scoring_function.gap_open() + (x as f64 * scoring_function.gap_extend())) * scoring_function.final_gap_multiplier();
+        // skipping the start of the reference is free in semi-global alignment
+        alignment.scores[[x, 0, 1]] = if alignment.is_semi_global && !alignment.is_local {
+            0.0
+        } else {
+            (scoring_function.gap_open() + (x as f64 * scoring_function.gap_extend())) * scoring_function.final_gap_multiplier()
+        };
         alignment.traceback[[x, 0, 1]] = AlignmentDirection::Up(1);
         alignment.scores[[x, 0, 2]] = (scoring_function.gap_open() + (x as f64 * scoring_function.gap_extend())) * scoring_function.final_gap_multiplier();
         alignment.traceback[[x, 0, 2]] = AlignmentDirection::Up(1);
@@ -406,37 +459,51 @@ pub fn perform_affine_alignment_bandwidth(alignment: &mut Alignment<Ix3>,
         false => { update_3d_score }
     };
 
+    // a semi-global read can sit anywhere along the reference, off the diagonal, so it gets the whole matrix
+    let semi_global = alignment.is_semi_global && !alignment.is_local;
     for x in 1..(sequence1.len() + 1) {
-        let y_bounds = ((x as f64 / (sequence1.len() + 1) as f64) * (sequence2.len() + 1) as f64) as i64;
-        //println!("y bounds {} {} {}",y_bounds,(x as f64 / (sequence1.len() + 1) as f64),(sequence2.len() + 1) as f64);
-
-        let y_bounds = (max(1, y_bounds - (*bandwidth as i64)), min(sequence2.len() as i64 + 1, y_bounds + (*bandwidth as i64)));
+        let y_bounds = if semi_global {
+            (1, sequence2.len() + 1)
+        } else {
+            diagonal_band(x, sequence1.len(), sequence2.len(), bandwidth)
+        };
         //println!("y bounds {} {} from x = {} bandwidth = {}",y_bounds.0,y_bounds.1,x,bandwidth);
-        assert!(y_bounds.0 >= 0);
-        assert!(y_bounds.1 >= 0);
         for y in (y_bounds.0)..(y_bounds.1) {
-            update_function(alignment, sequence1, sequence2, scoring_function, x, y as usize);
+            update_function(alignment, sequence1, sequence2, sequence2_quality, scoring_function, x, y);
         }
     }
 }
 
-/// Affine matrix dimensions are row,column,dimension, where dim 1 is match, dim 2 is deletion (relative to read, sequence2) and dim 3 is insertion
+/// the columns [start, end) of row `x` we fill for a banded alignment: `bandwidth` either side of the diagonal from the
+/// top left to the bottom right corner of the matrix
+pub(crate) fn diagonal_band(x: usize, sequence1_length: usize, sequence2_length: usize, bandwidth: &usize) -> (usize, usize) {
+    let y_bounds = ((x as f64 / (sequence1_length + 1) as f64) * (sequence2_length + 1) as f64) as i64;
+    let start = max(1, y_bounds - (*bandwidth as i64));
+    let end = min(sequence2_length as i64 + 1, y_bounds + (*bandwidth as i64));
+    if start < end { (start as usize, end as usize) } else { (1, 1) }
+}
+
+/// Affine matrix dimensions are row,column,dimension, where dim 1 is match, dim 2 is deletion (relative to read, sequence2) and dim 3 is insertion.
+/// Only the diagonal band of each row is filled, so the matrix can be larger than the sequences and reused between
+/// alignments: the cells just outside the band, which the next row reads, are reset rather than left from an earlier
+/// alignment
 #[allow(dead_code)]
 fn perform_inversion_aware_alignment(alignment: &mut Alignment<Ix3>,
                                      alignment_inversion: &HashMap<AlignmentLocation, BoundedAlignment>,
                                      sequence1: &Vec<FastaBase>,
                                      sequence2: &Vec<FastaBase>,
-                                     scoring_function: &InversionScoring) {
+                                     scoring_function: &InversionScoring,
+                                     bandwidth: &usize) {
     assert_eq!(alignment.scores.shape()[2], 3);
-    assert_eq!(alignment.scores.shape()[0], sequence1.len() + 1);
-    assert_eq!(alignment.scores.shape()[1], sequence2.len() + 1);
+    assert!(alignment.scores.shape()[0] > sequence1.len(), "Asked to align sequence 1 with length {} in a matrix sized {} in that dimension", sequence1.len() + 1, alignment.scores.shape()[0]);
+    assert!(alignment.scores.shape()[1] > sequence2.len(), "Asked to align sequence 2 with length {} in a matrix sized {} in that dimension", sequence2.len() + 1, alignment.scores.shape()[1]);
 
     alignment.scores[[0, 0, 0]] = 0.0;
     alignment.scores[[0, 0, 1]] = MAX_NEG_SCORE;
     alignment.scores[[0, 0, 2]] = MAX_NEG_SCORE;
 
     // first column (going down)
-    for x in 1..alignment.scores.shape()[0] {
+    for x in 1..(sequence1.len() + 1) {
         alignment.scores[[x, 0, 0]] = MAX_NEG_SCORE;
         alignment.traceback[[x, 0, 0]] = AlignmentDirection::Up(1);
         alignment.scores[[x, 0, 1]] = scoring_function.gap_open() + (x as f64 * scoring_function.gap_extend());
@@ -445,7 +512,7 @@ fn perform_inversion_aware_alignment(alignment: &mut Alignment<Ix3>,
         alignment.traceback[[x, 0, 2]] = AlignmentDirection::Up(1);
     }
     // top row
-    for y in 1..alignment.scores.shape()[1] {
+    for y in 1..(sequence2.len() + 1) {
         alignment.scores[[0, y, 0]] = MAX_NEG_SCORE;
         alignment.traceback[[0, y, 0]] = AlignmentDirection::Left(1);
         alignment.scores[[0, y, 1]] = scoring_function.gap_open() + (y as f64 * scoring_function.gap_extend());
@@ -454,8 +521,16 @@ fn perform_inversion_aware_alignment(alignment: &mut Alignment<Ix3>,
         alignment.traceback[[0, y, 2]] = AlignmentDirection::Left(1);
     }
 
-    for x in 1..alignment.scores.shape()[0] {
-        for y in 1..alignment.scores.shape()[1] {
+    for x in 1..(sequence1.len() + 1) {
+        let (start, end) = diagonal_band(x, sequence1.len(), sequence2.len(), bandwidth);
+        // this row reads the cell left of the band, and the next row reads up to the end of its own band
+        let next_end = if x < sequence1.len() { diagonal_band(x + 1, sequence1.len(), sequence2.len(), bandwidth).1 } else { end };
+        for y in (max(1, start.saturating_sub(1))..start).chain(end..next_end) {
+            for z in 0..3 {
+                alignment.scores[[x, y, z]] = MAX_NEG_SCORE;
+            }
+        }
+        for y in start..end {
             update_inversion_alignment(alignment, alignment_inversion, sequence1, sequence2, scoring_function, x, y);
         }
     }
@@ -556,7 +631,7 @@ fn update_inversion_alignment(alignment: &mut Alignment<Ix3>,
 }
 
 #[inline(always)]
-fn update_3d_score_local(alignment: &mut Alignment<Ix3>, sequence1: &[FastaBase], sequence2: &[FastaBase], scoring_function: &AffineScoring, x: usize, y: usize) -> (bool, bool, bool) {
+fn update_3d_score_local(alignment: &mut Alignment<Ix3>, sequence1: &[FastaBase], sequence2: &[FastaBase], sequence2_quality: Option<&[u8]>, scoring_function: &AffineScoring, x: usize, y: usize) -> (bool, bool, bool) {
     let mut _update_x = false;
     let mut _update_y = false;
     let mut _update_z = false;
@@ -566,7 +641,7 @@ fn update_3d_score_local(alignment: &mut Alignment<Ix3>, sequence1: &[FastaBase]
 
     {
         // match-mismatch matrix update
-        let match_score = scoring_function.match_mismatch(&sequence1[x - 1], &sequence2[y - 1]);
+        let match_score = scoring_function.quality_match_mismatch(&sequence1[x - 1], &sequence2[y - 1], sequence2_quality.map(|quality| &quality[y - 1]));
 
         let max_match_mismatch = three_way_max_and_direction(
             &(if alignment.is_local { 0.0 } else { MAX_NEG_SCORE }),
@@ -607,7 +682,7 @@ fn update_3d_score_local(alignment: &mut Alignment<Ix3>, sequence1: &[FastaBase]
 }
 
 #[inline(always)]
-fn update_3d_score(alignment: &mut Alignment<Ix3>, sequence1: &[FastaBase], sequence2: &[FastaBase], scoring_function: &AffineScoring, x: usize, y: usize) -> (bool, bool, bool) {
+fn update_3d_score(alignment: &mut Alignment<Ix3>, sequence1: &[FastaBase], sequence2: &[FastaBase], sequence2_quality: Option<&[u8]>, scoring_function: &AffineScoring, x: usize, y: usize) -> (bool, bool, bool) {
     let mut _update_x = false;
     let mut _update_y = false;
     let mut _update_z = false;
@@ -618,7 +693,7 @@ fn update_3d_score(alignment: &mut Alignment<Ix3>, sequence1: &[FastaBase], sequ
 
     {
         // match-mismatch matrix update
-        let match_score = scoring_function.match_mismatch(&sequence1[x - 1], &sequence2[y - 1]);
+        let match_score = scoring_function.quality_match_mismatch(&sequence1[x - 1], &sequence2[y - 1], sequence2_quality.map(|quality| &quality[y - 1]));
 
         let best_match = three_way_max_and_direction(
             &(alignment.scores[[x - 1, y - 1, 1]] + match_score),
@@ -722,43 +797,370 @@ impl AlignmentResult {
         }
     }
 
+    /// Slide every internal indel as far left or right as it can go without changing the aligned bases, the way VCF
+    /// normalization does: a gap can move one base over when the base it would uncover matches the base it covers, so
+    /// the score doesn't change (with quality weighting, a shifted insertion pairs the reference with a different
+    /// but identical read base). Gaps at either end of the alignment and alignments with clips or inversions are left
+    /// where they are, and the path is shifted to follow the new columns
+    pub fn normalize_gaps(mut self, placement: GapPlacement) -> AlignmentResult {
+        let normalizable = self.cigar_string.iter().all(|tag| matches!(tag, AlignmentTag::MatchMismatch(_) | AlignmentTag::Del(_) | AlignmentTag::Ins(_)));
+        if placement == GapPlacement::AsAligned || !normalizable || self.reference_aligned.len() != self.read_aligned.len() {
+            return self;
+        }
+        let columns = self.reference_aligned.len();
+
+        // runs of deletions (gaps in the read) or insertions (gaps in the reference), as [start, end) column ranges
+        let gap_kind = |reference: &[FastaBase], read: &[FastaBase], column: usize| -> u8 {
+            match (reference[column].identity(&FASTA_UNSET), read[column].identity(&FASTA_UNSET)) {
+                (false, false) => 0,
+                (false, true) => 1,
+                _ => 2,
+            }
+        };
+
+        let mut column = 0;
+        let mut runs = Vec::new();
+        while column < columns {
+            let kind = gap_kind(&self.reference_aligned, &self.read_aligned, column);
+            let start = column;
+            while column < columns && gap_kind(&self.reference_aligned, &self.read_aligned, column) == kind {
+                column += 1;
+            }
+            if kind != 0 {
+                runs.push((start, column, kind));
+            }
+        }
+        if placement == GapPlacement::Right {
+            runs.reverse();
+        }
+
+        for (mut start, mut end, kind) in runs {
+            // the row with the gaps, and the row whose bases have to match for the gap to move
+            let (gapped, other) = if kind == 1 {
+                (&mut self.read_aligned, &self.reference_aligned)
+            } else {
+                (&mut self.reference_aligned, &self.read_aligned)
+            };
+            let aligned_pair = |gapped: &Vec<FastaBase>, column: usize| !gapped[column].identity(&FASTA_UNSET) && !other[column].identity(&FASTA_UNSET);
+
+            match placement {
+                GapPlacement::Left => {
+                    while start >= 2 && end < columns && aligned_pair(gapped, start - 1) && aligned_pair(gapped, start - 2) && aligned_pair(gapped, end) &&
+                        other[start - 1].identity(&other[end - 1]) {
+                        gapped.swap(start - 1, end - 1);
+                        start -= 1;
+                        end -= 1;
+                    }
+                }
+                GapPlacement::Right => {
+                    while start >= 1 && end + 1 < columns && aligned_pair(gapped, start - 1) && aligned_pair(gapped, end) && aligned_pair(gapped, end + 1) &&
+                        other[start].identity(&other[end]) {
+                        gapped.swap(start, end);
+                        start += 1;
+                        end += 1;
+                    }
+                }
+                GapPlacement::AsAligned => {}
+            }
+        }
+
+        let tags = (0..columns).map(|column| match gap_kind(&self.reference_aligned, &self.read_aligned, column) {
+            0 => AlignmentTag::MatchMismatch(1),
+            1 => AlignmentTag::Del(1),
+            _ => AlignmentTag::Ins(1),
+        }).collect::<Vec<AlignmentTag>>();
+        self.cigar_string = simplify_cigar_string(&tags);
+
+        // the path follows the last path.len() columns (any leading end gaps come after the traceback), and ends where
+        // it did, since gaps never move past either end of the alignment
+        if !self.path.is_empty() && self.path.len() <= columns {
+            let first_column = columns - self.path.len();
+            let last = self.path[self.path.len() - 1];
+            let (mut x, mut y) = (last.x, last.y);
+            for column in (first_column..columns).rev() {
+                self.path[column - first_column] = AlignmentLocation { x, y };
+                x -= !self.reference_aligned[column].identity(&FASTA_UNSET) as usize;
+                y -= !self.read_aligned[column].identity(&FASTA_UNSET) as usize;
+            }
+        }
+        self
+    }
+
     pub fn to_cigar_string(&self) -> Box<dyn noodles_sam::alignment::record::Cigar> {
         let cigar: CigarBuf = self.cigar_string.iter().map(|t| t.to_op()).collect();
         Box::new(cigar)
     }
 
-    pub fn to_sam_record(&self, reference_id: &i32, extra_tags: &HashMap<[u8; 2], String>, read_names: Option<Vec<String>>) -> noodles_sam::alignment::RecordBuf {
+    /// the CIGAR for a SAM record; with `extended_cigar` set, match / mismatch runs are written as `=` and `X`
+    /// operations instead of `M`
+    pub fn to_sam_cigar(&self, extended_cigar: bool) -> CigarBuf {
+        if !extended_cigar {
+            return self.cigar_string.iter().map(|t| t.to_op()).collect();
+        }
+
+        let mut ops: Vec<Op> = Vec::new();
+        let mut column = 0;
+        for cigar in &self.cigar_string {
+            match cigar {
+                AlignmentTag::MatchMismatch(size) => {
+                    for index in column..(column + size) {
+                        let kind = if self.reference_aligned[index].identity(&self.read_aligned[index]) { Kind::SequenceMatch } else { Kind::SequenceMismatch };
+                        match ops.last_mut() {
+                            Some(op) if op.kind() == kind => { *op = Op::new(kind, op.len() + 1); }
+                            _ => { ops.push(Op::new(kind, 1)); }
+                        }
+                    }
+                    column += size;
+                }
+                AlignmentTag::Ins(size) | AlignmentTag::Del(size) | AlignmentTag::SoftClip(size) => {
+                    ops.push(cigar.to_op());
+                    column += size;
+                }
+                AlignmentTag::HardClip(_) | AlignmentTag::InversionOpen | AlignmentTag::InversionClose => { ops.push(cigar.to_op()); }
+            }
+        }
+        ops.into_iter().collect()
+    }
+
+    /// The SAM MD tag: the reference bases at mismatches and deletions, separated by the lengths of the matching runs
+    /// between them. Insertions and soft-clipped bases don't appear in the MD string
+    pub fn md_tag(&self) -> String {
+        let mut md = String::new();
+        let mut matches = 0;
+        let mut column = 0;
+        for cigar in &self.cigar_string {
+            match cigar {
+                AlignmentTag::MatchMismatch(size) => {
+                    for index in column..(column + size) {
+                        if self.reference_aligned[index].identity(&self.read_aligned[index]) {
+                            matches += 1;
+                        } else {
+                            md.push_str(&format!("{}{}", matches, FastaBase::string(&self.reference_aligned[index..(index + 1)])));
+                            matches = 0;
+                        }
+                    }
+                    column += size;
+                }
+                AlignmentTag::Del(size) => {
+                    md.push_str(&format!("{}^{}", matches, FastaBase::string(&self.reference_aligned[column..(column + size)])));
+                    matches = 0;
+                    column += size;
+                }
+                AlignmentTag::Ins(size) | AlignmentTag::SoftClip(size) => { column += size; }
+                AlignmentTag::HardClip(_) | AlignmentTag::InversionOpen | AlignmentTag::InversionClose => {}
+            }
+        }
+        md.push_str(&matches.to_string());
+        md
+    }
+
+    pub fn to_sam_record(&self, reference_id: &i32, extra_tags: &HashMap<[u8; 2], Value>, read_names: Option<Vec<String>>) -> noodles_sam::alignment::RecordBuf {
+        self.to_sam_record_with_flags(reference_id, extra_tags, read_names, Flags::empty(), None, false)
+    }
+
+    /// the same as `to_sam_record`, but with explicit SAM flags and mapping quality, e.g. for the supplementary records
+    /// of a split alignment or the secondary records of a read that aligns to more than one reference. Without a mapping
+    /// quality the record's MAPQ is 255 (unavailable)
+    pub fn to_sam_record_with_flags(&self, reference_id: &i32, extra_tags: &HashMap<[u8; 2], Value>, read_names: Option<Vec<String>>, flags: Flags, mapping_quality: Option<u8>, extended_cigar: bool) -> noodles_sam::alignment::RecordBuf {
 
         // set the aux. data with alignments stats and the extracted tags
         let mut data = Data::default();
-        extra_tags.iter().for_each(|(k, v)| { data.insert(Tag::from(k.clone()), Value::from(v.clone())); });
+        extra_tags.iter().for_each(|(k, v)| { data.insert(Tag::from(k.clone()), v.clone()); });
 
-        data.insert(Tag::from([b'r', b'm']), Value::from(get_reference_alignment_rate(&self.reference_aligned, &self.read_aligned).to_string()));
-        data.insert(Tag::from([b'r', b's']), Value::from(self.score.to_string()));
+        // the standard tags: the alignment score (an integer in the SAM spec), edit distance, and mismatch string
+        data.insert(Tag::from([b'A', b'S']), Value::from(self.score.round() as i32));
+        data.insert(Tag::from([b'N', b'M']), Value::from(self.edit_distance() as i32));
+        data.insert(Tag::from([b'M', b'D']), Value::from(self.md_tag()));
+
+        // our tags: the reference alignment rate and the unrounded score
+        data.insert(Tag::from([b'r', b'm']), Value::from(get_reference_alignment_rate(&self.reference_aligned, &self.read_aligned) as f32));
+        data.insert(Tag::from([b'r', b's']), Value::from(self.score as f32));
 
         match read_names {
             None => {}
             Some(x) => { data.insert(Tag::from([b'a', b'r']), Value::from(x.join(","))); }
         }
 
-        data.insert(Tag::from([b'a', b's']), Value::from(self.score.to_string()));
-
         let seq = FastaBase::string(self.read_aligned.clone().into_iter().filter(|b| b != &FASTA_UNSET).collect::<Vec<FastaBase>>().as_slice());
 
         // set the read name
-        //println!("quals\n{}seq{}\n",String::from_utf8(self.read_quals.as_ref().unwrap().clone()).unwrap(),seq);
-        RecordBuf::builder()
+        let builder = RecordBuf::builder()
             .set_name(Name::from(self.read_name.as_bytes()))
             .set_sequence(seq.as_bytes().into())
-            .set_cigar(Cigar::from_iter(self.cigar_string.iter().map(|m| m.to_op()).into_iter()).clone())
+            .set_cigar(self.to_sam_cigar(extended_cigar))
             .set_alignment_start(noodles_core::Position::new(self.reference_start+1).unwrap())
-            .set_quality_scores(match &self.read_quals {
-                Some(x) => { QualityScores::from(x.clone().iter().map(|x| u8::from(min(40,max(0,*x-33)))).collect::<Vec<u8>>()) }
-                None => { QualityScores::from(vec![b'H'; seq.len()]) }
-            })
+            .set_quality_scores(sam_quality_scores(&self.read_quals))
             .set_reference_sequence_id(*reference_id as usize)
-            .set_flags(Flags::PROPERLY_ALIGNED)
-            .set_data(data).build()
+            .set_flags(flags)
+            .set_data(data);
+
+        match mapping_quality.and_then(MappingQuality::new) {
+            None => builder.build(),
+            Some(mapq) => builder.set_mapping_quality(mapq).build(),
+        }
+    }
+
+    /// Split an inversion-aware alignment (one with InversionOpen / InversionClose tags in its CIGAR) into alignments
+    /// we can represent in a BAM file. The primary alignment keeps the read in its forward orientation: each inverted
+    /// segment is recorded as a deletion of the reference bases it covers followed by an insertion of the read bases.
+    /// Each inverted segment is also returned as its own reverse-strand alignment of the reverse-complemented read,
+    /// with the bases outside the segment soft-clipped. Alignments without inversions come back unchanged with no
+    /// inverted segments.
+    pub fn split_inversions(&self) -> (AlignmentResult, Vec<AlignmentResult>) {
+        if !self.cigar_string.contains(&AlignmentTag::InversionOpen) {
+            return (self.clone(), Vec::new());
+        }
+
+        let mut reference_aligned = Vec::new();
+        let mut read_aligned = Vec::new();
+        let mut cigar_string = Vec::new();
+
+        // (first column, last column + 1, reference position, read position) of each inverted segment
+        let mut inverted_blocks = Vec::new();
+        let mut inversion_start: Option<usize> = None;
+        let mut column = 0;
+        let mut reference_position = self.reference_start;
+        let mut read_position = self.read_start;
+
+        for cigar in &self.cigar_string {
+            match (cigar, inversion_start) {
+                (AlignmentTag::MatchMismatch(size) | AlignmentTag::Ins(size) | AlignmentTag::Del(size), None) => {
+                    reference_aligned.extend_from_slice(&self.reference_aligned[column..(column + size)]);
+                    read_aligned.extend_from_slice(&self.read_aligned[column..(column + size)]);
+                    reference_position += self.reference_aligned[column..(column + size)].iter().filter(|b| !b.identity(&FASTA_UNSET)).count();
+                    read_position += self.read_aligned[column..(column + size)].iter().filter(|b| !b.identity(&FASTA_UNSET)).count();
+                    cigar_string.push(*cigar);
+                    column += size;
+                }
+                (AlignmentTag::MatchMismatch(size) | AlignmentTag::Ins(size) | AlignmentTag::Del(size), Some(_)) => {
+                    column += size;
+                }
+                (AlignmentTag::InversionOpen, None) => {
+                    inversion_start = Some(column);
+                }
+                (AlignmentTag::InversionClose, Some(start)) => {
+                    // the inverted read bases are stored reverse-complemented, in reference order
+                    let inverted_reference = self.reference_aligned[start..column].iter().filter(|b| !b.identity(&FASTA_UNSET)).cloned().collect::<Vec<FastaBase>>();
+                    let inverted_read = self.read_aligned[start..column].iter().filter(|b| !b.identity(&FASTA_UNSET)).cloned().collect::<Vec<FastaBase>>();
+                    let forward_read = reverse_complement(&inverted_read);
+
+                    if !inverted_reference.is_empty() {
+                        reference_aligned.extend_from_slice(&inverted_reference);
+                        read_aligned.extend(vec![FASTA_UNSET; inverted_reference.len()]);
+                        cigar_string.push(AlignmentTag::Del(inverted_reference.len()));
+                    }
+                    if !forward_read.is_empty() {
+                        reference_aligned.extend(vec![FASTA_UNSET; forward_read.len()]);
+                        read_aligned.extend_from_slice(&forward_read);
+                        cigar_string.push(AlignmentTag::Ins(forward_read.len()));
+                    }
+
+                    inverted_blocks.push((start, column, reference_position, read_position));
+                    reference_position += inverted_reference.len();
+                    read_position += forward_read.len();
+                    inversion_start = None;
+                }
+                (tag, _) => { panic!("Unable to split the alignment for read {} at tag {}", self.read_name, tag) }
+            }
+        }
+        assert!(inversion_start.is_none(), "Unclosed inversion in the alignment for read {}", self.read_name);
+
+        let primary = AlignmentResult {
+            reference_name: self.reference_name.clone(),
+            read_name: self.read_name.clone(),
+            reference_aligned,
+            read_aligned,
+            read_quals: self.read_quals.clone(),
+            cigar_string: simplify_cigar_string(&cigar_string),
+            path: self.path.clone(),
+            score: self.score,
+            reference_start: self.reference_start,
+            read_start: self.read_start,
+            bounding_box: self.bounding_box,
+        };
+
+        let read = primary.read_aligned.iter().filter(|b| !b.identity(&FASTA_UNSET)).cloned().collect::<Vec<FastaBase>>();
+        let reverse_read = reverse_complement(&read);
+        let reverse_quals = self.read_quals.as_ref().map(|q| q.iter().rev().cloned().collect::<Vec<u8>>());
+
+        let inverted_segments = inverted_blocks.iter().map(|(start, end, reference_position, read_position)| {
+            let segment_read_length = self.read_aligned[*start..*end].iter().filter(|b| !b.identity(&FASTA_UNSET)).count();
+
+            // in reverse-complement coordinates the read bases after the segment come first
+            let leading_clip = read.len() - read_position - segment_read_length;
+            let trailing_clip = *read_position;
+
+            let mut reference_aligned = vec![FASTA_UNSET; leading_clip];
+            let mut read_aligned = reverse_read[0..leading_clip].to_vec();
+            let mut cigar_string = Vec::new();
+            if leading_clip > 0 {
+                cigar_string.push(AlignmentTag::SoftClip(leading_clip));
+            }
+            for index in *start..*end {
+                reference_aligned.push(self.reference_aligned[index]);
+                read_aligned.push(self.read_aligned[index]);
+                cigar_string.push(match (self.reference_aligned[index].identity(&FASTA_UNSET), self.read_aligned[index].identity(&FASTA_UNSET)) {
+                    (true, _) => AlignmentTag::Ins(1),
+                    (_, true) => AlignmentTag::Del(1),
+                    _ => AlignmentTag::MatchMismatch(1),
+                });
+            }
+            reference_aligned.extend(vec![FASTA_UNSET; trailing_clip]);
+            read_aligned.extend_from_slice(&reverse_read[(read.len() - trailing_clip)..]);
+            let mut cigar_string = simplify_cigar_string(&cigar_string);
+            if trailing_clip > 0 {
+                cigar_string.push(AlignmentTag::SoftClip(trailing_clip));
+            }
+
+            AlignmentResult {
+                reference_name: self.reference_name.clone(),
+                read_name: self.read_name.clone(),
+                reference_aligned,
+                read_aligned,
+                read_quals: reverse_quals.clone(),
+                cigar_string,
+                path: Vec::new(),
+                score: self.score,
+                reference_start: *reference_position,
+                read_start: leading_clip,
+                bounding_box: None,
+            }
+        }).collect::<Vec<AlignmentResult>>();
+
+        (primary, inverted_segments)
+    }
+
+    /// The number of mismatched, inserted, and deleted bases in the alignment, ignoring soft-clipped bases (the SAM NM tag)
+    pub fn edit_distance(&self) -> usize {
+        let mut distance = 0;
+        let mut column = 0;
+        for cigar in &self.cigar_string {
+            match cigar {
+                AlignmentTag::MatchMismatch(size) => {
+                    distance += self.reference_aligned[column..(column + size)].iter().zip(self.read_aligned[column..(column + size)].iter()).filter(|(a, b)| !a.identity(b)).count();
+                    column += size;
+                }
+                AlignmentTag::Ins(size) | AlignmentTag::Del(size) => {
+                    distance += size;
+                    column += size;
+                }
+                AlignmentTag::SoftClip(size) => { column += size; }
+                AlignmentTag::HardClip(_) | AlignmentTag::InversionOpen | AlignmentTag::InversionClose => {}
+            }
+        }
+        distance
+    }
+
+    /// An entry for the SAM SA (other canonical alignments in a chimeric alignment) tag describing this alignment:
+    /// reference name, 1-based position, strand, CIGAR, mapping quality, and edit distance
+    pub fn supplementary_alignment_entry(&self, reverse_strand: bool, mapping_quality: u8) -> String {
+        format!("{},{},{},{},{},{};",
+                self.reference_name,
+                self.reference_start + 1,
+                if reverse_strand { '-' } else { '+' },
+                self.cigar_string.iter().map(|c| c.to_string()).collect::<Vec<String>>().join(""),
+                mapping_quality,
+                self.edit_distance())
     }
 
     #[allow(dead_code)]
@@ -855,7 +1257,26 @@ impl AlignmentResult {
     }
 }
 
-pub fn find_max_value_3d_array(matrix: &Array<f64, Ix3>) -> Option<(AlignmentLocation, f64)> {
+/// SAM base qualities from our Phred+33 encoded qualities; reads without qualities get a missing ('*') quality string
+pub fn sam_quality_scores(read_quals: &Option<Vec<u8>>) -> QualityScores {
+    match read_quals {
+        Some(x) => { QualityScores::from(x.iter().map(|q| q.saturating_sub(PHRED_OFFSET)).collect::<Vec<u8>>()) }
+        None => { QualityScores::default() }
+    }
+}
+
+/// A SAM record for a read we couldn't align: the unmapped flag, the read's bases and qualities, and no reference
+/// position or CIGAR
+pub fn unmapped_sam_record(read_name: &str, read: &[FastaBase], read_quals: &Option<Vec<u8>>) -> noodles_sam::alignment::RecordBuf {
+    RecordBuf::builder()
+        .set_name(Name::from(read_name.as_bytes()))
+        .set_sequence(FastaBase::string(read).as_bytes().into())
+        .set_quality_scores(sam_quality_scores(read_quals))
+        .set_flags(Flags::UNMAPPED)
+        .build()
+}
+
+pub fn find_max_value_3d_array(matrix: &ArrayView3<f64>) -> Option<(AlignmentLocation, f64)> {
     let mut _g_max_row = 0;
     let mut _g_max_col = 0;
     let mut _g_max_z = 0;
@@ -893,38 +1314,63 @@ pub struct BoundedAlignment {
     bounding_box: (AlignmentLocation, AlignmentLocation),
 }
 
-#[allow(dead_code)]
-pub(crate) fn inversion_alignment(reference: &Vec<FastaBase>, read: &Vec<FastaBase>, reference_name: &String, read_name: &String, inversion_score: &InversionScoring, my_aff_score: &AffineScoring, local: bool) -> AlignmentResult {
-    let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, read.len() + 1, AlignmentType::Affine, local);
-    let mut inversion_mat = create_scoring_record_3d(reference.len() + 1, read.len() + 1, AlignmentType::Affine, true);
-
-    let mut long_enough_hits: HashMap<AlignmentLocation, BoundedAlignment> = HashMap::new();
-    let rev_comp_read = &reverse_complement(read);
-    perform_affine_alignment(&mut inversion_mat, reference, rev_comp_read, my_aff_score);
-
-    let mut aligned_inv: Option<AlignmentResult> = Some(perform_3d_global_traceback(&mut inversion_mat, None, reference, rev_comp_read, reference_name, read_name, None, None)); // TODO fix with quality scores
-
-    while aligned_inv.is_some() {
-        let aligned_inv_local = aligned_inv.unwrap();
-        let length = aligned_inv_local.path.len();
-        if length > 1 {
-            let converted_path = aligned_inv_local.convert_inverted_path(read.len());
-            let bounding = converted_path.bounding_box.unwrap();
-            let b_alignment = BoundedAlignment { alignment_result: converted_path, bounding_box: bounding };
-            let true_position = AlignmentLocation { x: bounding.1.x, y: bounding.1.y };
-            aligned_inv = if length >= inversion_score.min_inversion_length {
-                clean_and_find_next_best_match_3d(&mut inversion_mat, reference, rev_comp_read, my_aff_score, &aligned_inv_local);
-                long_enough_hits.insert(true_position, b_alignment);
-                Some(perform_3d_global_traceback(&mut inversion_mat, None, reference, rev_comp_read, reference_name, read_name, None, None))// TODO fix with quality scores
+thread_local!(static INVERSION_MATRICES: RefCell<Option<(Alignment<Ix3>, Alignment<Ix3>)>> = RefCell::new(None));
+
+/// Align the read to the reference allowing inversions: local alignments of the reverse-complemented read are found
+/// first, and then the inversion-aware DP can jump over any of them. The main DP is banded to `bandwidth` either side of
+/// the diagonal. Each thread keeps its two matrices between calls, growing them when a longer pair of sequences comes in
+pub(crate) fn inversion_alignment(reference: &Vec<FastaBase>, read: &Vec<FastaBase>, reference_name: &String, read_name: &String, inversion_score: &InversionScoring, my_aff_score: &AffineScoring, bandwidth: &usize, local: bool) -> AlignmentResult {
+    INVERSION_MATRICES.with(|matrices| {
+        let mut matrices = matrices.borrow_mut();
+        let large_enough = matrices.as_ref().map_or(false, |(alignment_mat, _)| {
+            alignment_mat.scores.shape()[0] > reference.len() && alignment_mat.scores.shape()[1] > read.len()
+        });
+        if !large_enough {
+            let (rows, columns) = matrices.as_ref().map_or((0, 0), |(alignment_mat, _)| (alignment_mat.scores.shape()[0], alignment_mat.scores.shape()[1]));
+            let rows = max(rows, reference.len() + 1);
+            let columns = max(columns, read.len() + 1);
+            *matrices = Some((create_scoring_record_3d(rows, columns, AlignmentType::Affine, local),
+                              create_scoring_record_3d(rows, columns, AlignmentType::Affine, true)));
+        }
+        let (alignment_mat, inversion_mat) = matrices.as_mut().unwrap();
+        alignment_mat.is_local = local;
+
+        let mut long_enough_hits: HashMap<AlignmentLocation, BoundedAlignment> = HashMap::new();
+        let rev_comp_read = &reverse_complement(read);
+        perform_affine_alignment(inversion_mat, reference, rev_comp_read, my_aff_score);
+
+        let mut aligned_inv: Option<AlignmentResult> = Some(perform_3d_global_traceback(inversion_mat, None, reference, rev_comp_read, reference_name, read_name, None, None)); // TODO fix with quality scores
+
+        // an inversion can only be used if the main DP fills the cells it starts from and lands on
+        let in_band = |location: &AlignmentLocation| {
+            let (start, end) = diagonal_band(location.x, reference.len(), read.len(), bandwidth);
+            location.x == 0 || location.y == 0 || (start <= location.y && location.y < end)
+        };
+
+        while aligned_inv.is_some() {
+            let aligned_inv_local = aligned_inv.unwrap();
+            let length = aligned_inv_local.path.len();
+            if length > 1 {
+                let converted_path = aligned_inv_local.convert_inverted_path(read.len());
+                let bounding = converted_path.bounding_box.unwrap();
+                let b_alignment = BoundedAlignment { alignment_result: converted_path, bounding_box: bounding };
+                let true_position = AlignmentLocation { x: bounding.1.x, y: bounding.1.y };
+                aligned_inv = if length >= inversion_score.min_inversion_length {
+                    clean_and_find_next_best_match_3d(inversion_mat, reference, rev_comp_read, my_aff_score, &aligned_inv_local);
+                    if in_band(&true_position) && in_band(&AlignmentLocation { x: bounding.0.x.saturating_sub(1), y: bounding.0.y.saturating_sub(1) }) {
+                        long_enough_hits.insert(true_position, b_alignment);
+                    }
+                    Some(perform_3d_global_traceback(inversion_mat, None, reference, rev_comp_read, reference_name, read_name, None, None))// TODO fix with quality scores
+                } else {
+                    None
+                }
             } else {
-                None
+                aligned_inv = None
             }
-        } else {
-            aligned_inv = None
         }
-    }
-    perform_inversion_aware_alignment(&mut alignment_mat, &long_enough_hits, reference, read, inversion_score);
-    perform_3d_global_traceback(&mut alignment_mat, Some(&long_enough_hits), reference, read, reference_name, read_name, None, None) // TODO fix with quality scores
+        perform_inversion_aware_alignment(alignment_mat, &long_enough_hits, reference, read, inversion_score, bandwidth);
+        perform_3d_global_traceback(alignment_mat, Some(&long_enough_hits), reference, read, reference_name, read_name, None, None) // TODO fix with quality scores
+    })
 }
 
 #[allow(dead_code)]
@@ -949,10 +1395,22 @@ pub fn perform_3d_global_traceback(alignment: &mut Alignment<Ix3>,
         _starting_y = starting_position.unwrap().1;
     } else if alignment.is_local {
         //println!("trying to find max for seq {}",String::from_utf8(sequence2.clone()).unwrap());
-        let max_value_tuple = find_max_value_3d_array(&alignment.scores).unwrap();
+        // a reused matrix can be larger than the sequences, so only search the part this alignment filled
+        let max_value_tuple = find_max_value_3d_array(&alignment.scores.slice(s![0..(sequence1.len() + 1), 0..(sequence2.len() + 1), ..])).unwrap();
         //println!("done! {}",String::from_utf8(sequence2.clone()).unwrap());
         _starting_x = max_value_tuple.0.x;
         _starting_y = max_value_tuple.0.y;
+    } else if alignment.is_semi_global {
+        // the read has to be fully aligned, but it can end anywhere in the reference: take the best score in the last
+        // column, the earliest in the reference on ties
+        let mut best_score = MAX_NEG_SCORE;
+        for x in 0..(sequence1.len() + 1) {
+            let row_best = (0..3).map(|z| alignment.scores[[x, sequence2.len(), z]]).fold(MAX_NEG_SCORE, f64::max);
+            if row_best > best_score {
+                best_score = row_best;
+                _starting_x = x;
+            }
+        }
     }
     let starting_z = [(alignment.scores[[_starting_x, _starting_y, 0]], 0),
         (alignment.scores[[_starting_x, _starting_y, 1]], 1),
@@ -1005,6 +1463,14 @@ pub fn perform_3d_global_traceback(alignment: &mut Alignment<Ix3>,
             }
         };
 
+        // when a match could have come from more than one matrix, the gap placement picks: leaving the match matrix for
+        // a gap as soon as we can puts gaps as far right as they go, staying in it as long as we can puts them left
+        let movement_delta = if _starting_z == 0 && movement_delta.1 > 0 {
+            (preferred_match_source(alignment, _starting_x, _starting_y, movement_delta.0), movement_delta.1)
+        } else {
+            movement_delta
+        };
+
         match _starting_z {
             0 => {
                 if movement_delta.1 > 0 { cigars.push(AlignmentTag::MatchMismatch(1)) };
@@ -1040,7 +1506,9 @@ pub fn perform_3d_global_traceback(alignment: &mut Alignment<Ix3>,
         _starting_z = movement_delta.0;
     }
     //info!("OUT: {},{},{}", starting_x, starting_y, starting_z);
-    while _starting_x > 0 && !alignment.is_local {
+    // semi-global alignments start wherever the read does, rather than with deletions of the reference before it
+    let reference_start = if alignment.is_semi_global && !alignment.is_local { _starting_x } else { 0 };
+    while _starting_x > 0 && !alignment.is_local && !alignment.is_semi_global {
         alignment1.push(*sequence1.get(_starting_x - 1).unwrap());
         alignment2.push(FASTA_UNSET);
         _starting_x -= 1;
@@ -1066,12 +1534,25 @@ pub fn perform_3d_global_traceback(alignment: &mut Alignment<Ix3>,
         cigar_string: simplify_cigar_string(&cigars),
         path,
         score,
-        reference_start: 0, // we're global
+        reference_start,
         read_start: 0, // we're global
         bounding_box: None,
     }
 }
 
+/// The matrix a match at (x, y) came from, given the one the fill chose: a match adds the same score whichever matrix it
+/// follows, so any matrix with the same score at (x - 1, y - 1) is an equally good source, and we take the one the
+/// alignment's gap placement prefers
+fn preferred_match_source(alignment: &Alignment<Ix3>, x: usize, y: usize, chosen: usize) -> usize {
+    let preference = match alignment.gap_placement {
+        GapPlacement::AsAligned => return chosen,
+        GapPlacement::Left => [0, 2, 1],
+        GapPlacement::Right => [2, 1, 0],
+    };
+    let best = alignment.scores[[x - 1, y - 1, chosen]];
+    preference.into_iter().find(|matrix| alignment.scores[[x - 1, y - 1, *matrix]] == best).unwrap_or(chosen)
+}
+
 #[allow(dead_code)]
 pub fn pretty_print_3d_matrix(alignment: &Alignment<Ix3>, sequence1: &Vec<u8>, sequence2: &Vec<u8>) {
     println!("DIM: {:?}", alignment.scores.shape());
@@ -1116,11 +1597,20 @@ pub fn pretty_print_3d_matrix(alignment: &Alignment<Ix3>, sequence1: &Vec<u8>, s
 mod tests {
     use super::*;
     use crate::alignment::fasta_bit_encoding::{reverse_complement};
+    use crate::alignment::integer_alignment::{IntegerAffineScoring, integer_affine_alignment};
 
     fn str_to_fasta_vec(input: &str) -> Vec<FastaBase> {
         FastaBase::from_vec_u8_default_ns(&input.as_bytes().to_vec())
     }
 
+    /// run a case through the integer kernel too, which has to match the f64 aligner field for field
+    fn assert_integer_kernel_matches(reference: &[FastaBase], test_read: &[FastaBase], my_score: &AffineScoring, local: bool, results: &AlignmentResult) {
+        let integer_scoring = IntegerAffineScoring::from_affine(my_score).unwrap();
+        let bandwidth = max(reference.len(), test_read.len());
+        let integer = integer_affine_alignment(reference, test_read, &integer_scoring, &bandwidth, local, false, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None);
+        assert_eq!(serde_json::to_string(&integer).unwrap(), serde_json::to_string(results).unwrap());
+    }
+
     fn fasta_vec_to_string(input: &Vec<FastaBase>) -> String {
         FastaBase::string(input)
     }
@@ -1137,6 +1627,7 @@ mod tests {
             gap_open: -10.0,
             gap_extend: -10.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, true);
@@ -1144,6 +1635,7 @@ mod tests {
         //pretty_print_3d_matrix(&alignment_mat, &reference, &test_read);
 
         let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, true, &results);
 
         assert_eq!(results.reference_aligned, str_to_fasta_vec("CCAATCTACT"));
         assert_eq!(results.read_aligned, str_to_fasta_vec("CTACTCTACT"));
@@ -1168,12 +1660,14 @@ mod tests {
             gap_open: -20.0,
             gap_extend: -10.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, true);
         perform_affine_alignment(&mut alignment_mat, &reference, &test_read, &my_score);
 
         let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, true, &results);
         assert_eq!(results.reference_aligned, str_to_fasta_vec("CCAATCTACT"));
         assert_eq!(results.read_aligned, str_to_fasta_vec("CTACTCTACT"));
     }
@@ -1190,16 +1684,103 @@ mod tests {
             gap_open: -10.0,
             gap_extend: -10.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, false);
         perform_affine_alignment(&mut alignment_mat, &reference, &test_read, &my_score);
 
         let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, false, &results);
         assert_eq!(results.reference_aligned, str_to_fasta_vec("AAAANAAAA"));
         assert_eq!(results.read_aligned, str_to_fasta_vec("AAAA-AAAA"));
     }
 
+    #[test]
+    fn affine_degenerate_reference_test() {
+        // a VNNWSN tag: every read base that fits the IUPAC code is a special character match
+        let reference = str_to_fasta_vec("ACGTACVNNWSNACGTACGT");
+        let test_read = str_to_fasta_vec("ACGTACAGTACCACGTACGT");
+        let my_score = AffineScoring::default_dna();
+
+        let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, false);
+        perform_affine_alignment(&mut alignment_mat, &reference, &test_read, &my_score);
+        let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, false, &results);
+        assert_eq!(results.cigar_string, vec![AlignmentTag::MatchMismatch(20)]);
+        assert_eq!(results.score, 14.0 * my_score.match_score + 6.0 * my_score.special_character_score);
+
+        // but a T can't be a V
+        let test_read = str_to_fasta_vec("ACGTACTGTACCACGTACGT");
+        perform_affine_alignment(&mut alignment_mat, &reference, &test_read, &my_score);
+        let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, false, &results);
+        assert_eq!(results.cigar_string, vec![AlignmentTag::MatchMismatch(20)]);
+        assert_eq!(results.score, 14.0 * my_score.match_score + 5.0 * my_score.special_character_score + my_score.mismatch_score);
+    }
+
+    fn aligned_with_gap_placement(reference: &str, read: &str, gap_placement: GapPlacement) -> AlignmentResult {
+        let reference = str_to_fasta_vec(reference);
+        let test_read = str_to_fasta_vec(read);
+        let my_score = AffineScoring::default_dna();
+        let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, false);
+        perform_affine_alignment(&mut alignment_mat, &reference, &test_read, &my_score);
+        let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, false, &results);
+        results.normalize_gaps(gap_placement)
+    }
+
+    #[test]
+    fn gap_placement_test() {
+        // a deletion in a homopolymer goes to either end of it
+        let as_aligned = aligned_with_gap_placement("ACGTAAAACGT", "ACGTAAACGT", GapPlacement::AsAligned);
+        let left = aligned_with_gap_placement("ACGTAAAACGT", "ACGTAAACGT", GapPlacement::Left);
+        let right = aligned_with_gap_placement("ACGTAAAACGT", "ACGTAAACGT", GapPlacement::Right);
+        assert_eq!(left.cigar_string, vec![AlignmentTag::MatchMismatch(4), AlignmentTag::Del(1), AlignmentTag::MatchMismatch(6)]);
+        assert_eq!(right.cigar_string, vec![AlignmentTag::MatchMismatch(7), AlignmentTag::Del(1), AlignmentTag::MatchMismatch(3)]);
+        assert_eq!(FastaBase::string(&left.read_aligned), "ACGT-AAACGT");
+        assert_eq!(FastaBase::string(&right.read_aligned), "ACGTAAA-CGT");
+        assert_eq!(left.score, as_aligned.score);
+        assert_eq!(right.score, as_aligned.score);
+
+        // the path still walks the new columns and ends in the same place
+        assert_eq!(left.path.len(), as_aligned.path.len());
+        assert_eq!(left.path.last(), as_aligned.path.last());
+        assert_eq!(left.path[4], AlignmentLocation { x: 5, y: 4 });
+        assert_eq!(right.path[7], AlignmentLocation { x: 8, y: 7 });
+
+        // and so does an insertion in a dinucleotide repeat
+        let left = aligned_with_gap_placement("GGCACACATT", "GGCACACACATT", GapPlacement::Left);
+        let right = aligned_with_gap_placement("GGCACACATT", "GGCACACACATT", GapPlacement::Right);
+        assert_eq!(left.cigar_string, vec![AlignmentTag::MatchMismatch(2), AlignmentTag::Ins(2), AlignmentTag::MatchMismatch(8)]);
+        assert_eq!(right.cigar_string, vec![AlignmentTag::MatchMismatch(8), AlignmentTag::Ins(2), AlignmentTag::MatchMismatch(2)]);
+
+        // normalizing again doesn't move anything
+        assert_eq!(left.clone().normalize_gaps(GapPlacement::Left).cigar_string, left.cigar_string);
+        assert_eq!(GapPlacement::from_name("Right"), GapPlacement::Right);
+    }
+
+    #[test]
+    fn gap_placement_traceback_test() {
+        // the traceback's tie-breaking places the gaps on its own, before `normalize_gaps` gets to them
+        for (reference, read) in [("ACGTAAAACGT", "ACGTAAACGT"), ("GGCACACATT", "GGCACACACATT")] {
+            for gap_placement in [GapPlacement::Left, GapPlacement::Right] {
+                let reference_bases = str_to_fasta_vec(reference);
+                let read_bases = str_to_fasta_vec(read);
+                let my_score = AffineScoring::default_dna();
+                let mut alignment_mat = create_scoring_record_3d(reference_bases.len() + 1, read_bases.len() + 1, AlignmentType::Affine, false);
+                alignment_mat.gap_placement = gap_placement;
+                perform_affine_alignment(&mut alignment_mat, &reference_bases, &read_bases, &my_score);
+                let traced = perform_3d_global_traceback(&mut alignment_mat, None, &reference_bases, &read_bases, &"reference_name".to_string(), &"read_name".to_string(), None, None);
+
+                let normalized = aligned_with_gap_placement(reference, read, gap_placement);
+                assert_eq!(traced.cigar_string, normalized.cigar_string);
+                assert_eq!(FastaBase::string(&traced.read_aligned), FastaBase::string(&normalized.read_aligned));
+                assert_eq!(traced.score, normalized.score);
+            }
+        }
+    }
+
     #[test]
     fn affine_loose_ends() {
         let reference = str_to_fasta_vec("ACGTACGTACGT");
@@ -1212,6 +1793,7 @@ mod tests {
             gap_open: -10.0,
             gap_extend: -10.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, false);
@@ -1248,12 +1830,14 @@ mod tests {
             gap_open: -10.0,
             gap_extend: -10.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, false);
         perform_affine_alignment(&mut alignment_mat, &reference, &test_read, &my_score);
 
         let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, false, &results);
         assert_eq!(results.reference_aligned, str_to_fasta_vec("----------------AAAAAAAA############################AGATCGGAAGAGCGTCGTGTAGGGAAAGA"));
         assert_eq!(results.read_aligned, str_to_fasta_vec("AAAAAAAAAAAAAAAAAAAAAAAAATATCTCGTTTAATTGACTCTGAAATCAAGATCGGAAGAGCGTCGTGTAGGGAAAGA"));
     }
@@ -1271,12 +1855,14 @@ mod tests {
             gap_open: -10.0,
             gap_extend: -10.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, false);
         perform_affine_alignment(&mut alignment_mat, &reference, &test_read, &my_score);
 
         let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, false, &results);
         assert_eq!(results.reference_aligned, str_to_fasta_vec("AA-AA"));
         assert_eq!(results.read_aligned, str_to_fasta_vec("AATAA"));
     }
@@ -1293,6 +1879,7 @@ mod tests {
 
         //pretty_print_3d_matrix(&alignment_mat, &FastaBase::vec_u8(&reference), &FastaBase::vec_u8(&test_read));
         let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, false, &results);
         println!("{}\n{}\n{}", FastaBase::string(results.reference_aligned.as_slice()), FastaBase::string(results.read_aligned.as_slice()), results.score);
         assert_eq!(results.reference_aligned, str_to_fasta_vec("TTAAGCAGTGGTATCAACGCAGAGTACGCCTTAGGTTAACTTGCTATTTCTAGCTCTAACCCCACCCACGATTGCCGCCGACCCCCATATAAGAAANNNNNNNNNNNNNNNNNNNNNNNNNNAGAT"));
         //                                                            TTAAGCAGTGGTATCAACGCAGAGTACGCCTTAGGTTAACTTGCTAGTTCTAGCTCTAACCCCACC----------------------------AACAAGTTTTTCAACACCTAGCGTG------T
@@ -1311,12 +1898,14 @@ mod tests {
             gap_open: -10.0,
             gap_extend: -10.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, false);
         perform_affine_alignment(&mut alignment_mat, &reference, &test_read, &my_score);
 
         let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, false, &results);
         assert_eq!(results.reference_aligned, str_to_fasta_vec("AA-AA"));
         assert_eq!(results.read_aligned, str_to_fasta_vec("AATAA"));
 
@@ -1339,6 +1928,7 @@ mod tests {
             gap_open: -15.0,
             gap_extend: -5.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, true);
@@ -1346,6 +1936,7 @@ mod tests {
         //pretty_print_3d_matrix(&alignment_mat, &reference, &test_read);
 
         let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, true, &results);
         println!("Aligned {} and {}; from {} and {}",
                  fasta_vec_to_string(&results.reference_aligned),
                  fasta_vec_to_string(&results.read_aligned),
@@ -1372,6 +1963,7 @@ mod tests {
             gap_open: -15.0,
             gap_extend: -5.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, true);
@@ -1379,6 +1971,7 @@ mod tests {
         //pretty_print_3d_matrix(&alignment_mat, &reference, &test_read);
 
         let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, true, &results);
         println!("Aligned {} and {}; from {} and {}",
                  fasta_vec_to_string(&results.reference_aligned),
                  fasta_vec_to_string(&results.read_aligned),
@@ -1402,12 +1995,14 @@ mod tests {
             gap_open: -15.0,
             gap_extend: -5.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, test_read.len() + 1, AlignmentType::Affine, true);
         perform_affine_alignment(&mut alignment_mat, &reference, &test_read, &my_score);
 
         let results = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), None, None);
+        assert_integer_kernel_matches(&reference, &test_read, &my_score, true, &results);
 
         assert_eq!(fasta_vec_to_string(&results.reference_aligned), "TACTGC");
         assert_eq!(fasta_vec_to_string(&results.read_aligned), "TACAGC");
@@ -1436,9 +2031,10 @@ mod tests {
             gap_open: -15.0,
             gap_extend: -5.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
-        let results = inversion_alignment(&reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), &my_score, &my_aff_score, true);
+        let results = inversion_alignment(&reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), &my_score, &my_aff_score, &max(reference.len(), test_read.len()), true);
 
         println!("Aligned {} and {} from {} and {}",
                  fasta_vec_to_string(&results.reference_aligned),
@@ -1474,9 +2070,10 @@ mod tests {
             gap_open: -15.0,
             gap_extend: -5.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
-        let results = inversion_alignment(&reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), &my_score, &my_aff_score, false);
+        let results = inversion_alignment(&reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), &my_score, &my_aff_score, &max(reference.len(), test_read.len()), false);
 
 
         println!("Aligned {} and {} from {} and {}",
@@ -1513,9 +2110,10 @@ mod tests {
             gap_open: -15.0,
             gap_extend: -5.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
-        let results = inversion_alignment(&reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), &my_score, &my_aff_score, false);
+        let results = inversion_alignment(&reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), &my_score, &my_aff_score, &max(reference.len(), test_read.len()), false);
 
         println!("Aligned {} and {} from {} and {}",
                  fasta_vec_to_string(&results.reference_aligned),
@@ -1525,5 +2123,136 @@ mod tests {
 
         println!("CIGAR: {:?}", results.cigar_string);
     }
-}
 
+    #[test]
+    fn split_inversions_test() {
+        let reference = str_to_fasta_vec("CCAATCTACTACTGCTTGCA");
+        let test_read = str_to_fasta_vec("CCGTAGATTTACTGCTTGCA");
+
+        let my_score = InversionScoring {
+            match_score: 10.0,
+            mismatch_score: -11.0,
+            gap_open: -15.0,
+            gap_extend: -5.0,
+            inversion_penalty: -2.0,
+            min_inversion_length: 2,
+        };
+
+        let my_aff_score = AffineScoring {
+            match_score: 10.0,
+            mismatch_score: -11.0,
+            special_character_score: 8.0,
+            gap_open: -15.0,
+            gap_extend: -5.0,
+            final_gap_multiplier: 1.0,
+            quality_weighting: None,
+        };
+
+        let mut results = inversion_alignment(&reference, &test_read, &"reference_name".to_ascii_uppercase(), &"read_name".to_ascii_uppercase(), &my_score, &my_aff_score, &max(reference.len(), test_read.len()), false);
+        results.read_quals = Some((0..test_read.len()).map(|x| 33 + x as u8).collect());
+        assert!(results.cigar_string.contains(&AlignmentTag::InversionOpen));
+
+        let (primary, inverted) = results.split_inversions();
+
+        // the primary alignment is the forward read, without inversion tags
+        assert!(!primary.cigar_string.contains(&AlignmentTag::InversionOpen));
+        assert!(!primary.cigar_string.contains(&AlignmentTag::InversionClose));
+        assert_eq!(fasta_vec_to_string(&primary.read_aligned).replace("-", ""), "CCGTAGATTTACTGCTTGCA");
+        assert_eq!(fasta_vec_to_string(&primary.reference_aligned).replace("-", ""), "CCAATCTACTACTGCTTGCA");
+        primary.cigar_string.iter().for_each(|c| { c.to_op(); });
+
+        // the inverted segment carries the whole reverse-complemented read, clipped down to the inverted bases
+        assert_eq!(inverted.len(), 1);
+        let segment = &inverted[0];
+        assert_eq!(fasta_vec_to_string(&segment.read_aligned).replace("-", ""), fasta_vec_to_string(&reverse_complement(&test_read)));
+        assert_eq!(segment.read_quals.as_ref().unwrap()[0], 33 + test_read.len() as u8 - 1);
+        assert!(segment.reference_start > 0);
+
+        let segment_reference = fasta_vec_to_string(&segment.reference_aligned).replace("-", "");
+        assert_eq!(segment_reference, fasta_vec_to_string(&reference[segment.reference_start..(segment.reference_start + segment_reference.len())].to_vec()));
+
+        let clipped = segment.cigar_string.iter().map(|c| match c {
+            AlignmentTag::SoftClip(size) => *size,
+            _ => 0,
+        }).sum::<usize>();
+        assert!(clipped > 0 && clipped < test_read.len());
+        assert!(segment.supplementary_alignment_entry(true, 60).starts_with("REFERENCE_NAME,"));
+    }
+
+    #[test]
+    fn inversion_alignment_reuses_matrices_test() {
+        let reference = str_to_fasta_vec("CCAATCTACTACTGCTTGCA");
+        let test_read = str_to_fasta_vec("CCGTAGATTTACTGCTTGCA");
+        let my_score = InversionScoring { match_score: 10.0, mismatch_score: -11.0, gap_open: -15.0, gap_extend: -5.0, inversion_penalty: -2.0, min_inversion_length: 2 };
+        let my_aff_score = AffineScoring { match_score: 10.0, mismatch_score: -11.0, special_character_score: 8.0, gap_open: -15.0, gap_extend: -5.0, final_gap_multiplier: 1.0, quality_weighting: None };
+        let name = &"reference_name".to_string();
+        let read_name = &"read_name".to_string();
+
+        let first = inversion_alignment(&reference, &test_read, name, read_name, &my_score, &my_aff_score, &20, false);
+
+        // a larger alignment grows this thread's matrices and leaves its scores behind in them
+        let long_reference = str_to_fasta_vec("TTGACCAGTAGGACCATTAGGCAGTACCAGGTTCAGACCCATGATTAGACAGGTTTAGCAT");
+        let long_read = str_to_fasta_vec("TTGACCAGTAGGACCTAATGGTCAGTACCAGGTTCAGACCCATGATCTGTAGGTTTAGCAT");
+        inversion_alignment(&long_reference, &long_read, name, read_name, &my_score, &my_aff_score, &60, false);
+
+        let second = inversion_alignment(&reference, &test_read, name, read_name, &my_score, &my_aff_score, &20, false);
+        assert_eq!(serde_json::to_string(&first).unwrap(), serde_json::to_string(&second).unwrap());
+
+        // a narrow band still aligns the whole read
+        let banded = inversion_alignment(&long_reference, &long_read, name, read_name, &my_score, &my_aff_score, &3, false);
+        let (primary, _inverted) = banded.split_inversions();
+        assert_eq!(fasta_vec_to_string(&primary.read_aligned).replace("-", ""), fasta_vec_to_string(&long_read));
+    }
+
+    #[test]
+    fn sam_record_test() {
+        let result = AlignmentResult {
+            reference_name: "REFERENCE_NAME".to_string(),
+            read_name: "read_name".to_string(),
+            reference_aligned: str_to_fasta_vec("ACGTACGT-ACGT"),
+            read_aligned: str_to_fasta_vec("ACCT--GTTACAT"),
+            read_quals: Some((0..11).map(|x| 33 + 35 + x as u8).collect()),
+            cigar_string: vec![AlignmentTag::MatchMismatch(4), AlignmentTag::Del(2), AlignmentTag::MatchMismatch(2), AlignmentTag::Ins(1), AlignmentTag::MatchMismatch(4)],
+            path: vec![],
+            score: 12.5,
+            reference_start: 3,
+            read_start: 0,
+            bounding_box: None,
+        };
+
+        assert_eq!(result.md_tag(), "2G1^AC4G1");
+        assert_eq!(result.edit_distance(), 5);
+
+        let extended = result.to_sam_cigar(true);
+        assert_eq!(extended.as_ref(), &[Op::new(Kind::SequenceMatch, 2), Op::new(Kind::SequenceMismatch, 1), Op::new(Kind::SequenceMatch, 1),
+            Op::new(Kind::Deletion, 2), Op::new(Kind::SequenceMatch, 2), Op::new(Kind::Insertion, 1),
+            Op::new(Kind::SequenceMatch, 2), Op::new(Kind::SequenceMismatch, 1), Op::new(Kind::SequenceMatch, 1)]);
+        assert_eq!(result.to_sam_cigar(false).as_ref(), &[Op::new(Kind::Match, 4), Op::new(Kind::Deletion, 2), Op::new(Kind::Match, 2),
+            Op::new(Kind::Insertion, 1), Op::new(Kind::Match, 4)]);
+
+        // a plain record isn't flagged as a properly aligned pair, and without a mapping quality it's unavailable (255)
+        let record = result.to_sam_record(&0, &HashMap::new(), None);
+        assert_eq!(record.flags(), Flags::empty());
+        assert!(record.mapping_quality().is_none());
+        assert_eq!(record.alignment_start().unwrap().get(), 4);
+        assert_eq!(record.quality_scores().as_ref(), (35..46).collect::<Vec<u8>>().as_slice());
+        assert_eq!(record.data().get(&Tag::from([b'N', b'M'])), Some(&Value::from(5)));
+        assert_eq!(record.data().get(&Tag::from([b'M', b'D'])), Some(&Value::from("2G1^AC4G1")));
+        assert_eq!(record.data().get(&Tag::from([b'A', b'S'])), Some(&Value::from(13)));
+        assert_eq!(record.data().get(&Tag::from([b'r', b's'])), Some(&Value::from(12.5f32)));
+        assert!(record.data().get(&Tag::from([b'a', b's'])).is_none());
+
+        let mut tags = HashMap::new();
+        tags.insert([b'X', b'S'], Value::from(10.0f32));
+        let record = result.to_sam_record_with_flags(&0, &tags, None, Flags::SECONDARY | Flags::REVERSE_COMPLEMENTED, Some(0), true);
+        assert!(record.flags().is_secondary() && record.flags().is_reverse_complemented());
+        assert_eq!(record.mapping_quality().map(u8::from), Some(0));
+        assert_eq!(record.cigar().as_ref(), extended.as_ref());
+        assert_eq!(record.data().get(&Tag::from([b'X', b'S'])), Some(&Value::from(10.0f32)));
+
+        let unmapped = unmapped_sam_record("read_name", &str_to_fasta_vec("ACGT"), &None);
+        assert!(unmapped.flags().is_unmapped());
+        assert!(unmapped.reference_sequence_id().is_none() && unmapped.alignment_start().is_none());
+        assert!(unmapped.quality_scores().as_ref().is_empty());
+    }
+}
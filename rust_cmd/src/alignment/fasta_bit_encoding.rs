@@ -22,6 +22,13 @@ impl FastaBase {
         //(*self ^ *other).0 == 0 //-- even this slight indirection (to double access the .0 member) was costing us -- we go right to the source below
         self.0 ^ other.0 == 0
     }
+    /// is this an IUPAC degenerate base (N, or one of R, Y, K, M, S, W, B, D, H, V) that stands for more than one of
+    /// A, C, G, and T? Gaps aren't degenerate
+    #[inline(always)]
+    pub fn is_degenerate(&self) -> bool {
+        self.0 & FASTA_UNSET.0 == 0 && self.0.count_ones() > 1
+    }
+
     #[inline(always)]
     pub fn strict_identity(&self, other: &FastaBase) -> bool {
         //(*self ^ *other).0 == 0 //-- even this slight indirection (to double access the .0 member) was costing us -- we go right to the source below
@@ -334,6 +341,13 @@ mod tests {
         assert!(!FASTA_N.identity(&FASTA_A));
     }
 
+    #[test]
+    fn test_is_degenerate() {
+        assert!(FASTA_N.is_degenerate());
+        assert!(FastaBase::from_str("RYKMSWBDHV").iter().all(|b| b.is_degenerate()));
+        assert!(!FastaBase::from_str("ACGT-").iter().any(|b| b.is_degenerate()));
+    }
+
     #[test]
     fn test_u8_to_encoding_defaulted_to_n() {
         assert_eq!(FASTA_A, u8_to_encoding_defaulted_to_n(&b'A'));
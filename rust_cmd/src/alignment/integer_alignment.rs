@@ -0,0 +1,1053 @@
+use std::cmp::{max, min};
+
+use crate::alignment::alignment_matrix::{AlignmentLocation, AlignmentResult, AlignmentTag, MAX_NEG_SCORE, diagonal_band};
+use crate::alignment::fasta_bit_encoding::{FASTA_UNSET, FastaBase};
+use crate::alignment::scoring_functions::{AffineScoring, QUALITY_WEIGHT_STEPS, QualityWeighting};
+use crate::alignment_manager::simplify_cigar_string;
+
+// traceback moves, numbered by the matrix they come from: match (0), deletion (1, moving up), and insertion (2, moving left)
+const DIAG: u8 = 0;
+const UP: u8 = 1;
+const LEFT: u8 = 2;
+
+/// the largest scaling we'll try when turning the floating point scores into integers
+const MAX_SCORE_SCALE: i32 = 256;
+
+/// An `AffineScoring` with every score multiplied by a power of two so they're all whole numbers. Dyadic scores like
+/// -0.5 or -0.25 are exact in floating point, so integer scores divided by the scale are exactly what the f64 aligner
+/// computes, and both aligners make the same decisions on every tie
+#[derive(Clone, Debug, PartialEq)]
+pub struct IntegerAffineScoring {
+    pub scale: i32,
+    match_score: i32,
+    mismatch_score: i32,
+    special_character_score: i32,
+    gap_open: i32,
+    gap_extend: i32,
+    final_gap_open: i32,
+    final_gap_extend: i32,
+    max_neg_score: i32,
+    quality_weighting: Option<QualityWeighting>,
+}
+
+impl IntegerAffineScoring {
+    /// Scale the scoring to integers, or None if no power of two up to `MAX_SCORE_SCALE` makes every score whole (a
+    /// final gap multiplier of 1/3, for example), in which case the f64 aligner should be used
+    pub fn from_affine(scoring: &AffineScoring) -> Option<IntegerAffineScoring> {
+        let mut values = vec![scoring.match_score, scoring.mismatch_score, scoring.special_character_score, scoring.gap_open,
+            scoring.gap_extend, scoring.gap_open * scoring.final_gap_multiplier, scoring.gap_extend * scoring.final_gap_multiplier];
+        // quality weights are in sixteenths, so the base scores have to stay whole numbers after dividing by sixteen
+        if scoring.quality_weighting.is_some() {
+            let steps = QUALITY_WEIGHT_STEPS as f64;
+            values.extend([scoring.match_score / steps, scoring.mismatch_score / steps, scoring.special_character_score / steps]);
+        }
+
+        let mut scale = 1;
+        while scale <= MAX_SCORE_SCALE {
+            let scaled = values.iter().map(|v| v * scale as f64).collect::<Vec<f64>>();
+            if scaled.iter().all(|v| v.fract() == 0.0 && v.abs() < (1 << 20) as f64) {
+                return Some(IntegerAffineScoring {
+                    scale,
+                    match_score: scaled[0] as i32,
+                    mismatch_score: scaled[1] as i32,
+                    special_character_score: scaled[2] as i32,
+                    gap_open: scaled[3] as i32,
+                    gap_extend: scaled[4] as i32,
+                    final_gap_open: scaled[5] as i32,
+                    final_gap_extend: scaled[6] as i32,
+                    max_neg_score: MAX_NEG_SCORE as i32 * scale,
+                    quality_weighting: scoring.quality_weighting.clone(),
+                });
+            }
+            scale *= 2;
+        }
+        None
+    }
+
+    /// the same rules as `AffineScoring::match_mismatch`
+    fn match_mismatch(&self, bit_a: &FastaBase, bit_b: &FastaBase) -> i32 {
+        if bit_a == bit_b && (bit_a.is_degenerate() || bit_b.is_degenerate()) { self.special_character_score } else if bit_a == bit_b { self.match_score } else { self.mismatch_score }
+    }
+
+    /// the same rules as `AffineScoring::quality_match_mismatch`
+    fn quality_match_mismatch(&self, bit_a: &FastaBase, bit_b: &FastaBase, quality: Option<&u8>) -> i32 {
+        match (&self.quality_weighting, quality) {
+            (Some(weighting), Some(quality)) => self.match_mismatch(bit_a, bit_b) * weighting.steps(*quality) as i32 / QUALITY_WEIGHT_STEPS as i32,
+            _ => self.match_mismatch(bit_a, bit_b),
+        }
+    }
+
+    /// the score of a gap of `length` along the first row or column, which always gets the final gap multiplier
+    fn edge_gap(&self, length: usize) -> i32 {
+        self.final_gap_open + (length as i32 * self.final_gap_extend)
+    }
+
+    fn unscale(&self, score: i32) -> f64 {
+        score as f64 / self.scale as f64
+    }
+}
+
+/// This matches `three_way_max_and_direction`, tie-breaking included
+#[inline(always)]
+fn three_way_max(up_value: i32, left_value: i32, diag_value: i32) -> (i32, u8) {
+    if up_value > left_value {
+        if up_value > diag_value { (up_value, UP) } else { (diag_value, DIAG) }
+    } else if left_value > diag_value {
+        (left_value, LEFT)
+    } else {
+        (diag_value, DIAG)
+    }
+}
+
+/// The best local alignment end so far, using the ordering of `find_max_value_3d_array`: the highest score, then the
+/// smallest x + y, then the smallest x
+struct LocalMaximum {
+    location: AlignmentLocation,
+    scores: [i32; 3],
+    best: i32,
+}
+
+impl LocalMaximum {
+    fn consider(&mut self, x: usize, y: usize, scores: [i32; 3]) {
+        for score in scores {
+            if score > self.best ||
+                (score == self.best && (x + y) < (self.location.x + self.location.y)) ||
+                (score == self.best && (x + y) == (self.location.x + self.location.y) && x < self.location.x) {
+                self.best = score;
+                self.location = AlignmentLocation { x, y };
+                self.scores = scores;
+            }
+        }
+    }
+}
+
+/// Where a semi-global alignment ends, with the ordering of `perform_3d_global_traceback`: the best score in the last
+/// column, the earliest row on ties, and the last row if nothing beats the most negative score
+struct SemiGlobalEnd {
+    x: usize,
+    scores: [i32; 3],
+    best: i32,
+}
+
+impl SemiGlobalEnd {
+    fn consider(&mut self, x: usize, scores: [i32; 3]) {
+        let row_best = *scores.iter().max().unwrap();
+        if row_best > self.best {
+            self.best = row_best;
+            self.x = x;
+            self.scores = scores;
+        }
+    }
+}
+
+/// The traceback for each band cell packs the 2-bit move of all three matrices into a byte. Local alignments also need
+/// to know where each matrix scored zero, which takes another three bits per cell. Rows are stored from `first_row` on,
+/// so we can hold the traceback for just part of the matrix
+struct BandedTraceback {
+    first_row: usize,
+    row_start: Vec<usize>,
+    row_end: Vec<usize>,
+    row_offset: Vec<usize>,
+    moves: Vec<u8>,
+    zeros: Vec<u8>,
+}
+
+impl BandedTraceback {
+    fn new(first_row: usize) -> BandedTraceback {
+        BandedTraceback { first_row, row_start: Vec::new(), row_end: Vec::new(), row_offset: Vec::new(), moves: Vec::new(), zeros: Vec::new() }
+    }
+
+    fn reset(&mut self, first_row: usize) {
+        self.first_row = first_row;
+        self.row_start.clear();
+        self.row_end.clear();
+        self.row_offset.clear();
+        self.moves.clear();
+        self.zeros.clear();
+    }
+
+    fn add_row(&mut self, start: usize, end: usize) {
+        self.row_start.push(start);
+        self.row_end.push(end);
+        self.row_offset.push(self.moves.len());
+    }
+
+    fn index(&self, x: usize, y: usize) -> Option<usize> {
+        if x < self.first_row || x - self.first_row >= self.row_start.len() {
+            return None;
+        }
+        let row = x - self.first_row;
+        if y < self.row_start[row] || y >= self.row_end[row] {
+            None
+        } else {
+            Some(self.row_offset[row] + y - self.row_start[row])
+        }
+    }
+
+    fn next_matrix(&self, x: usize, y: usize, matrix: usize) -> usize {
+        match self.index(x, y) {
+            None => DIAG as usize,
+            Some(index) => ((self.moves[index] >> (matrix * 2)) & 0b11) as usize,
+        }
+    }
+
+    fn is_zero(&self, x: usize, y: usize, matrix: usize) -> bool {
+        match self.index(x, y) {
+            None => false,
+            Some(index) => (self.zeros[index] >> matrix) & 1 == 1,
+        }
+    }
+}
+
+/// the number of cells the kernel works on at once: eight i32 lanes fill an AVX2 register, or two SSE/NEON registers
+const LANES: usize = 8;
+
+/// one vector's worth of scores. The lane loops below have a fixed trip count and no branches, so the compiler turns
+/// each of them into vector instructions
+type Lanes = [i32; LANES];
+
+/// padding for the striped lanes past the end of the band; low enough to never win a max, high enough not to overflow
+const PAD_SCORE: i32 = i32::MIN / 4;
+
+#[inline(always)]
+fn load_lanes(values: &[i32], start: usize) -> Lanes {
+    values[start..(start + LANES)].try_into().unwrap()
+}
+
+#[inline(always)]
+fn add_lanes(values: &Lanes, addend: i32) -> Lanes {
+    let mut sum = [0; LANES];
+    for lane in 0..LANES {
+        sum[lane] = values[lane] + addend;
+    }
+    sum
+}
+
+#[inline(always)]
+fn add_lanes_lanes(values: &Lanes, addends: &Lanes) -> Lanes {
+    let mut sum = [0; LANES];
+    for lane in 0..LANES {
+        sum[lane] = values[lane] + addends[lane];
+    }
+    sum
+}
+
+#[inline(always)]
+fn max_lanes(first: &Lanes, second: &Lanes) -> Lanes {
+    let mut best = [0; LANES];
+    for lane in 0..LANES {
+        best[lane] = max(first[lane], second[lane]);
+    }
+    best
+}
+
+/// `three_way_max` on every lane, written as compares and selects so it vectorizes
+#[inline(always)]
+fn three_way_max_lanes(up_value: &Lanes, left_value: &Lanes, diag_value: &Lanes, best: &mut [i32], moves: &mut [u8]) {
+    for lane in 0..LANES {
+        let up_wins = up_value[lane] > left_value[lane] && up_value[lane] > diag_value[lane];
+        let left_wins = up_value[lane] <= left_value[lane] && left_value[lane] > diag_value[lane];
+        best[lane] = if up_wins { up_value[lane] } else if left_wins { left_value[lane] } else { diag_value[lane] };
+        moves[lane] = if up_wins { UP } else if left_wins { LEFT } else { DIAG };
+    }
+}
+
+/// one row of scores for each of the match, deletion, and insertion matrices, and the columns [start, end) an earlier
+/// row left values in; everything else past column zero holds the most negative score
+#[derive(Clone)]
+struct ScoreRow {
+    matches: Vec<i32>,
+    deletions: Vec<i32>,
+    insertions: Vec<i32>,
+    written: (usize, usize),
+}
+
+impl ScoreRow {
+    fn first_row(scoring: &IntegerAffineScoring, columns: usize) -> ScoreRow {
+        let mut row = ScoreRow {
+            matches: vec![scoring.max_neg_score; columns + 1],
+            deletions: (0..(columns + 1)).map(|y| scoring.edge_gap(y)).collect(),
+            insertions: (0..(columns + 1)).map(|y| scoring.edge_gap(y)).collect(),
+            written: (1, columns + 1),
+        };
+        row.matches[0] = 0;
+        row.deletions[0] = scoring.max_neg_score;
+        row.insertions[0] = scoring.max_neg_score;
+        row
+    }
+
+    fn empty(scoring: &IntegerAffineScoring, columns: usize) -> ScoreRow {
+        ScoreRow {
+            matches: vec![scoring.max_neg_score; columns + 1],
+            deletions: vec![scoring.max_neg_score; columns + 1],
+            insertions: vec![scoring.max_neg_score; columns + 1],
+            written: (0, 0),
+        }
+    }
+
+    fn scores(&self, y: usize) -> [i32; 3] {
+        [self.matches[y], self.deletions[y], self.insertions[y]]
+    }
+
+    /// Get ready to fill the band [start, end): only the cells an earlier row wrote outside the band need resetting, so
+    /// a row costs its band rather than the whole read
+    fn start_band(&mut self, start: usize, end: usize, neg: i32) {
+        let (written_start, written_end) = self.written;
+        for y in (written_start..min(written_end, start)).chain(max(written_start, end)..written_end) {
+            self.matches[y] = neg;
+            self.deletions[y] = neg;
+            self.insertions[y] = neg;
+        }
+        self.matches[0] = neg;
+        self.written = (start, end);
+    }
+}
+
+/// Fills the rows of the DP one at a time, keeping the read's query profile (the match score of each read position
+/// against each distinct reference base, weighted by the read's qualities) and the per-row scratch space between rows
+struct RowFiller<'a> {
+    sequence1: &'a [FastaBase],
+    sequence2: &'a [FastaBase],
+    sequence2_quality: Option<&'a [u8]>,
+    scoring: &'a IntegerAffineScoring,
+    bandwidth: usize,
+    local: bool,
+    semi_global: bool,
+    profiles: Vec<(FastaBase, Vec<i32>)>,
+    match_scores: Vec<i32>,
+    match_moves: Vec<u8>,
+    del_moves: Vec<u8>,
+    striped_open: Vec<Lanes>,
+    striped_insertions: Vec<Lanes>,
+}
+
+impl<'a> RowFiller<'a> {
+    fn new(sequence1: &'a [FastaBase], sequence2: &'a [FastaBase], sequence2_quality: Option<&'a [u8]>, scoring: &'a IntegerAffineScoring, bandwidth: usize, local: bool, semi_global: bool) -> RowFiller<'a> {
+        if let Some(quality) = sequence2_quality {
+            assert_eq!(quality.len(), sequence2.len(), "The read has {} quality scores for {} bases", quality.len(), sequence2.len());
+        }
+        RowFiller {
+            sequence1,
+            sequence2,
+            sequence2_quality,
+            scoring,
+            bandwidth,
+            local,
+            semi_global: semi_global && !local,
+            profiles: Vec::new(),
+            match_scores: vec![0; sequence2.len() + 1],
+            match_moves: vec![DIAG; sequence2.len() + 1],
+            del_moves: vec![DIAG; sequence2.len() + 1],
+            striped_open: Vec::new(),
+            striped_insertions: Vec::new(),
+        }
+    }
+
+    /// the match and deletion scores of cell `y`, one cell at a time, for the ragged end of the band and the last column
+    fn match_and_deletion(&mut self, y: usize, gap_open: i32, del_extend: i32, prev: &ScoreRow, cur: &mut ScoreRow) {
+        let match_score = self.match_scores[y];
+        let diag_value = if self.local {
+            three_way_max(0, prev.matches[y - 1] + match_score, match_score).0
+        } else {
+            prev.matches[y - 1] + match_score
+        };
+        let (best_match, match_move) = three_way_max(prev.deletions[y - 1] + match_score, prev.insertions[y - 1] + match_score, diag_value);
+        cur.matches[y] = best_match;
+        self.match_moves[y] = match_move;
+
+        let (best_del, del_move) = three_way_max(prev.deletions[y] + del_extend, prev.insertions[y] + gap_open, prev.matches[y] + gap_open);
+        cur.deletions[y] = best_del;
+        self.del_moves[y] = del_move;
+    }
+
+    /// Striped (Farrar) insertion scores for the columns [start, end), which all share the same gap costs. An insertion
+    /// is the best of opening a gap from the cell to the left or extending the insertion there, so it runs along the
+    /// row. We split the columns into `LANES` runs of `segments` cells, one run per lane, so each vector holds cells
+    /// `segments` apart and the lanes never depend on each other within a pass. The first pass ignores what flows into
+    /// each run from the end of the one before; the lazy-F passes then push those values through, stopping as soon as
+    /// no lane improves. Integer scores make the result exactly the serial recurrence's
+    fn fill_insertions_striped(&mut self, start: usize, end: usize, gap_open: i32, ins_extend: i32, cur: &mut ScoreRow) {
+        let segments = (end - start + LANES - 1) / LANES;
+        self.striped_open.clear();
+        self.striped_open.resize(segments, [PAD_SCORE; LANES]);
+        self.striped_insertions.clear();
+        self.striped_insertions.resize(segments, [PAD_SCORE; LANES]);
+
+        // the score of opening an insertion at each cell, from the match or deletion to its left
+        for lane in 0..LANES {
+            for segment in 0..segments {
+                let y = start + lane * segments + segment;
+                if y < end {
+                    self.striped_open[segment][lane] = max(cur.deletions[y - 1], cur.matches[y - 1]) + gap_open;
+                }
+            }
+        }
+
+        // first pass: each lane's run on its own, with the cell before the band feeding the first lane
+        let mut carry = [PAD_SCORE; LANES];
+        carry[0] = cur.insertions[start - 1];
+        for segment in 0..segments {
+            let extended = add_lanes(&carry, ins_extend);
+            self.striped_insertions[segment] = max_lanes(&self.striped_open[segment], &extended);
+            carry = self.striped_insertions[segment];
+        }
+
+        // lazy-F: carry the end of each run into the start of the next lane's until nothing changes
+        loop {
+            let mut carry = [PAD_SCORE; LANES];
+            carry[1..LANES].copy_from_slice(&self.striped_insertions[segments - 1][0..(LANES - 1)]);
+            let mut reached_end = true;
+            for segment in 0..segments {
+                let extended = add_lanes(&carry, ins_extend);
+                let updated = max_lanes(&self.striped_insertions[segment], &extended);
+                if updated == self.striped_insertions[segment] {
+                    reached_end = false;
+                    break;
+                }
+                self.striped_insertions[segment] = updated;
+                carry = updated;
+            }
+            if !reached_end {
+                break;
+            }
+        }
+
+        for lane in 0..LANES {
+            for segment in 0..segments {
+                let y = start + lane * segments + segment;
+                if y < end {
+                    cur.insertions[y] = self.striped_insertions[segment][lane];
+                }
+            }
+        }
+    }
+
+    /// Fill row `x` from the row above it, adding the row's moves to the traceback. The match and deletion matrices only
+    /// depend on the row above, so we fill them `LANES` cells at a time straight along the band. The insertion matrix
+    /// depends on the cell to its left, so it's filled with the striped kernel, and its moves are read back off the
+    /// finished scores. The last column has its own (final) gap costs, so it's filled one cell at a time
+    fn fill_row(&mut self, x: usize, prev: &ScoreRow, cur: &mut ScoreRow, traceback: &mut BandedTraceback, local_maximum: &mut LocalMaximum) {
+        let rows = self.sequence1.len();
+        let columns = self.sequence2.len();
+        let scoring = self.scoring;
+        let local = self.local;
+        let neg = scoring.max_neg_score;
+        let (start, end) = if self.semi_global { (1, columns + 1) } else { diagonal_band(x, rows, columns, &self.bandwidth) };
+
+        let base = self.sequence1[x - 1];
+        let profile = match self.profiles.iter().position(|(b, _)| b.identity(&base)) {
+            Some(index) => index,
+            None => {
+                let quality = self.sequence2_quality;
+                self.profiles.push((base, self.sequence2.iter().enumerate().map(|(y, read_base)| scoring.quality_match_mismatch(&base, read_base, quality.map(|quality| &quality[y]))).collect()));
+                self.profiles.len() - 1
+            }
+        };
+        if start < end {
+            self.match_scores[start..end].copy_from_slice(&self.profiles[profile].1[(start - 1)..(end - 1)]);
+        }
+
+        cur.start_band(start, end, neg);
+        cur.deletions[0] = if self.semi_global { 0 } else { scoring.edge_gap(x) };
+        cur.insertions[0] = scoring.edge_gap(x);
+        if local {
+            local_maximum.consider(x, 0, cur.scores(0));
+        }
+
+        // the final gap multiplier applies along the last row and column
+        let (row_gap_open, row_gap_extend) = if x == rows {
+            (scoring.gap_open + scoring.final_gap_extend, scoring.final_gap_extend)
+        } else {
+            (scoring.gap_open + scoring.gap_extend, scoring.gap_extend)
+        };
+        let gap_costs = |y: usize| if y == columns {
+            (scoring.gap_open + scoring.final_gap_extend, scoring.final_gap_extend)
+        } else {
+            (row_gap_open, row_gap_extend)
+        };
+        let gap_extend_for = |gap_extend: i32| if local { scoring.gap_extend } else { gap_extend };
+
+        // every column but the last shares the row's gap costs
+        let shared_end = min(end, columns);
+        let shared_extend = gap_extend_for(row_gap_extend);
+
+        // match and deletion from the row above, a vector at a time
+        let mut y = start;
+        while y + LANES <= shared_end {
+            let match_score = load_lanes(&self.match_scores, y);
+            let prev_matches = load_lanes(&prev.matches, y - 1);
+            let diagonal = add_lanes_lanes(&prev_matches, &match_score);
+            let diag_value = if local { max_lanes(&max_lanes(&[0; LANES], &diagonal), &match_score) } else { diagonal };
+            let from_deletion = add_lanes_lanes(&load_lanes(&prev.deletions, y - 1), &match_score);
+            let from_insertion = add_lanes_lanes(&load_lanes(&prev.insertions, y - 1), &match_score);
+            three_way_max_lanes(&from_deletion, &from_insertion, &diag_value, &mut cur.matches[y..(y + LANES)], &mut self.match_moves[y..(y + LANES)]);
+
+            let extend_deletion = add_lanes(&load_lanes(&prev.deletions, y), shared_extend);
+            let open_from_insertion = add_lanes(&load_lanes(&prev.insertions, y), row_gap_open);
+            let open_from_match = add_lanes(&load_lanes(&prev.matches, y), row_gap_open);
+            three_way_max_lanes(&extend_deletion, &open_from_insertion, &open_from_match, &mut cur.deletions[y..(y + LANES)], &mut self.del_moves[y..(y + LANES)]);
+            y += LANES;
+        }
+        for y in y..end {
+            let (gap_open, gap_extend) = gap_costs(y);
+            self.match_and_deletion(y, gap_open, gap_extend_for(gap_extend), prev, cur);
+        }
+
+        // insertions along the row: striped over the shared columns, then the last column on its own
+        if start < shared_end {
+            self.fill_insertions_striped(start, shared_end, row_gap_open, shared_extend, cur);
+        }
+        if start < end && shared_end < end {
+            let (gap_open, gap_extend) = gap_costs(columns);
+            cur.insertions[columns] = three_way_max(cur.deletions[columns - 1] + gap_open, cur.insertions[columns - 1] + gap_extend_for(gap_extend), cur.matches[columns - 1] + gap_open).0;
+        }
+
+        // the insertion moves, from the finished scores, and the row's traceback
+        traceback.add_row(start, end);
+        for y in start..end {
+            let (gap_open, gap_extend) = gap_costs(y);
+            let ins_move = three_way_max(cur.deletions[y - 1] + gap_open, cur.insertions[y - 1] + gap_extend_for(gap_extend), cur.matches[y - 1] + gap_open).1;
+            traceback.moves.push(self.match_moves[y] | (self.del_moves[y] << 2) | (ins_move << 4));
+            if local {
+                traceback.zeros.push((cur.matches[y] == 0) as u8 | (((cur.deletions[y] == 0) as u8) << 1) | (((cur.insertions[y] == 0) as u8) << 2));
+                local_maximum.consider(x, y, cur.scores(y));
+            }
+        }
+    }
+}
+
+/// The alignment we've traced back so far, built end first like `perform_3d_global_traceback`
+struct AlignmentTrace {
+    x: usize,
+    y: usize,
+    matrix: usize,
+    score: i32,
+    alignment1: Vec<FastaBase>,
+    alignment2: Vec<FastaBase>,
+    cigars: Vec<AlignmentTag>,
+    path: Vec<AlignmentLocation>,
+}
+
+impl AlignmentTrace {
+    fn new(x: usize, y: usize, end_scores: [i32; 3]) -> AlignmentTrace {
+        // like the f64 traceback, take the last of any tied matrices
+        let matrix = (0..3).fold(0, |best, z| if end_scores[z] >= end_scores[best] { z } else { best });
+        AlignmentTrace {
+            x,
+            y,
+            matrix,
+            score: end_scores[matrix],
+            alignment1: Vec::with_capacity(x * 2),
+            alignment2: Vec::with_capacity(x * 2),
+            cigars: Vec::with_capacity(100),
+            path: Vec::new(),
+        }
+    }
+
+    fn step(&mut self, traceback: &BandedTraceback, sequence1: &[FastaBase], sequence2: &[FastaBase]) {
+        self.path.push(AlignmentLocation { x: self.x, y: self.y });
+        let next_matrix = traceback.next_matrix(self.x, self.y, self.matrix);
+        match self.matrix {
+            0 => {
+                self.cigars.push(AlignmentTag::MatchMismatch(1));
+                self.alignment1.push(sequence1[self.x - 1]);
+                self.alignment2.push(sequence2[self.y - 1]);
+                self.x -= 1;
+                self.y -= 1;
+            }
+            1 => {
+                self.cigars.push(AlignmentTag::Del(1));
+                self.alignment1.push(sequence1[self.x - 1]);
+                self.alignment2.push(FASTA_UNSET);
+                self.x -= 1;
+            }
+            _ => {
+                self.cigars.push(AlignmentTag::Ins(1));
+                self.alignment1.push(FASTA_UNSET);
+                self.alignment2.push(sequence2[self.y - 1]);
+                self.y -= 1;
+            }
+        }
+        self.matrix = next_matrix;
+    }
+
+    fn into_result(mut self,
+                   local: bool,
+                   semi_global: bool,
+                   scoring: &IntegerAffineScoring,
+                   sequence1: &[FastaBase],
+                   sequence2: &[FastaBase],
+                   sequence1_name: &String,
+                   sequence2_name: &String,
+                   read_quality: Option<Vec<u8>>) -> AlignmentResult {
+        // semi-global alignments start where the read does, rather than with deletions of the reference before it
+        let reference_start = if semi_global && !local { self.x } else { 0 };
+        while self.x > 0 && !local && !semi_global {
+            self.alignment1.push(sequence1[self.x - 1]);
+            self.alignment2.push(FASTA_UNSET);
+            self.x -= 1;
+            self.cigars.push(AlignmentTag::Del(1));
+        }
+        while self.y > 0 && !local {
+            self.alignment1.push(FASTA_UNSET);
+            self.alignment2.push(sequence2[self.y - 1]);
+            self.y -= 1;
+            self.cigars.push(AlignmentTag::Ins(1));
+        }
+        self.alignment1.reverse();
+        self.alignment2.reverse();
+        self.path.reverse();
+        self.cigars.reverse();
+
+        AlignmentResult {
+            reference_name: sequence1_name.clone(),
+            read_name: sequence2_name.clone(),
+            reference_aligned: self.alignment1,
+            read_aligned: self.alignment2,
+            read_quals: read_quality,
+            cigar_string: simplify_cigar_string(&self.cigars),
+            path: self.path,
+            score: scoring.unscale(self.score),
+            reference_start,
+            read_start: 0,
+            bounding_box: None,
+        }
+    }
+}
+
+/// Banded affine alignment of `sequence1` (the reference) and `sequence2` (the read) with integer scores, producing the same
+/// `AlignmentResult` as `perform_affine_alignment_bandwidth` followed by `perform_3d_global_traceback` on a freshly created
+/// matrix. Instead of the three f64 score and `AlignmentDirection` cubes, we keep two rows of integer scores per matrix
+/// and a byte of traceback per band cell, so memory grows with the band rather than the full reference by read matrix,
+/// and each row is filled a vector at a time (see `RowFiller::fill_row`).
+/// With `semi_global` (and not `local`) the gaps before and after the read in the reference are free.
+pub fn integer_affine_alignment(sequence1: &[FastaBase],
+                                sequence2: &[FastaBase],
+                                scoring: &IntegerAffineScoring,
+                                bandwidth: &usize,
+                                local: bool,
+                                semi_global: bool,
+                                sequence1_name: &String,
+                                sequence2_name: &String,
+                                read_quality: Option<Vec<u8>>) -> AlignmentResult {
+    let columns = sequence2.len();
+    let semi_global = semi_global && !local;
+    let mut filler = RowFiller::new(sequence1, sequence2, read_quality.as_deref(), scoring, *bandwidth, local, semi_global);
+    let mut traceback = BandedTraceback::new(1);
+    let mut local_maximum = LocalMaximum { location: AlignmentLocation { x: 0, y: 0 }, scores: [0, scoring.max_neg_score, scoring.max_neg_score], best: 0 };
+    let mut semi_global_end = SemiGlobalEnd { x: sequence1.len(), scores: [scoring.max_neg_score; 3], best: scoring.max_neg_score };
+
+    let mut prev = ScoreRow::first_row(scoring, columns);
+    let mut cur = ScoreRow::empty(scoring, columns);
+    if local {
+        (1..(columns + 1)).for_each(|y| local_maximum.consider(0, y, prev.scores(y)));
+    }
+    semi_global_end.consider(0, prev.scores(columns));
+
+    for x in 1..(sequence1.len() + 1) {
+        filler.fill_row(x, &prev, &mut cur, &mut traceback, &mut local_maximum);
+        semi_global_end.consider(x, cur.scores(columns));
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    let mut trace = if local {
+        AlignmentTrace::new(local_maximum.location.x, local_maximum.location.y, local_maximum.scores)
+    } else if semi_global {
+        AlignmentTrace::new(semi_global_end.x, columns, semi_global_end.scores)
+    } else {
+        AlignmentTrace::new(sequence1.len(), columns, prev.scores(columns))
+    };
+    while trace.x > 0 && trace.y > 0 && (!local || !traceback.is_zero(trace.x, trace.y, trace.matrix)) {
+        trace.step(&traceback, sequence1, sequence2);
+    }
+    trace.into_result(local, semi_global, scoring, sequence1, sequence2, sequence1_name, sequence2_name, read_quality)
+}
+
+/// where a stretch of rows starts its traceback path: the first row of the whole matrix, a single cell of the path
+/// with its score, or nowhere, when the path reaches the first column before it gets back to the stretch's first row
+#[derive(Clone, Copy)]
+enum SegmentEntry {
+    FirstRow,
+    Cell { y: usize, matrix: usize, score: i32 },
+    Nowhere,
+}
+
+/// marks a cell whose traceback path reaches the first column before the middle row
+const NO_CROSSING: usize = usize::MAX;
+
+/// traceback blocks this small are filled outright rather than split further; it's a constant, so memory stays O(n + m)
+const CHECKPOINTED_BLOCK_CELLS: usize = 1 << 22;
+
+/// The divide and conquer state for `checkpointed_affine_alignment`
+struct CheckpointedAligner<'a> {
+    filler: RowFiller<'a>,
+    scoring: &'a IntegerAffineScoring,
+    traceback: BandedTraceback,
+    unused_maximum: LocalMaximum,
+    columns: usize,
+    block_cells: usize,
+}
+
+impl<'a> CheckpointedAligner<'a> {
+    /// The row a stretch starts from. Past the first row only the entry cell keeps its score; every other cell can then
+    /// only score lower than in the full matrix, while the cells along the path score the same and so pick the same moves
+    fn entry_row(&self, entry: SegmentEntry) -> ScoreRow {
+        match entry {
+            SegmentEntry::FirstRow => ScoreRow::first_row(self.scoring, self.columns),
+            _ => {
+                let mut row = ScoreRow {
+                    matches: vec![PAD_SCORE; self.columns + 1],
+                    deletions: vec![PAD_SCORE; self.columns + 1],
+                    insertions: vec![PAD_SCORE; self.columns + 1],
+                    written: (1, self.columns + 1),
+                };
+                if let SegmentEntry::Cell { y, matrix, score } = entry {
+                    match matrix {
+                        0 => row.matches[y] = score,
+                        1 => row.deletions[y] = score,
+                        _ => row.insertions[y] = score,
+                    }
+                }
+                row
+            }
+        }
+    }
+
+    /// Fill rows (start, end] and find where the path back from `end_cell` on the last row first reaches row `middle`.
+    /// Past the middle row each cell carries the middle row cell its own path reaches, following its moves, so this
+    /// takes a few rows of memory rather than a traceback
+    fn middle_crossing(&mut self, start: usize, entry: SegmentEntry, end: usize, middle: usize, end_cell: (usize, usize)) -> SegmentEntry {
+        let columns = self.columns;
+        let mut prev = self.entry_row(entry);
+        let mut cur = ScoreRow::empty(self.scoring, columns);
+        let mut prev_labels = [vec![NO_CROSSING; columns + 1], vec![NO_CROSSING; columns + 1], vec![NO_CROSSING; columns + 1]];
+        let mut cur_labels = prev_labels.clone();
+        let mut middle_row = None;
+
+        for x in (start + 1)..(end + 1) {
+            self.traceback.reset(x);
+            self.filler.fill_row(x, &prev, &mut cur, &mut self.traceback, &mut self.unused_maximum);
+            if x == middle {
+                for (matrix, labels) in cur_labels.iter_mut().enumerate() {
+                    for (y, label) in labels.iter_mut().enumerate() {
+                        *label = y * 3 + matrix;
+                    }
+                }
+                middle_row = Some(cur.clone());
+            } else if x > middle {
+                for matrix in 0..3 {
+                    cur_labels[matrix][0] = NO_CROSSING;
+                }
+                for y in 1..(columns + 1) {
+                    for matrix in 0..3 {
+                        let next_matrix = self.traceback.next_matrix(x, y, matrix);
+                        let label = match matrix {
+                            0 => prev_labels[next_matrix][y - 1],
+                            1 => prev_labels[next_matrix][y],
+                            _ => cur_labels[next_matrix][y - 1],
+                        };
+                        cur_labels[matrix][y] = label;
+                    }
+                }
+            }
+            std::mem::swap(&mut prev, &mut cur);
+            std::mem::swap(&mut prev_labels, &mut cur_labels);
+        }
+
+        let label = prev_labels[end_cell.1][end_cell.0];
+        if label == NO_CROSSING {
+            SegmentEntry::Nowhere
+        } else {
+            let (y, matrix) = (label / 3, label % 3);
+            SegmentEntry::Cell { y, matrix, score: middle_row.unwrap().scores(y)[matrix] }
+        }
+    }
+
+    /// Trace the path back from row `end` to row `start`: small stretches are filled with their moves and stepped
+    /// through, larger ones are split at the row where the path crosses their middle, the later half first
+    fn trace_segment(&mut self, start: usize, entry: SegmentEntry, end: usize, trace: &mut AlignmentTrace) {
+        if trace.x <= start || trace.y == 0 {
+            return;
+        }
+        if end - start <= 1 || (end - start) * (self.columns + 1) <= self.block_cells {
+            let mut prev = self.entry_row(entry);
+            let mut cur = ScoreRow::empty(self.scoring, self.columns);
+            self.traceback.reset(start + 1);
+            for x in (start + 1)..(end + 1) {
+                self.filler.fill_row(x, &prev, &mut cur, &mut self.traceback, &mut self.unused_maximum);
+                std::mem::swap(&mut prev, &mut cur);
+            }
+            while trace.x > start && trace.y > 0 {
+                trace.step(&self.traceback, self.filler.sequence1, self.filler.sequence2);
+            }
+            return;
+        }
+
+        let middle = (start + end) / 2;
+        let crossing = self.middle_crossing(start, entry, end, middle, (trace.y, trace.matrix));
+        self.trace_segment(middle, crossing, end, trace);
+        self.trace_segment(start, entry, middle, trace);
+    }
+}
+
+/// The same global (or semi-global) alignment as `integer_affine_alignment`, in O(n + m) memory for reads and references
+/// too long to hold a traceback for the whole band. After one pass to find where the alignment ends, it's divide and
+/// conquer over the rows as in Myers-Miller: a pass over a stretch of rows finds the cell where the traceback path
+/// crosses the stretch's middle row, and we recurse on the two halves until a block is small enough to hold its moves.
+/// Unlike Myers-Miller we split on the path the one-pass traceback takes rather than any optimal one, so the alignment,
+/// tie-breaking included, matches the other aligners exactly. That costs O(log n) passes rather than one
+pub fn checkpointed_affine_alignment(sequence1: &[FastaBase],
+                                      sequence2: &[FastaBase],
+                                      scoring: &IntegerAffineScoring,
+                                      bandwidth: &usize,
+                                      semi_global: bool,
+                                      sequence1_name: &String,
+                                      sequence2_name: &String,
+                                      read_quality: Option<Vec<u8>>) -> AlignmentResult {
+    let block_cells = max(sequence1.len() + sequence2.len() + 1, CHECKPOINTED_BLOCK_CELLS);
+    checkpointed_alignment_in_blocks(sequence1, sequence2, scoring, bandwidth, semi_global, sequence1_name, sequence2_name, read_quality, block_cells)
+}
+
+fn checkpointed_alignment_in_blocks(sequence1: &[FastaBase],
+                                    sequence2: &[FastaBase],
+                                    scoring: &IntegerAffineScoring,
+                                    bandwidth: &usize,
+                                    semi_global: bool,
+                                    sequence1_name: &String,
+                                    sequence2_name: &String,
+                                    read_quality: Option<Vec<u8>>,
+                                    block_cells: usize) -> AlignmentResult {
+    let rows = sequence1.len();
+    let columns = sequence2.len();
+    let mut aligner = CheckpointedAligner {
+        filler: RowFiller::new(sequence1, sequence2, read_quality.as_deref(), scoring, *bandwidth, false, semi_global),
+        scoring,
+        traceback: BandedTraceback::new(1),
+        unused_maximum: LocalMaximum { location: AlignmentLocation { x: 0, y: 0 }, scores: [0; 3], best: 0 },
+        columns,
+        block_cells,
+    };
+    let mut semi_global_end = SemiGlobalEnd { x: rows, scores: [scoring.max_neg_score; 3], best: scoring.max_neg_score };
+
+    // find where the alignment ends, keeping only the last row
+    let mut prev = ScoreRow::first_row(scoring, columns);
+    let mut cur = ScoreRow::empty(scoring, columns);
+    semi_global_end.consider(0, prev.scores(columns));
+    for x in 1..(rows + 1) {
+        aligner.traceback.reset(x);
+        aligner.filler.fill_row(x, &prev, &mut cur, &mut aligner.traceback, &mut aligner.unused_maximum);
+        semi_global_end.consider(x, cur.scores(columns));
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    let mut trace = if semi_global {
+        AlignmentTrace::new(semi_global_end.x, columns, semi_global_end.scores)
+    } else {
+        AlignmentTrace::new(rows, columns, prev.scores(columns))
+    };
+    let end = trace.x;
+    aligner.trace_segment(0, SegmentEntry::FirstRow, end, &mut trace);
+    trace.into_result(false, semi_global, scoring, sequence1, sequence2, sequence1_name, sequence2_name, read_quality)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_strategies::sequence_layout::QualityCurve;
+    use crate::alignment::alignment_matrix::{AlignmentType, create_scoring_record_3d, perform_3d_global_traceback, perform_affine_alignment_bandwidth};
+
+    fn str_to_fasta_vec(input: &str) -> Vec<FastaBase> {
+        FastaBase::from_vec_u8_default_ns(&input.as_bytes().to_vec())
+    }
+
+    fn scoring(match_score: f64, mismatch_score: f64, special_character_score: f64, gap_open: f64, gap_extend: f64, final_gap_multiplier: f64) -> AffineScoring {
+        AffineScoring { match_score, mismatch_score, special_character_score, gap_open, gap_extend, final_gap_multiplier, quality_weighting: None }
+    }
+
+    /// run both aligners and compare their serialized results, so every field has to match byte for byte
+    fn assert_same_alignment(reference: &str, read: &str, affine: &AffineScoring, local: bool) {
+        assert_same_alignment_mode(reference, read, affine, local, false);
+    }
+
+    fn assert_same_alignment_mode(reference: &str, read: &str, affine: &AffineScoring, local: bool, semi_global: bool) -> AlignmentResult {
+        assert_same_alignment_quality(reference, read, &vec![b'I'; read.len()], affine, local, semi_global)
+    }
+
+    fn assert_same_alignment_quality(reference: &str, read: &str, quality: &[u8], affine: &AffineScoring, local: bool, semi_global: bool) -> AlignmentResult {
+        let reference = str_to_fasta_vec(reference);
+        let read = str_to_fasta_vec(read);
+        let bandwidth = max(reference.len(), read.len());
+        let name = &"reference_name".to_string();
+        let read_name = &"read_name".to_string();
+
+        let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, read.len() + 1, AlignmentType::Affine, local);
+        alignment_mat.is_semi_global = semi_global;
+        perform_affine_alignment_bandwidth(&mut alignment_mat, &reference, &read, Some(quality), affine, &bandwidth);
+        let expected = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &read, name, read_name, Some(quality.to_vec()), None);
+
+        let integer_scoring = IntegerAffineScoring::from_affine(affine).unwrap();
+        let result = integer_affine_alignment(&reference, &read, &integer_scoring, &bandwidth, local, semi_global, name, read_name, Some(quality.to_vec()));
+
+        assert_eq!(serde_json::to_string(&result).unwrap(), serde_json::to_string(&expected).unwrap());
+
+        if !local {
+            let checkpointed = checkpointed_affine_alignment(&reference, &read, &integer_scoring, &bandwidth, semi_global, name, read_name, Some(quality.to_vec()));
+            assert_eq!(serde_json::to_string(&checkpointed).unwrap(), serde_json::to_string(&expected).unwrap());
+
+            // split all the way down to single rows, so every case goes through the middle row crossings
+            let split = checkpointed_alignment_in_blocks(&reference, &read, &integer_scoring, &bandwidth, semi_global, name, read_name, Some(quality.to_vec()), 0);
+            assert_eq!(serde_json::to_string(&split).unwrap(), serde_json::to_string(&expected).unwrap());
+        }
+        expected
+    }
+
+    #[test]
+    fn test_integer_scaling() {
+        assert_eq!(IntegerAffineScoring::from_affine(&AffineScoring::default_reference_alignment()).unwrap().scale, 1);
+        assert_eq!(IntegerAffineScoring::from_affine(&AffineScoring::default_dna()).unwrap().scale, 4);
+        assert!(IntegerAffineScoring::from_affine(&scoring(10.0, -9.0, 9.0, -20.0, -1.0, 1.0 / 3.0)).is_none());
+    }
+
+    #[test]
+    fn test_matches_affine_aligner_global() {
+        let gap_heavy = scoring(6.0, -6.0, 5.0, -10.0, -10.0, 1.0);
+        assert_same_alignment("AAAANAAAA", "AAAAAAAA", &gap_heavy, false);
+        assert_same_alignment("ACGTACGTACGT", "ACGTACGTT", &gap_heavy, false);
+        assert_same_alignment("AAAA", "AATAA", &scoring(6.0, -6.0, 8.0, -10.0, -10.0, 1.0), false);
+        assert_same_alignment("ACGTACVNNWSNACGTACGT", "ACGTACTGTACCACTACGT", &AffineScoring::default_dna(), false);
+        assert_same_alignment("AAAAAAAA############################AGATCGGAAGAGCGTCGTGTAGGGAAAGA",
+                              "AAAAAAAAAAAAAAAAAAAAAAAAATATCTCGTTTAATTGACTCTGAAATCAAGATCGGAAGAGCGTCGTGTAGGGAAAGA", &gap_heavy, false);
+        assert_same_alignment("TTAAGCAGTGGTATCAACGCAGAGTACGCCTTAGGTTAACTTGCTATTTCTAGCTCTAACCCCACCCACGATTGCCGCCGACCCCCATATAAGAAANNNNNNNNNNNNNNNNNNNNNNNNNNAGAT",
+                              "TTAAGCAGTGGTATCAACGCAGAGTACGCCTTAGGTTAACTTGCTAGTTCTAGCTCTAACCCCACCAACAAGTTTTTCAACACCTAGCGTGT", &AffineScoring::default_dna(), false);
+        assert_same_alignment("CCAATCTACTACTGCTTGCAGTAC", "AGTCCGAGGGCTACTCTACTGAAC", &AffineScoring::default_reference_alignment(), false);
+        assert_same_alignment("ACGT", "", &AffineScoring::default_reference_alignment(), false);
+        assert_same_alignment("", "ACGT", &AffineScoring::default_dna(), false);
+    }
+
+    #[test]
+    fn test_checkpointed_long_read() {
+        // a long read with scattered indels, checked against the one-pass integer aligner
+        let reference = "ACGTTGCAAGCTTGACCATGGTACCGATCGATTGCA".repeat(40);
+        let mut read = reference.clone();
+        read.replace_range(300..306, "");
+        read.insert_str(700, "TTT");
+        read.replace_range(1100..1101, "G");
+        let reference = str_to_fasta_vec(&reference);
+        let read = str_to_fasta_vec(&read);
+        let integer_scoring = IntegerAffineScoring::from_affine(&AffineScoring::default_dna()).unwrap();
+        let name = &"reference_name".to_string();
+        let read_name = &"read_name".to_string();
+
+        for semi_global in [false, true] {
+            let expected = integer_affine_alignment(&reference, &read, &integer_scoring, &50, false, semi_global, name, read_name, None);
+            let checkpointed = checkpointed_affine_alignment(&reference, &read, &integer_scoring, &50, semi_global, name, read_name, None);
+            assert_eq!(serde_json::to_string(&checkpointed).unwrap(), serde_json::to_string(&expected).unwrap());
+
+            // blocks of a few hundred cells, so the traceback is split several levels deep
+            let split = checkpointed_alignment_in_blocks(&reference, &read, &integer_scoring, &50, semi_global, name, read_name, None, 500);
+            assert_eq!(serde_json::to_string(&split).unwrap(), serde_json::to_string(&expected).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_striped_insertions_cross_lanes() {
+        // a long insertion runs through several of the striped kernel's lanes, so the lazy-F passes have to carry it
+        let reference = "ACGTTGCAAGCTTGACCATGGTACCGATCGATTGCAGGTACCTTAGGCATCGA";
+        let read = format!("{}{}{}", &reference[0..20], "TTTTTGGGGGCCCCCAAAAATTTTTGGGGGCCCCCAAAAA", &reference[20..]);
+        assert_same_alignment(reference, &read, &AffineScoring::default_dna(), false);
+        assert_same_alignment(reference, &read, &AffineScoring::default_dna(), true);
+        assert_same_alignment_mode(reference, &read, &AffineScoring::default_dna(), false, true);
+    }
+
+    #[test]
+    fn test_narrow_band_matches_full_band() {
+        // the band moves along a long alignment, reusing the score rows, so stale cells have to be reset as it goes
+        let reference = "ACGTTGCAAGCTTGACCATGGTACCGATCGATTGCA".repeat(20);
+        let mut read = reference.clone();
+        read.replace_range(200..204, "");
+        read.insert_str(500, "GG");
+        let reference = str_to_fasta_vec(&reference);
+        let read = str_to_fasta_vec(&read);
+        let integer_scoring = IntegerAffineScoring::from_affine(&AffineScoring::default_dna()).unwrap();
+        let name = &"reference_name".to_string();
+        let read_name = &"read_name".to_string();
+
+        let full = integer_affine_alignment(&reference, &read, &integer_scoring, &reference.len(), false, false, name, read_name, None);
+        let banded = integer_affine_alignment(&reference, &read, &integer_scoring, &20, false, false, name, read_name, None);
+        assert_eq!(banded.cigar_string, full.cigar_string);
+        assert_eq!(banded.score, full.score);
+    }
+
+    #[test]
+    fn test_matches_affine_aligner_semi_global() {
+        // a read from the middle of the reference starts where it aligns, without the leading deletions
+        let aligned = assert_same_alignment_mode("TTTTTTTTTTACGTACGGATCCAAGGTTTTTTTTTTTTTTT", "ACGTACGGATCCAAGG", &AffineScoring::default_reference_alignment(), false, true);
+        assert_eq!(aligned.reference_start, 10);
+        assert_eq!(aligned.cigar_string, vec![AlignmentTag::MatchMismatch(16)]);
+        assert_eq!(FastaBase::string(&aligned.reference_aligned), "ACGTACGGATCCAAGG");
+
+        // but the read itself still has to be fully aligned
+        let aligned = assert_same_alignment_mode("TTTTTTTTTTACGTACGGATCCAAGGTTTTTTTTTTTTTTT", "GGGACGTACGGATCCAAGG", &AffineScoring::default_reference_alignment(), false, true);
+        assert_eq!(FastaBase::string(&aligned.read_aligned).replace("-", ""), "GGGACGTACGGATCCAAGG");
+
+        assert_same_alignment_mode("CCAATCTACTACTGCTTGCAGTAC", "CTACTGCTAGCAG", &AffineScoring::default_dna(), false, true);
+        assert_same_alignment_mode("AAAANAAAA", "AAAAAAAA", &scoring(6.0, -6.0, 5.0, -10.0, -10.0, 1.0), false, true);
+        assert_same_alignment_mode("ACGT", "", &AffineScoring::default_reference_alignment(), false, true);
+        assert_same_alignment_mode("", "ACGT", &AffineScoring::default_dna(), false, true);
+    }
+
+    #[test]
+    fn test_semi_global_ignores_the_band() {
+        // the read sits far off the diagonal, well outside a band of 4
+        let reference = str_to_fasta_vec("TTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTACGTACGGATCCAAGGTTTTTTTTTT");
+        let read = str_to_fasta_vec("ACGTACGGATCCAAGG");
+        let affine = AffineScoring::default_reference_alignment();
+        let integer_scoring = IntegerAffineScoring::from_affine(&affine).unwrap();
+        let name = &"reference_name".to_string();
+        let read_name = &"read_name".to_string();
+
+        let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, read.len() + 1, AlignmentType::Affine, false);
+        alignment_mat.is_semi_global = true;
+        perform_affine_alignment_bandwidth(&mut alignment_mat, &reference, &read, None, &affine, &4);
+        let expected = perform_3d_global_traceback(&mut alignment_mat, None, &reference, &read, name, read_name, None, None);
+        assert_eq!(expected.reference_start, 40);
+        assert_eq!(expected.cigar_string, vec![AlignmentTag::MatchMismatch(16)]);
+
+        let integer = integer_affine_alignment(&reference, &read, &integer_scoring, &4, false, true, name, read_name, None);
+        assert_eq!(integer.reference_start, 40);
+        assert_eq!(integer.cigar_string, vec![AlignmentTag::MatchMismatch(16)]);
+
+        let checkpointed = checkpointed_affine_alignment(&reference, &read, &integer_scoring, &4, true, name, read_name, None);
+        assert_eq!(serde_json::to_string(&checkpointed).unwrap(), serde_json::to_string(&integer).unwrap());
+    }
+
+    #[test]
+    fn test_matches_affine_aligner_quality_weighted() {
+        let mut weighted = AffineScoring::default_reference_alignment();
+        weighted.quality_weighting = Some(QualityWeighting::new(QualityCurve::Linear, 2, 30, 0.25));
+        assert_eq!(weighted.quality_weighting.as_ref().unwrap().weight(b'#'), 0.25);
+        assert_eq!(weighted.quality_weighting.as_ref().unwrap().weight(b'I'), 1.0);
+
+        // a mismatch at a low quality base costs a quarter of a full mismatch
+        let aligned = assert_same_alignment_quality("ACGTACGTAAGGCCTTACGT", "ACGTAGGTAAGGCCTTACGT", "IIIII#IIIIIIIIIIIIII".as_bytes(), &weighted, false, false);
+        assert_eq!(aligned.score, 19.0 * 10.0 - 9.0 * 0.25);
+        assert_eq!(aligned.cigar_string, vec![AlignmentTag::MatchMismatch(20)]);
+
+        // and every mode still matches the f64 aligner with qualities all over the place
+        let mut curved = AffineScoring::default_dna();
+        curved.quality_weighting = Some(QualityWeighting::new(QualityCurve::ErrorProbability, 3, 25, 0.1));
+        let read = "AGTCCGAGGGCTACTCTACTGAAC";
+        let quality = (0..read.len()).map(|i| 33 + ((i * 7) % 41) as u8).collect::<Vec<u8>>();
+        for (local, semi_global) in [(false, false), (true, false), (false, true)] {
+            assert_same_alignment_quality("CCAATCTACTACTGCTTGCAGTAC", read, &quality, &curved, local, semi_global);
+        }
+    }
+
+    #[test]
+    fn test_matches_affine_aligner_local() {
+        assert_same_alignment("CCAATCTACTACTGCTTGCAGTAC", "AGTCCGAGGGCTACTCTACTGAAC", &scoring(10.0, -9.0, 8.0, -10.0, -10.0, 1.0), true);
+        assert_same_alignment("CCAATCTACTACTGCTTGCAGTAC", "AGTCCGAGGGCTACTCTACTGAAC", &scoring(10.0, -9.0, 8.0, -20.0, -10.0, 1.0), true);
+        assert_same_alignment("CCAATCTACTACTGCTTGCA", "CACAGTACAGCGAGAGTGGC", &scoring(10.0, -11.0, 8.0, -15.0, -5.0, 1.0), true);
+        assert_same_alignment("AAAA", "TTTT", &scoring(10.0, -11.0, 8.0, -15.0, -5.0, 1.0), true);
+    }
+}
@@ -1,4 +1,7 @@
-use crate::alignment::fasta_bit_encoding::{FastaBase, FASTA_N};
+use std::cmp::min;
+
+use crate::alignment::fasta_bit_encoding::FastaBase;
+use crate::read_strategies::sequence_layout::{InversionScoringConfiguration, QualityCurve, QualityWeightingConfiguration, ScoringConfiguration, SequenceLayout};
 
 /// Trait required to instantiate a Scoring instance
 pub trait ScoringFunction {
@@ -60,6 +63,7 @@ impl ConvexScoringFunction for ConvexScoring {
 //    fn final_gap_multiplier(&self) -> f64;
 //}
 
+#[derive(Clone, Debug)]
 pub struct AffineScoring {
     pub(crate) match_score: f64,
     pub(crate) mismatch_score: f64,
@@ -67,7 +71,8 @@ pub struct AffineScoring {
     pub(crate) gap_open: f64,
     pub(crate) gap_extend: f64,
     pub(crate) final_gap_multiplier: f64,
-
+    /// scale match and mismatch scores by the read's base qualities, when it has them
+    pub(crate) quality_weighting: Option<QualityWeighting>,
 }
 
 impl AffineScoring {
@@ -80,6 +85,51 @@ impl AffineScoring {
             gap_open: -10.0,
             gap_extend: -0.5,
             final_gap_multiplier: 0.5,
+            quality_weighting: None,
+        }
+    }
+
+    /// the scoring we use when aligning reads to their reference, which penalizes gaps more heavily than DNAFull
+    pub fn default_reference_alignment() -> AffineScoring {
+        AffineScoring {
+            match_score: 10.0,
+            mismatch_score: -9.0,
+            special_character_score: 9.0,
+            gap_open: -20.0,
+            gap_extend: -1.0,
+            final_gap_multiplier: 1.0,
+            quality_weighting: None,
+        }
+    }
+
+    /// take a stage's default scoring and replace any values set in the layout's global `scoring` section
+    pub fn from_layout(defaults: &AffineScoring, layout: &SequenceLayout) -> AffineScoring {
+        match &layout.scoring {
+            None => defaults.clone(),
+            Some(config) => defaults.with_configuration(config),
+        }
+    }
+
+    /// apply the per-reference `scoring` section, if the reference has one, on top of this scoring
+    pub fn for_reference(&self, layout: &SequenceLayout, reference_name: &str) -> AffineScoring {
+        match layout.references.get(reference_name).and_then(|rf| rf.scoring.as_ref()) {
+            None => self.clone(),
+            Some(config) => self.with_configuration(config),
+        }
+    }
+
+    pub fn with_configuration(&self, config: &ScoringConfiguration) -> AffineScoring {
+        AffineScoring {
+            match_score: config.match_score.unwrap_or(self.match_score),
+            mismatch_score: config.mismatch_score.unwrap_or(self.mismatch_score),
+            special_character_score: config.special_character_score.unwrap_or(self.special_character_score),
+            gap_open: config.gap_open.unwrap_or(self.gap_open),
+            gap_extend: config.gap_extend.unwrap_or(self.gap_extend),
+            final_gap_multiplier: config.final_gap_multiplier.unwrap_or(self.final_gap_multiplier),
+            quality_weighting: match &config.quality_weighting {
+                None => self.quality_weighting.clone(),
+                Some(weighting) => Some(QualityWeighting::from_configuration(weighting)),
+            },
         }
     }
 
@@ -92,11 +142,24 @@ impl AffineScoring {
             gap_open: 0.0, // gap open costs gap_open + gap_extend, we automatically get -1.0 for the start of a gap
             gap_extend: -1.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         }
     }
 
+    /// Bases are compared as IUPAC codes: a read base that's one of the bases a degenerate reference base stands for
+    /// (A against V, or C against S) is a match, scored with the `special_character_score` like any match to an N, so
+    /// constrained UMI and barcode positions (VN, WSN) align as well as fully degenerate ones
     pub fn match_mismatch(&self, bit_a: &FastaBase, bit_b: &FastaBase) -> f64 {
-        if bit_a == bit_b && (bit_a.identity(&FASTA_N) || bit_b.identity(&FASTA_N)) { self.special_character_score } else if bit_a == bit_b { self.match_score } else { self.mismatch_score }
+        if bit_a == bit_b && (bit_a.is_degenerate() || bit_b.is_degenerate()) { self.special_character_score } else if bit_a == bit_b { self.match_score } else { self.mismatch_score }
+    }
+
+    /// the match or mismatch score, scaled by the quality of the read base if we're weighting by quality
+    #[inline(always)]
+    pub fn quality_match_mismatch(&self, bit_a: &FastaBase, bit_b: &FastaBase, quality: Option<&u8>) -> f64 {
+        match (&self.quality_weighting, quality) {
+            (Some(weighting), Some(quality)) => self.match_mismatch(bit_a, bit_b) * weighting.weight(*quality),
+            _ => self.match_mismatch(bit_a, bit_b),
+        }
     }
 
     pub fn gap_open(&self) -> f64 {
@@ -110,7 +173,67 @@ impl AffineScoring {
     pub fn final_gap_multiplier(&self) -> f64 { self.final_gap_multiplier }
 }
 
+/// the offset of Phred scores in FASTQ quality strings
+pub const PHRED_OFFSET: u8 = 33;
+
+/// quality weights are rounded to sixteenths, so weighted scores stay exact in floating point and the integer aligners
+/// make the same decisions as the f64 aligner
+pub const QUALITY_WEIGHT_STEPS: u8 = 16;
+
+/// the highest Phred score a FASTQ quality character can carry ('~')
+pub const MAX_PHRED_SCORE: u8 = 93;
 
+/// A lookup from each Phred score to how much of the match or mismatch score a base with that quality gets, so a
+/// mismatch at a low-quality base (a likely sequencing error) costs less, and a match there counts for less
+#[derive(Clone, Debug, PartialEq)]
+pub struct QualityWeighting {
+    steps: Vec<u8>,
+}
+
+impl QualityWeighting {
+    pub fn new(curve: QualityCurve, min_quality: u8, max_quality: u8, min_weight: f64) -> QualityWeighting {
+        assert!(min_quality < max_quality, "The quality weighting min_quality ({}) must be below the max_quality ({})", min_quality, max_quality);
+        assert!((0.0..=1.0).contains(&min_weight), "The quality weighting min_weight must be between 0 and 1, not {}", min_weight);
+
+        let steps = (0..(MAX_PHRED_SCORE + 1)).map(|quality| {
+            let position = if quality <= min_quality {
+                0.0
+            } else if quality >= max_quality {
+                1.0
+            } else {
+                match curve {
+                    QualityCurve::Linear => (quality - min_quality) as f64 / (max_quality - min_quality) as f64,
+                    QualityCurve::ErrorProbability => 1.0 - 10.0_f64.powf(-(quality as f64) / 10.0),
+                }
+            };
+            let weight = min_weight + (1.0 - min_weight) * position;
+            (weight * QUALITY_WEIGHT_STEPS as f64).round() as u8
+        }).collect();
+        QualityWeighting { steps }
+    }
+
+    /// the weighting from a layout's `quality_weighting` section, with defaults for anything it doesn't set
+    pub fn from_configuration(config: &QualityWeightingConfiguration) -> QualityWeighting {
+        QualityWeighting::new(config.curve.unwrap_or(QualityCurve::Linear),
+                              config.min_quality.unwrap_or(2),
+                              config.max_quality.unwrap_or(30),
+                              config.min_weight.unwrap_or(0.25))
+    }
+
+    /// the weight of a base from its FASTQ quality character, in sixteenths
+    #[inline(always)]
+    pub fn steps(&self, quality: u8) -> u8 {
+        self.steps[min(quality.saturating_sub(PHRED_OFFSET), MAX_PHRED_SCORE) as usize]
+    }
+
+    /// the weight of a base from its FASTQ quality character
+    #[inline(always)]
+    pub fn weight(&self, quality: u8) -> f64 {
+        self.steps(quality) as f64 / QUALITY_WEIGHT_STEPS as f64
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct InversionScoring {
     pub match_score: f64,
     pub mismatch_score: f64,
@@ -123,7 +246,6 @@ pub struct InversionScoring {
 
 impl InversionScoring {
 
-    #[allow(dead_code)]
     pub fn default() -> InversionScoring {
         InversionScoring {
             match_score: 9.0,
@@ -135,6 +257,33 @@ impl InversionScoring {
         }
     }
 
+    /// take the default inversion scoring and replace any values set in the layout's `scoring.inversion` section
+    pub fn from_layout(defaults: &InversionScoring, layout: &SequenceLayout) -> InversionScoring {
+        match layout.scoring.as_ref().and_then(|sc| sc.inversion.as_ref()) {
+            None => defaults.clone(),
+            Some(config) => defaults.with_configuration(config),
+        }
+    }
+
+    /// apply the per-reference `scoring.inversion` section, if the reference has one, on top of this scoring
+    pub fn for_reference(&self, layout: &SequenceLayout, reference_name: &str) -> InversionScoring {
+        match layout.references.get(reference_name).and_then(|rf| rf.scoring.as_ref()).and_then(|sc| sc.inversion.as_ref()) {
+            None => self.clone(),
+            Some(config) => self.with_configuration(config),
+        }
+    }
+
+    pub fn with_configuration(&self, config: &InversionScoringConfiguration) -> InversionScoring {
+        InversionScoring {
+            match_score: config.match_score.unwrap_or(self.match_score),
+            mismatch_score: config.mismatch_score.unwrap_or(self.mismatch_score),
+            gap_open: config.gap_open.unwrap_or(self.gap_open),
+            gap_extend: config.gap_extend.unwrap_or(self.gap_extend),
+            inversion_penalty: config.inversion_penalty.unwrap_or(self.inversion_penalty),
+            min_inversion_length: config.min_inversion_length.unwrap_or(self.min_inversion_length),
+        }
+    }
+
     pub fn match_mismatch(&self, a: &FastaBase, b: &FastaBase) -> f64 {
         if a == b { self.match_score } else { self.mismatch_score }
     }
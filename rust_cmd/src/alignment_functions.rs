@@ -1,15 +1,20 @@
-use std::collections::{BTreeMap, VecDeque};
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::BufWriter;
 use std::sync::{Arc, Mutex};
 use std::path::{Path, PathBuf};
 
 use crate::rayon::iter::ParallelBridge;
 use crate::rayon::iter::ParallelIterator;
-use crate::alignment::alignment_matrix::{Alignment, AlignmentResult, AlignmentTag, AlignmentType, create_scoring_record_3d, perform_3d_global_traceback, perform_affine_alignment, perform_affine_alignment_bandwidth};
+use crate::alignment::alignment_matrix::{Alignment, AlignmentResult, AlignmentTag, AlignmentType, create_scoring_record_3d, GapPlacement, inversion_alignment, perform_3d_global_traceback, perform_affine_alignment, perform_affine_alignment_bandwidth};
 use crate::alignment::scoring_functions::{AffineScoring, InversionScoring};
+use crate::alignment::integer_alignment::{IntegerAffineScoring, integer_affine_alignment, checkpointed_affine_alignment};
+use crate::alignment::aligner::{Aligner, aligner_for_layout};
 
 use crate::linked_alignment::{align_string_with_anchors, find_greedy_non_overlapping_segments, orient_by_longest_segment};
 use crate::read_strategies::read_set::{ReadIterator};
-use crate::reference::fasta_reference::{ReferenceManager};
+use crate::reference::fasta_reference::{Reference, ReferenceManager};
 use std::time::{Instant};
 use bio::alignment::AlignmentOperation;
 use ndarray::Ix3;
@@ -21,113 +26,222 @@ use crate::read_strategies::sequence_layout::{SequenceLayout};
 
 
 use crate::read_strategies::read_disk_sorter::{SortingReadSetContainer};
-use itertools::Itertools;
 use crate::alignment_manager::BamFileAlignmentWriter;
 use crate::consensus::consensus_builders::SamReadyOutput;
 use crate::alignment_manager::OutputAlignmentWriter;
-use sigalign::{
-    Aligner,
-    algorithms::Local,
-    ReferenceBuilder,
-};
+use serde::Serialize;
+use noodles_sam::alignment::record::Flags;
+use noodles_sam::alignment::record_buf::data::field::Value;
+
+/// the width of the bins we use when recording alignment score distributions
+pub const ALIGNMENT_SCORE_BIN_SIZE: f64 = 10.0;
+
+/// Tracks what happened to each read during an alignment run, from reads we couldn't parse through to the
+/// reference each aligned read was assigned. This is shared across the rayon threads and written as JSON next to
+/// the output BAM when we're done
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct AlignmentRunStats {
+    pub total_reads: usize,
+    pub aligned: usize,
+    pub too_long: usize,
+    pub no_candidate_reference: usize,
+    pub unparseable: usize,
+    pub below_min_length: usize,
+    pub above_max_reference_multiplier: usize,
+    pub reference_hits: BTreeMap<String, usize>,
+    pub score_distributions: BTreeMap<String, BTreeMap<i64, usize>>,
+}
+
+impl AlignmentRunStats {
+    pub fn aligned_read(&mut self, reference_name: &str, score: f64) {
+        self.total_reads += 1;
+        self.aligned += 1;
+        *self.reference_hits.entry(reference_name.to_string()).or_insert(0) += 1;
+
+        let score_bin = ((score / ALIGNMENT_SCORE_BIN_SIZE).floor() * ALIGNMENT_SCORE_BIN_SIZE) as i64;
+        *self.score_distributions.entry(reference_name.to_string()).or_default().entry(score_bin).or_insert(0) += 1;
+    }
+
+    pub fn too_long_read(&mut self) {
+        self.total_reads += 1;
+        self.too_long += 1;
+    }
+
+    pub fn no_candidate_reference_read(&mut self) {
+        self.total_reads += 1;
+        self.no_candidate_reference += 1;
+    }
+
+    pub fn below_min_length_read(&mut self) {
+        self.total_reads += 1;
+        self.below_min_length += 1;
+    }
+
+    pub fn above_max_reference_multiplier_read(&mut self) {
+        self.total_reads += 1;
+        self.above_max_reference_multiplier += 1;
+    }
+
+    /// unparseable records never make it to the aligner, so they're counted by the read iterator and added at the end
+    pub fn unparseable_reads(&mut self, count: usize) {
+        self.total_reads += count;
+        self.unparseable += count;
+    }
+
+    /// where we put the summary for a given output BAM: alongside it, as <bam name>.alignment_stats.json
+    pub fn stats_path(output: &Path) -> PathBuf {
+        output.with_extension("alignment_stats.json")
+    }
+
+    pub fn write_json(&self, output: &Path) {
+        let file = File::create(output).unwrap_or_else(|_| panic!("Unable to create alignment stats file {:?}", output));
+        serde_json::to_writer_pretty(BufWriter::new(file), self).expect("Unable to write alignment stats");
+    }
+
+    pub fn results(&self) {
+        info!(
+            "Alignment finished, Total reads: {}, Aligned: {}, Too long: {}, No candidate reference: {}, Unparseable: {}, Below minimum length: {}, Above max reference multiplier: {}",
+            self.total_reads,
+            self.aligned,
+            self.too_long,
+            self.no_candidate_reference,
+            self.unparseable,
+            self.below_min_length,
+            self.above_max_reference_multiplier,
+        );
+    }
+}
 
 pub fn align_reads(read_structure: &SequenceLayout,
                    rm: &ReferenceManager,
                    output: &Path,
                    max_reference_multiplier: &usize,
                    min_read_length: &usize,
+                   max_indel: &Option<usize>,
+                   max_secondary: &usize,
                    read1: &String,
                    read2: &String,
                    index1: &String,
                    index2: &String,
+                   interleaved: &bool,
+                   max_read_length: &Option<usize>,
+                   semi_global: &bool,
+                   gap_placement: &GapPlacement,
+                   extended_cigar: &bool,
+                   sorting_file: Option<PathBuf>,
                    threads: &usize,
-                   inversions: &bool) {
-    let read_iterator = ReadIterator::new(PathBuf::from(&read1),
-                                          Some(PathBuf::from(&read2)),
-                                          Some(PathBuf::from(&index1)),
-                                          Some(PathBuf::from(&index2)));
+                   inversions: &bool,
+                   fast_reference_lookup: &bool) {
+    // the inversion-aware and anchored aligners are always global
+    assert!(!*semi_global || !(*inversions || *fast_reference_lookup || read_structure.aligner.as_deref() == Some("anchors")),
+            "Semi-global alignment isn't supported with --find-inversions, --fast-reference-lookup, or the anchors aligner");
+    let read_iterator = ReadIterator::from_layout(read_structure, read1, read2, index1, index2, *interleaved);
 
-    let read_iterator = MergedReadSequence::new(read_iterator, read_structure);
+    let mut read_iterator = MergedReadSequence::new(read_iterator, read_structure);
 
-    let writer = BamFileAlignmentWriter::new(&PathBuf::from(output), &rm);
+    let writer = BamFileAlignmentWriter::new(&PathBuf::from(output), &rm, *extended_cigar, sorting_file);
+    let stats_path = AlignmentRunStats::stats_path(output);
 
     let output = Arc::new(Mutex::new(writer));
+    let run_stats = Arc::new(Mutex::new(AlignmentRunStats::default()));
 
     // setup our thread pool
     rayon::ThreadPoolBuilder::new().num_threads(*threads).build_global().unwrap();
 
-    let my_score = InversionScoring {
-        match_score: 9.0,
-        mismatch_score: -21.0,
-        gap_open: -25.0,
-        gap_extend: -1.0,
-        inversion_penalty: -40.0,
-        min_inversion_length: 20,
-    };
-
-    let my_aff_score = AffineScoring {
-        match_score: 10.0,
-        mismatch_score: -9.0,
-        special_character_score: 9.0,
-        gap_open: -20.0,
-        gap_extend: -1.0,
-        final_gap_multiplier: 1.0,
+    // scoring comes from the layout's scoring section, falling back to our reference-alignment defaults
+    let my_score = InversionScoring::from_layout(&InversionScoring::default(), read_structure);
+    let my_aff_score = AffineScoring::from_layout(&AffineScoring::default_reference_alignment(), read_structure);
 
-    };
     let start = Instant::now();
     let read_count = Arc::new(Mutex::new(0)); // we rely on this Arc for output file access control
 
-    type SharedStore = Arc<Mutex<Option<Alignment<Ix3>>>>;
+    // each thread's preallocated matrix, and the (buffer-free) matrix for reads too long for it
+    type SharedStore = Arc<Mutex<Option<(Alignment<Ix3>, Alignment<Ix3>)>>>;
 
     lazy_static! {static ref STORE_CLONES: Mutex<Vec<SharedStore>> = Mutex::new(Vec::new());}
     thread_local!(static STORE: SharedStore = Arc::new(Mutex::new(None)));
 
     let max_read_size = (rm.longest_ref + 1) * 2;
-    let alignment_mat: Alignment<Ix3> = create_scoring_record_3d(rm.longest_ref + 1, max_read_size, AlignmentType::Affine, false);
+    let aligner = aligner_for_layout(read_structure, rm);
+    let alignment_type = aligner.alignment_type();
+
+    let mut alignment_mat: Alignment<Ix3> = create_scoring_record_3d(rm.longest_ref + 1, max_read_size, alignment_type, false);
+    alignment_mat.is_semi_global = *semi_global;
+    alignment_mat.gap_placement = *gap_placement;
 
-    read_iterator.par_bridge().for_each(|mut xx: UnifiedRead| {
+    // reads that don't fit the preallocated matrix go to the checkpointed aligner, which doesn't need one (the layout
+    // has already checked the scoring scales to integers for it)
+    let mut long_read_mat: Alignment<Ix3> = create_scoring_record_3d(0, 0, AlignmentType::CheckpointedAffine, false);
+    long_read_mat.is_semi_global = *semi_global;
+    long_read_mat.gap_placement = *gap_placement;
+
+    read_iterator.by_ref().par_bridge().for_each(|mut xx: UnifiedRead| {
         STORE.with(|arc_mtx| {
             let mut local_alignment = arc_mtx.lock().unwrap();
             if local_alignment.is_none() {
-                *local_alignment = Some(alignment_mat.clone());
+                *local_alignment = Some((alignment_mat.clone(), long_read_mat.clone()));
                 STORE_CLONES.lock().unwrap().push(arc_mtx.clone());
             }
 
+            xx.merge_with_reference(rm);
             let name = &String::from_utf8(xx.name().clone()).unwrap();
 
             let seq_len = &xx.seq().len();
-            let qual = Some(xx.quals.as_ref().unwrap().clone());
-            if seq_len < &max_read_size {
+            let qual = Some(xx.quals().clone());
+            let exceeds_max_length = max_read_length.map_or(false, |max_length| *seq_len > max_length);
+            let long_read = *seq_len >= max_read_size;
+            if !exceeds_max_length {
+                let (read_mat, long_read_alignment) = local_alignment.as_mut().unwrap();
+                let matrix = if long_read { long_read_alignment } else { read_mat };
                 let aligned = align_to_reference_choices(name,
                                                          xx.seq(),
                                                          qual,
                                                          rm,
-                                                         &false,
+                                                         fast_reference_lookup,
                                                          read_structure,
-                                                         local_alignment.as_mut().unwrap(),
+                                                         aligner.as_ref(),
+                                                         matrix,
                                                          &my_aff_score,
                                                          &my_score,
                                                          inversions,
                                                          *max_reference_multiplier as f64,
                                                          *min_read_length,
-                                                         seq_len);
+                                                         &max_indel.unwrap_or(*seq_len),
+                                                         max_secondary);
 
                 match aligned {
-                    None => {
-                        // TODO: we should track this and provide a final summary
+                    Err(AlignmentFailure::BelowMinReadLength) => {
+                        debug!("Read {} is shorter than the minimum read length {}", name, min_read_length);
+                        run_stats.lock().unwrap().below_min_length_read();
+                        output.lock().unwrap().write_unmapped_read(name, &xx.seq().clone(), &xx.quals).expect("Unable to write an unmapped read to the arc writer (LOC2)");
+                    }
+                    Err(AlignmentFailure::ExceedsReferenceMultiplier) => {
+                        debug!("Read {} has more than {}x the bases of the reference region it aligned to", name, max_reference_multiplier);
+                        run_stats.lock().unwrap().above_max_reference_multiplier_read();
+                        output.lock().unwrap().write_unmapped_read(name, &xx.seq().clone(), &xx.quals).expect("Unable to write an unmapped read to the arc writer (LOC3)");
+                    }
+                    Err(AlignmentFailure::NoCandidateReference) => {
                         debug!("Unable to create alignment for read {}",name);
+                        run_stats.lock().unwrap().no_candidate_reference_read();
+                        output.lock().unwrap().write_unmapped_read(name, &xx.seq().clone(), &xx.quals).expect("Unable to write an unmapped read to the arc writer (LOC1)");
                     }
-                    Some(alignment_obj) => {
+                    Ok(alignment_obj) => {
+                        let mapping_quality = alignment_obj.mapping_quality;
+                        let suboptimal_score = alignment_obj.suboptimal_score;
+                        let secondary_alignments = alignment_obj.secondary_alignments;
+                        let reverse_strand = alignment_obj.reverse_strand;
                         let results = alignment_obj.alignment;
                         let _orig_ref_seq = alignment_obj.ref_sequence;
                         let ref_name = alignment_obj.ref_name;
                         match results {
                             None => {
-                                // TODO: we should track this and provide a final summary
-
                                 debug!("Unable to create alignment for read {}",name);
+                                run_stats.lock().unwrap().no_candidate_reference_read();
+                                output.lock().unwrap().write_unmapped_read(name, &xx.seq().clone(), &xx.quals).expect("Unable to write an unmapped read to the arc writer (LOC1)");
                             }
                             Some(aln) => {
+                                run_stats.lock().unwrap().aligned_read(&String::from_utf8_lossy(&ref_name), aln.score);
+
                                 let output = Arc::clone(&output);
                                 let mut read_count = read_count.lock().unwrap();
                                 *read_count += 1;
@@ -137,24 +251,51 @@ pub fn align_reads(read_structure: &SequenceLayout,
                                 }
                                 assert_eq!(aln.reference_aligned.len(), aln.read_aligned.len());
 
-                                let read = SortingReadSetContainer::empty_tags(aln);
-                                let new_read = SamReadyOutput { read, added_tags: Default::default() };
+                                // reads we reverse-complemented to align are flagged as reverse strand, and their inverted
+                                // segments are on the opposite strand
+                                let strand_flag = if reverse_strand { Flags::REVERSE_COMPLEMENTED } else { Flags::empty() };
+
+                                // inverted segments are written as supplementary records, cross-referenced by SA tags
+                                let (primary, inverted_segments) = aln.split_inversions();
+                                let mut primary_tags: HashMap<[u8; 2], Value> = HashMap::new();
+                                if let Some(suboptimal_score) = suboptimal_score {
+                                    primary_tags.insert([b'X', b'S'], Value::from(suboptimal_score as f32));
+                                }
 
-                                //let samrecord = aln.to_sam_record(&i32::try_from(*reference_record).ok().unwrap(), &empty_tags, None);
+                                let read = SortingReadSetContainer::empty_tags(primary);
+                                let new_read = SamReadyOutput { read, added_tags: primary_tags };
 
                                 let arc_writer = output.clone();
                                 let mut arc_writer = arc_writer.lock().expect("Unable to access multi-threaded writer");
-                                arc_writer.write_read(&new_read.read, &new_read.added_tags).expect("Unable to write a read to the arc writer (LOC1)");
+                                arc_writer.write_split_read(&new_read.read, &new_read.added_tags, &inverted_segments, reverse_strand, Some(mapping_quality)).expect("Unable to write a read to the arc writer (LOC1)");
+
+                                // near-tie alignments to other references; we only report their forward portions
+                                for secondary in secondary_alignments {
+                                    let (secondary, _inverted_segments) = secondary.split_inversions();
+                                    arc_writer.write_read_with_flags(&SortingReadSetContainer::empty_tags(secondary),
+                                                                     &Default::default(),
+                                                                     Flags::SECONDARY | strand_flag,
+                                                                     Some(0)).expect("Unable to write a secondary read to the arc writer (LOC1)");
+                                }
                                 //output.write(&samrecord).expect("Unable to write read to output bam file");
                             }
                         }
                     }
                 }
             } else {
-                warn!("Dropped read {} is it's length {} exceeds 2x the reference length {}", String::from_utf8(xx.name().clone()).unwrap(), xx.seq().len(), max_read_size);
+                warn!("Not aligning read {} as its length {} exceeds the maximum read length {}", name, seq_len, max_read_length.unwrap());
+                run_stats.lock().unwrap().too_long_read();
+                output.lock().unwrap().write_unmapped_read(name, &xx.seq().clone(), &xx.quals).expect("Unable to write an unmapped read to the arc writer (LOC4)");
             }
         });
     });
+
+    output.lock().unwrap().finish().expect("Unable to finish the output bam file");
+
+    let mut run_stats = run_stats.lock().unwrap();
+    run_stats.unparseable_reads(read_iterator.broken_reads());
+    run_stats.results();
+    run_stats.write_json(&stats_path);
 }
 
 #[allow(dead_code)]
@@ -304,6 +445,20 @@ pub fn align_two_strings_passed_matrix(
     scoring_function: &AffineScoring,
     alignment_mat: &mut Alignment<Ix3>,
     max_indel: &usize) -> AlignmentResult {
+    if alignment_mat.alignment_type == AlignmentType::IntegerAffine || alignment_mat.alignment_type == AlignmentType::CheckpointedAffine {
+        return match IntegerAffineScoring::from_affine(scoring_function) {
+            // the checkpointed kernel only does global alignment
+            Some(integer_scoring) if alignment_mat.alignment_type == AlignmentType::CheckpointedAffine && !alignment_mat.is_local =>
+                checkpointed_affine_alignment(read1_seq, read2_seq, &integer_scoring, max_indel, alignment_mat.is_semi_global, read1_name, read2_name, qual_sequence),
+            Some(integer_scoring) => integer_affine_alignment(read1_seq, read2_seq, &integer_scoring, max_indel, alignment_mat.is_local, alignment_mat.is_semi_global, read1_name, read2_name, qual_sequence),
+            None => {
+                // this scoring can't be scaled to integers, so use the f64 aligner with a matrix just for this pair
+                let mut pair_mat = create_scoring_record_3d(read1_seq.len() + 1, read2_seq.len() + 1, AlignmentType::Affine, alignment_mat.is_local);
+                pair_mat.is_semi_global = alignment_mat.is_semi_global;
+                align_two_strings_passed_matrix(read1_name, read2_name, read1_seq, read2_seq, qual_sequence, scoring_function, &mut pair_mat, max_indel)
+            }
+        };
+    }
     /*match (reference_manager, ref_name) {
         (Some(x), Some(y)) => {
             //let ref_id = x.reference_name_to_ref.get(y).unwrap();
@@ -344,6 +499,7 @@ pub fn align_two_strings_passed_matrix(
         alignment_mat,
         read1_seq,
         read2_seq,
+        qual_sequence.as_deref(),
         scoring_function,
         &max_indel);
 
@@ -366,6 +522,107 @@ pub struct AlignmentWithRef {
     alignment: Option<AlignmentResult>,
     ref_name: Vec<u8>,
     ref_sequence: Vec<u8>,
+    /// the mapping quality of the alignment, from the score gap to the next best reference
+    mapping_quality: u8,
+    /// the score of the next best reference's alignment, if we aligned to more than one (the XS tag)
+    suboptimal_score: Option<f64>,
+    /// near-tie alignments to other references, written as secondary records
+    secondary_alignments: Vec<AlignmentResult>,
+    /// true if we reverse-complemented the read to align it
+    reverse_strand: bool,
+}
+
+/// Reverse-complement a read (and reverse its qualities) if it's not from the forward strand
+fn orient_read(read: &Vec<FastaBase>, qual_sequence: Option<Vec<u8>>, forward_strand: bool) -> (Vec<FastaBase>, Option<Vec<u8>>) {
+    if forward_strand {
+        (read.clone(), qual_sequence)
+    } else {
+        (reverse_complement(read), qual_sequence.map(|mut quals| {
+            quals.reverse();
+            quals
+        }))
+    }
+}
+
+/// the mapping quality we give reads that only look like one reference
+pub const MAX_MAPPING_QUALITY: u8 = 60;
+
+/// alignments to other references scoring within this fraction of the best alignment are near-ties, which we can
+/// report as secondary alignments
+pub const SECONDARY_ALIGNMENT_SCORE_FRACTION: f64 = 0.95;
+
+/// The mapping quality of a read's best alignment, scaled by the gap between its score and the next best reference's
+/// score: reads without a competing reference get `MAX_MAPPING_QUALITY`, and ties (or worse) get 0
+pub fn mapping_quality(best_score: f64, suboptimal_score: Option<f64>) -> u8 {
+    match suboptimal_score {
+        None => MAX_MAPPING_QUALITY,
+        Some(_) if best_score <= 0.0 => 0,
+        Some(suboptimal) => {
+            let gap = ((best_score - suboptimal) / best_score).max(0.0).min(1.0);
+            (gap * MAX_MAPPING_QUALITY as f64).round() as u8
+        }
+    }
+}
+
+/// Rank the alignments of a read to candidate references by score. The best alignment is the primary; its mapping
+/// quality comes from the gap to the runner-up, and up to `max_secondary` near-ties are kept as secondary alignments
+fn rank_candidate_alignments(mut candidates: Vec<(AlignmentResult, Vec<u8>, Vec<u8>)>, max_secondary: &usize) -> Option<AlignmentWithRef> {
+    // an alignment without a real score can't be ranked against the others
+    candidates.retain(|candidate| !candidate.0.score.is_nan());
+    candidates.sort_by(|a, b| b.0.score.total_cmp(&a.0.score));
+    let mut candidates = candidates.into_iter();
+    let (best, ref_sequence, ref_name) = candidates.next()?;
+
+    let others = candidates.collect::<Vec<(AlignmentResult, Vec<u8>, Vec<u8>)>>();
+    let suboptimal_score = others.first().map(|other| other.0.score);
+    let near_tie_score = best.score - best.score.abs() * (1.0 - SECONDARY_ALIGNMENT_SCORE_FRACTION);
+    let secondary_alignments = others.into_iter()
+        .filter(|other| other.0.score >= near_tie_score)
+        .take(*max_secondary)
+        .map(|other| other.0)
+        .collect::<Vec<AlignmentResult>>();
+
+    Some(AlignmentWithRef {
+        mapping_quality: mapping_quality(best.score, suboptimal_score),
+        alignment: Some(best),
+        ref_name,
+        ref_sequence,
+        suboptimal_score,
+        secondary_alignments,
+        reverse_strand: false,
+    })
+}
+
+/// The reasons `align_to_reference_choices` can turn down a read
+#[derive(Clone, Debug, PartialEq)]
+pub enum AlignmentFailure {
+    /// the read was shorter than the minimum read length, so we never tried to align it
+    BelowMinReadLength,
+    /// there were no references, or none of them looked like the read
+    NoCandidateReference,
+    /// the read has too many bases relative to the reference region it covers (see `reference_coverage_ratio`)
+    ExceedsReferenceMultiplier,
+}
+
+/// The number of read bases per reference base over the part of the reference the read covers, from the first to the
+/// last aligned read base. Reads that are mostly insertion relative to their reference (adapter dimers, concatemers, etc)
+/// have large ratios
+pub fn reference_coverage_ratio(alignment: &AlignmentResult) -> f64 {
+    let first = alignment.read_aligned.iter().position(|b| !b.identity(&FASTA_UNSET));
+    let last = alignment.read_aligned.iter().rposition(|b| !b.identity(&FASTA_UNSET));
+
+    match (first, last) {
+        (Some(first), Some(last)) => {
+            let read_bases = alignment.read_aligned[first..=last].iter().filter(|b| !b.identity(&FASTA_UNSET)).count();
+            let reference_bases = alignment.reference_aligned[first..=last].iter().filter(|b| !b.identity(&FASTA_UNSET)).count();
+            if reference_bases == 0 {
+                f64::INFINITY
+            } else {
+                read_bases as f64 / reference_bases as f64
+            }
+        }
+        _ => 0.0,
+    }
 }
 
 
@@ -387,8 +644,9 @@ pub struct AlignmentWithRef {
 ///
 /// # Returns
 ///
-/// An `AlignmentResult` containing the details of the alignment, which may include the alignment score,
-/// aligned sequences, and other relevant information.
+/// An `AlignmentWithRef` containing the details of the alignment, or the `AlignmentFailure` explaining why the read
+/// was turned down: reads shorter than `min_read_length` are never aligned, and aligned reads with more than
+/// `max_reference_multiplier` read bases per covered reference base are dropped.
 ///
 /// # Behavior
 ///
@@ -436,71 +694,95 @@ pub fn align_to_reference_choices(read_name: &String,
                                   rm: &ReferenceManager,
                                   fast_lookup: &bool,
                                   read_structure: &SequenceLayout,
+                                  aligner: &dyn Aligner,
                                   alignment_mat: &mut Alignment<Ix3>,
                                   my_aff_score: &AffineScoring,
-                                  _my_score: &InversionScoring,
-                                  _use_inversions: &bool,
-                                  _max_reference_multiplier: f64,
-                                  _min_read_length: usize,
+                                  my_score: &InversionScoring,
+                                  use_inversions: &bool,
+                                  max_reference_multiplier: f64,
+                                  min_read_length: usize,
                                   max_indel: &usize,
-) -> Option<AlignmentWithRef> {
-    match rm.references.len() {
+                                  max_secondary: &usize,
+) -> Result<AlignmentWithRef, AlignmentFailure> {
+    if read.len() < min_read_length {
+        return Err(AlignmentFailure::BelowMinReadLength);
+    }
+
+    let aligned = match rm.references.len() {
         0 => {
-            // TODO: we should track this and provide a final summary
             warn!("Unable to align read {} as it has no candidate references",FastaBase::string(read));
             None
         }
         1 => {
             let ref_base = &rm.references.get(&0).unwrap();
-            let ref_name = String::from_utf8(ref_base.name.clone()).unwrap();
-            let forward_oriented_seq = if !read_structure.known_strand {
-                let orientation = orient_by_longest_segment(&read, &ref_base.sequence_u8, &ref_base.suffix_table).0;
-                if orientation {
-                    read.clone()
-                } else {
-                    reverse_complement(&read)
-                }
-            } else {
-                read.clone()
-            };
+            let forward_strand = read_structure.known_strand || orient_by_longest_segment(&read, &ref_base.sequence_u8, &ref_base.suffix_table).0;
+            let (forward_oriented_seq, forward_oriented_qual) = orient_read(read, qual_sequence, forward_strand);
 
-            let aln = align_two_strings_passed_matrix(
-                &ref_name,
+            let aln = align_to_reference(
+                ref_base,
                 read_name,
-                &ref_base.sequence,
                 &forward_oriented_seq,
-                qual_sequence,
-                my_aff_score,
+                forward_oriented_qual,
+                read_structure,
+                aligner,
                 alignment_mat,
+                my_aff_score,
+                my_score,
+                use_inversions,
                 max_indel);
 
             Some(AlignmentWithRef {
-                alignment: Some(aln),
+                alignment: aln,
                 ref_name: ref_base.name.clone(),
                 ref_sequence: ref_base.sequence_u8.clone(),
+                mapping_quality: MAX_MAPPING_QUALITY,
+                suboptimal_score: None,
+                secondary_alignments: Vec::new(),
+                reverse_strand: !forward_strand,
             })
         }
         x if x > 1 => {
-            if *fast_lookup {
-                quick_alignment_search(read_name, read, qual_sequence, &rm, alignment_mat, my_aff_score)
+            // the unique k-mers of the best supported reference tell us which strand the read is from
+            let kmer_hits = rm.kmer_hits(&FastaBase::vec_u8(read));
+            let forward_strand = read_structure.known_strand || kmer_hits.first().map(|(_reference, forward, reverse)| forward >= reverse).unwrap_or(true);
+            let (oriented_read, oriented_qual) = orient_read(read, qual_sequence, forward_strand);
+
+            let aligned = if *fast_lookup {
+                quick_alignment_search(read_name, &oriented_read, oriented_qual, &kmer_hits, read_structure, aligner, alignment_mat, my_aff_score, my_score, use_inversions, max_indel, max_secondary)
             } else {
-                exhaustive_alignment_search(read_name, read, qual_sequence, &rm, alignment_mat, my_aff_score)
-            }
+                exhaustive_alignment_search(read_name, &oriented_read, oriented_qual, &rm, read_structure, aligner, alignment_mat, my_aff_score, my_score, use_inversions, max_indel, max_secondary)
+            };
+            aligned.map(|mut aligned| {
+                aligned.reverse_strand = !forward_strand;
+                aligned
+            })
         }
         x => { panic!("we dont know what to do with a reference count of {}", x) }
+    };
+
+    match aligned {
+        None => Err(AlignmentFailure::NoCandidateReference),
+        Some(aligned) => {
+            match &aligned.alignment {
+                Some(aln) if reference_coverage_ratio(aln) > max_reference_multiplier => {
+                    Err(AlignmentFailure::ExceedsReferenceMultiplier)
+                }
+                _ => Ok(aligned),
+            }
+        }
     }
 }
 
 /// Performs a quick alignment search for a given read against a reference manager.
 ///
-/// This function takes a read sequence and aligns it to the most likely reference sequence
+/// This function takes a read sequence and aligns it to the most likely reference sequences
 /// in a reference manager using k-mers. It supports both affine scoring and inversion scoring,
 /// and can optionally include inversions in the alignment process.
 ///
 /// # Arguments
 ///
 /// * `read` - A reference to the sequence to be aligned, represented as `Vec<FastaBase>`.
-/// * `rm` - A reference to the `ReferenceManager` containing reference sequences and k-mer information.
+/// * `kmer_hits` - The references sharing unique k-mers with the read, best supported first (see `ReferenceManager::kmer_hits`).
 /// * `read_structure` - A reference to the `SequenceLayoutDesign` that describes the layout of the read sequence.
 /// * `alignment_mat` - A mutable reference to an `Alignment` object (3D matrix) used for storing alignment scores.
 /// * `my_aff_score` - A reference to an `AffineScoring` object for scoring alignments.
@@ -518,11 +800,10 @@ pub fn align_to_reference_choices(read_name: &String,
 ///
 /// # Behavior
 ///
-/// The function converts the read sequence into a `Vec<u8>` and generates k-mers. It then finds
-/// the reference sequence in the reference manager with the highest count of matching k-mers.
-/// If such a reference is found, it performs an alignment using `align_two_strings_passed_matrix`
-/// and returns the alignment result along with the sequence and name of the matched reference.
-/// If no matching reference is found, it returns `None`.
+/// The read should already be oriented to the strand its k-mer hits support. The function aligns
+/// the read to the top candidates (at least two, so the mapping quality reflects the runner-up, and enough to
+/// fill `max_secondary`), and returns the best alignment along with the sequence and name of the
+/// matched reference. If no matching reference is found, it returns `None`.
 ///
 /// # Example
 ///
@@ -554,73 +835,73 @@ pub fn align_to_reference_choices(read_name: &String,
 fn quick_alignment_search(read_name: &String,
                           read: &Vec<FastaBase>,
                           qual_sequence: Option<Vec<u8>>,
-                          rm: &ReferenceManager,
+                          kmer_hits: &Vec<(&Reference, usize, usize)>,
+                          read_structure: &SequenceLayout,
+                          aligner: &dyn Aligner,
                           alignment_mat: &mut Alignment<Ix3>,
-                          my_aff_score: &AffineScoring) -> Option<AlignmentWithRef> {
-    let read_u8 = FastaBase::vec_u8(read);
-    let read_kmers = ReferenceManager::sequence_to_kmers(&read_u8, &rm.kmer_size, &rm.kmer_skip);
-
-    let max_ref = read_kmers.iter().map(|(kmer, _c)| {
-        rm.unique_kmers.kmer_to_reference.get(kmer)
-    }).flatten().counts();
-    let max_ref = max_ref.iter().max_by(|x, y| x.1.cmp(y.1));
-
-    match max_ref {
-        None => {
-            None
-        }
-        Some(x) => {
-            let ref_name = String::from_utf8(x.0.name.clone()).unwrap();
-            Some(AlignmentWithRef {
-                alignment: Some(align_two_strings_passed_matrix(
-                    &ref_name,
-                    read_name,
-                    &x.0.sequence,
-                    read,
-                    qual_sequence,
-                    my_aff_score,
-                    alignment_mat,
-                    &read.len())),
-                ref_name: x.0.name.clone(),
-                ref_sequence: x.0.sequence_u8.clone(),
-            })
-        }
-    }
+                          my_aff_score: &AffineScoring,
+                          my_score: &InversionScoring,
+                          use_inversions: &bool,
+                          max_indel: &usize,
+                          max_secondary: &usize) -> Option<AlignmentWithRef> {
+    // references are ranked by their unique k-mer hits; align to enough of them to measure ambiguity
+    let candidates = kmer_hits.iter().take(max(2, max_secondary + 1)).filter_map(|(reference, _forward, _reverse)| {
+        let aln = align_to_reference(reference, read_name, read, qual_sequence.clone(), read_structure, aligner, alignment_mat, my_aff_score, my_score, use_inversions, max_indel);
+        aln.map(|aln| (aln, reference.sequence_u8.clone(), reference.name.clone()))
+    }).collect::<Vec<(AlignmentResult, Vec<u8>, Vec<u8>)>>();
+
+    rank_candidate_alignments(candidates, max_secondary)
 }
 
 fn exhaustive_alignment_search(read_name: &String,
                                read: &Vec<FastaBase>,
                                qual_sequence: Option<Vec<u8>>,
                                rm: &ReferenceManager,
+                               read_structure: &SequenceLayout,
+                               aligner: &dyn Aligner,
                                alignment_mat: &mut Alignment<Ix3>,
-                               my_aff_score: &AffineScoring) -> Option<AlignmentWithRef> {
+                               my_aff_score: &AffineScoring,
+                               my_score: &InversionScoring,
+                               use_inversions: &bool,
+                               max_indel: &usize,
+                               max_secondary: &usize) -> Option<AlignmentWithRef> {
     let references = &rm.references;
 
-    let ranked_alignments = references.iter().map(|reference| {
+    let candidates = references.iter().filter_map(|reference| {
         let qual = qual_sequence.clone();
-        let lt = align_two_strings_passed_matrix(&String::from_utf8(reference.1.name.clone()).unwrap(), read_name, &reference.1.sequence, read, qual, my_aff_score, alignment_mat, &read.len());
+        let lt = align_to_reference(reference.1, read_name, read, qual, read_structure, aligner, alignment_mat, my_aff_score, my_score, use_inversions, max_indel);
 
-        Some((lt, reference.1.sequence_u8.clone(), reference.1.name.clone()))
-    }).filter(|x| x.is_some()).map(|c| c.unwrap());
+        lt.map(|lt| (lt, reference.1.sequence_u8.clone(), reference.1.name.clone()))
+    }).collect::<Vec<(AlignmentResult, Vec<u8>, Vec<u8>)>>();
 
-    let ranked_alignments = ranked_alignments.into_iter().enumerate().max_by(|al, al2| {
-        let score1 = al.1.0.score; // / al.1.0.reference_aligned.len() as f64;
-        let score2 = al2.1.0.score; // / al2.1.0.reference_aligned.len() as f64;
-        score1.partial_cmp(&score2).unwrap()
-    });
+    rank_candidate_alignments(candidates, max_secondary)
+}
 
-    match ranked_alignments.iter().next() {
-        None => { None }
-        Some((_x, y)) => {
-            //Some((Some(y.0.clone()), , y.2.clone()))
-            //println!("---- {} {}",String::from_utf8(y.1.clone()).unwrap(),String::from_utf8(y.2.clone()).unwrap());
-            Some(AlignmentWithRef {
-                alignment: Some(y.0.clone()),
-                ref_name: y.2.clone(),
-                ref_sequence: y.1.clone(),
-            })
-        }
-    }
+/// Align a read to a single reference with the scoring for that reference. With `use_inversions` we run the
+/// inversion-aware DP, whose CIGAR can contain inversion tags (see `AlignmentResult::split_inversions`); otherwise we
+/// use the layout's aligner and the pre-allocated matrix. None if the aligner couldn't align the read
+fn align_to_reference(reference: &Reference,
+                      read_name: &String,
+                      read: &Vec<FastaBase>,
+                      qual_sequence: Option<Vec<u8>>,
+                      read_structure: &SequenceLayout,
+                      aligner: &dyn Aligner,
+                      alignment_mat: &mut Alignment<Ix3>,
+                      my_aff_score: &AffineScoring,
+                      my_score: &InversionScoring,
+                      use_inversions: &bool,
+                      max_indel: &usize) -> Option<AlignmentResult> {
+    let ref_name = &String::from_utf8(reference.name.clone()).unwrap();
+    let aff_score = my_aff_score.for_reference(read_structure, ref_name);
+    let aln = if *use_inversions {
+        let mut aln = inversion_alignment(&reference.sequence, read, ref_name, read_name, &my_score.for_reference(read_structure, ref_name), &aff_score, max_indel, false);
+        aln.read_quals = qual_sequence;
+        Some(aln)
+    } else {
+        aligner.align(reference, read_name, read, qual_sequence, &aff_score, alignment_mat, max_indel)
+    };
+    // indels are placed here, and only here, so every aligner's (and the inversion aligner's) results match
+    aln.map(|aln| aln.normalize_gaps(alignment_mat.gap_placement))
 }
 
 #[allow(dead_code)]
@@ -656,8 +937,8 @@ fn cigar_to_alignment(reference: &Vec<FastaBase>,
     (alignment_string1, alignment_string2)
 }
 
-#[allow(dead_code)]
-pub fn bio_to_alignment_result(_read_name: &String, _ref_name: &String, alignment: bio::alignment::Alignment, reference: &Vec<FastaBase>, read: &Vec<FastaBase>) -> AlignmentResult {
+/// Convert a rust-bio alignment of the read (its x sequence) to the reference (its y sequence) to an `AlignmentResult`
+pub fn bio_to_alignment_result(read_name: &String, ref_name: &String, alignment: bio::alignment::Alignment, reference: &Vec<FastaBase>, read: &Vec<FastaBase>) -> AlignmentResult {
     let mut aligned_ref = Vec::new();
     let mut aligned_read = Vec::new();
     let mut ref_pos = alignment.ystart;
@@ -707,16 +988,16 @@ pub fn bio_to_alignment_result(_read_name: &String, _ref_name: &String, alignmen
         }
     }
     AlignmentResult {
-        reference_name: "".to_string(),
-        read_name: "".to_string(),
+        reference_name: ref_name.clone(),
+        read_name: read_name.clone(),
         reference_aligned: aligned_ref,
         read_aligned: aligned_read,
         read_quals: None,
         cigar_string: simplify_cigar_string(&resulting_cigar),
         path: vec![],
         score: alignment.score as f64,
-        reference_start: alignment.xstart,
-        read_start: alignment.ystart,
+        reference_start: alignment.ystart,
+        read_start: alignment.xstart,
         bounding_box: None,
     }
 }
@@ -767,12 +1048,33 @@ mod tests {
     use sigalign::algorithms::SemiGlobal;
     use sigalign::{Aligner, ReferenceBuilder};
 
-    use crate::alignment::alignment_matrix::{AlignmentTag, AlignmentType, create_scoring_record_3d};
-    use crate::alignment::fasta_bit_encoding::FastaBase;
+    use crate::alignment::aligner::AffineAligner;
+    use crate::alignment::alignment_matrix::{AlignmentResult, AlignmentTag, AlignmentType, create_scoring_record_3d};
+    use crate::alignment::fasta_bit_encoding::{FastaBase, reverse_complement};
     use crate::alignment::scoring_functions::{AffineScoring, InversionScoring};
-    use crate::alignment_functions::{exhaustive_alignment_search, simplify_cigar_string};
+    use crate::alignment_functions::{align_to_reference_choices, align_two_strings_passed_matrix, AlignmentFailure, AlignmentRunStats, exhaustive_alignment_search, mapping_quality, MAX_MAPPING_QUALITY, rank_candidate_alignments, simplify_cigar_string};
     use crate::read_strategies::sequence_layout::{AlignedReadOrientation, ReadPosition, SequenceLayout};
-    use crate::reference::fasta_reference::{ReferenceManager};
+    use crate::reference::fasta_reference::{Reference, ReferenceManager};
+
+    #[test]
+    fn test_integer_aligner_matches_affine() {
+        let reference = FastaBase::from_str("TTAAGCAGTGGTATCAACGCAGAGTACGCCTTAGGTTAACTTGCTATTTCTAGCTCTAACCCCACCCACGATTGCCGCCGACCCCCATATAAGAAAGAT");
+        let read = FastaBase::from_str("TTAAGCAGTGGTATCAACGCAGAGTACGCCTTAGGTTAACTTGCTAGTTCTAGCTCTAACCCCACCAACAAGTTTTTCAACACCTAGCGTGT");
+        let band = reference.len();
+        let name = &"reference".to_string();
+        let read_name = &"read".to_string();
+
+        for scoring in [AffineScoring::default_reference_alignment(), AffineScoring::default_dna()] {
+            let mut affine_mat = create_scoring_record_3d(reference.len() + 1, read.len() + 1, AlignmentType::Affine, false);
+            let expected = align_two_strings_passed_matrix(name, read_name, &reference, &read, None, &scoring, &mut affine_mat, &band);
+
+            let mut integer_mat = create_scoring_record_3d(reference.len() + 1, read.len() + 1, AlignmentType::IntegerAffine, false);
+            assert_eq!(integer_mat.scores.len(), 0);
+            let result = align_two_strings_passed_matrix(name, read_name, &reference, &read, None, &scoring, &mut integer_mat, &band);
+
+            assert_eq!(serde_json::to_string(&result).unwrap(), serde_json::to_string(&expected).unwrap());
+        }
+    }
 
     #[test]
     fn test_find_best_reference() {
@@ -781,17 +1083,19 @@ mod tests {
 
         let read_one = FastaBase::from_string(&"atggactatcatatgcttaccgtaacttgaaagtatttcgatttcttggctttatatatcttgtggaaaggacgaaacaccgGGTAGCAAACGTTTGGACGTGGGGTTAGAGCTAGAAATAGCAAGTTAACCTAAGGCTAGTCCGTTATCAACTTGAAAAAGTGGCACCGAGTCGGTGCTTTTTTTTCCTGCAGGAAACCCCGGGgaat".to_string().to_ascii_uppercase());
 
-        let _read_structure = SequenceLayout {
+        let read_structure = SequenceLayout {
             aligner: None,
             merge: None,
             reads: vec![ReadPosition::Read1 { chain_align: None, orientation: AlignedReadOrientation::Forward }],
             known_strand: true,
             references: BTreeMap::new(),
+            scoring: None,
+            reference_fasta: None,
         };
 
         let mut read_mat = create_scoring_record_3d(read_one.len() + 100, read_one.len() + 100, AlignmentType::Affine, false);
 
-        let _my_score = InversionScoring {
+        let my_score = InversionScoring {
             match_score: 9.0,
             mismatch_score: -21.0,
             gap_open: -25.0,
@@ -807,15 +1111,16 @@ mod tests {
             gap_open: -20.0,
             gap_extend: -1.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
 
         };
 
-        let best_ref = exhaustive_alignment_search(&"testread".to_string(), &read_one, None, &&rm, &mut read_mat, &my_aff_score);
+        let best_ref = exhaustive_alignment_search(&"testread".to_string(), &read_one, None, &&rm, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat, &my_aff_score, &my_score, &false, &read_one.len(), &0);
         assert_eq!(String::from_utf8(best_ref.unwrap().ref_name).unwrap(),
                    String::from_utf8("1_AAACCCCGGG_GGTAGCAAACGTTTGGACGTG".to_string().into_bytes()).unwrap());
 
         let read_one = FastaBase::from_string(&"atggactatcatatgcttaccgtaacttgaaagtatttcgatttcttggctttatatatcttgtggaaaggacgaaacaccgGGTGCCCTTACTCTCACCTGATTACTTAATCCGTGGGGTTAGAGCTAGAAATAGCAAGTTAACCTAAGGCTAGTCCGTTATCAACTTGAAAAAGTGGCACCGAGTCGGTGCTTTTTTTTCCTGCAGGAACGCCCTACgaattcgggcccattggtatggc".to_string().to_ascii_uppercase());
-        let best_ref = exhaustive_alignment_search(&"testread".to_string(), &read_one, None, &&rm, &mut read_mat, &my_aff_score);
+        let best_ref = exhaustive_alignment_search(&"testread".to_string(), &read_one, None, &&rm, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat, &my_aff_score, &my_score, &false, &read_one.len(), &0);
 
         assert_eq!(String::from_utf8(best_ref.unwrap().ref_name).unwrap(),
                    String::from_utf8("2_AACGCCCTAC_GGTGCCCTTACTCTCACCTGATTACTTAATCCGTG".to_string().into_bytes()).unwrap());
@@ -828,17 +1133,19 @@ mod tests {
 
         let read_one = FastaBase::from_string(&"ATGGACTATCATATGCTTACCGTAACTTGAAAGTATTTCGATTTCTTGGCTTTATATATCTTGTGGAAAGGACGAAACACCGGTAAATTTGAGGCTCCGGCATGCAGGAGGCCGTGGGGTTAGAGCTAGAAATAGCAAGTTAACCTAAGGCTAGTCCGTTATCAACTTG".to_string().to_ascii_uppercase());
 
-        let _read_structure = SequenceLayout {
+        let read_structure = SequenceLayout {
             aligner: None,
             merge: None,
             reads: vec![ReadPosition::Read1 { chain_align: None, orientation: AlignedReadOrientation::Forward }],
             known_strand: true,
             references: BTreeMap::new(),
+            scoring: None,
+            reference_fasta: None,
         };
 
         let mut read_mat = create_scoring_record_3d(read_one.len() + 100, read_one.len() + 100, AlignmentType::Affine, false);
 
-        let _my_score = InversionScoring {
+        let my_score = InversionScoring {
             match_score: 9.0,
             mismatch_score: -21.0,
             gap_open: -25.0,
@@ -854,14 +1161,195 @@ mod tests {
             gap_open: -20.0,
             gap_extend: -1.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
 
         };
 
-        let best_ref = exhaustive_alignment_search(&"testread".to_string(), &read_one, None, &&rm, &mut read_mat, &my_aff_score);
+        let best_ref = exhaustive_alignment_search(&"testread".to_string(), &read_one, None, &&rm, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat, &my_aff_score, &my_score, &false, &read_one.len(), &0);
         assert_eq!(String::from_utf8(best_ref.unwrap().ref_name).unwrap(),
                    String::from_utf8("ref_48_GGTAAATTTGAGGCTCCGGCATGCAGGAGGCCGTG".to_string().into_bytes()).unwrap());
     }
 
+    #[test]
+    fn test_mapping_quality() {
+        assert_eq!(mapping_quality(100.0, None), MAX_MAPPING_QUALITY);
+        assert_eq!(mapping_quality(100.0, Some(100.0)), 0);
+        assert_eq!(mapping_quality(100.0, Some(50.0)), MAX_MAPPING_QUALITY / 2);
+        assert_eq!(mapping_quality(100.0, Some(-50.0)), MAX_MAPPING_QUALITY);
+        assert_eq!(mapping_quality(-10.0, Some(-20.0)), 0);
+    }
+
+    fn scored_alignment(reference_name: &str, score: f64) -> AlignmentResult {
+        AlignmentResult {
+            reference_name: reference_name.to_string(),
+            read_name: "read".to_string(),
+            reference_aligned: FastaBase::from_str("ACGT"),
+            read_aligned: FastaBase::from_str("ACGT"),
+            read_quals: None,
+            cigar_string: vec![AlignmentTag::MatchMismatch(4)],
+            path: vec![],
+            score,
+            reference_start: 0,
+            read_start: 0,
+            bounding_box: None,
+        }
+    }
+
+    #[test]
+    fn test_rank_candidates_with_nan_score() {
+        let candidates = vec![
+            (scored_alignment("nan", f64::NAN), vec![], b"nan".to_vec()),
+            (scored_alignment("low", 10.0), vec![], b"low".to_vec()),
+            (scored_alignment("high", 40.0), vec![], b"high".to_vec()),
+        ];
+        let ranked = rank_candidate_alignments(candidates, &2).unwrap();
+        assert_eq!(ranked.ref_name, b"high".to_vec());
+        assert_eq!(ranked.suboptimal_score, Some(10.0));
+
+        let candidates = vec![(scored_alignment("nan", f64::NAN), vec![], b"nan".to_vec())];
+        assert!(rank_candidate_alignments(candidates, &2).is_none());
+    }
+
+    #[test]
+    fn test_ranked_reference_candidates() {
+        let ref_location = &"test_data/test_best_alignment.fasta".to_string();
+        let rm = ReferenceManager::from_fa_file(&ref_location, 8, 8);
+        let read_structure = known_strand_read_structure();
+
+        let read_one = FastaBase::from_string(&"atggactatcatatgcttaccgtaacttgaaagtatttcgatttcttggctttatatatcttgtggaaaggacgaaacaccgGGTAGCAAACGTTTGGACGTGGGGTTAGAGCTAGAAATAGCAAGTTAACCTAAGGCTAGTCCGTTATCAACTTGAAAAAGTGGCACCGAGTCGGTGCTTTTTTTTCCTGCAGGAAACCCCGGGgaat".to_string().to_ascii_uppercase());
+        let mut read_mat = create_scoring_record_3d(read_one.len() + 100, read_one.len() + 100, AlignmentType::Affine, false);
+
+        // the references share most of their sequence, so the runner-up is close and the read is only weakly unique
+        let best_ref = exhaustive_alignment_search(&"testread".to_string(), &read_one, None, &&rm, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat,
+                                                   &AffineScoring::default_reference_alignment(), &InversionScoring::default(), &false, &read_one.len(), &0).unwrap();
+        let best_score = best_ref.alignment.as_ref().unwrap().score;
+        let suboptimal_score = best_ref.suboptimal_score.unwrap();
+        assert!(suboptimal_score <= best_score);
+        assert!(best_ref.mapping_quality < MAX_MAPPING_QUALITY);
+        assert_eq!(best_ref.mapping_quality, mapping_quality(best_score, Some(suboptimal_score)));
+        assert!(best_ref.secondary_alignments.is_empty());
+
+        // secondary alignments are near-ties only, and are never the primary reference
+        let best_ref = exhaustive_alignment_search(&"testread".to_string(), &read_one, None, &&rm, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat,
+                                                   &AffineScoring::default_reference_alignment(), &InversionScoring::default(), &false, &read_one.len(), &10).unwrap();
+        best_ref.secondary_alignments.iter().for_each(|secondary| {
+            assert!(secondary.score >= best_score - best_score.abs() * 0.05);
+            assert_ne!(secondary.reference_name.as_bytes(), best_ref.ref_name.as_slice());
+        });
+    }
+
+    fn known_strand_read_structure() -> SequenceLayout {
+        SequenceLayout {
+            aligner: None,
+            merge: None,
+            reads: vec![ReadPosition::Read1 { chain_align: None, orientation: AlignedReadOrientation::Forward }],
+            known_strand: true,
+            references: BTreeMap::new(),
+            scoring: None,
+            reference_fasta: None,
+        }
+    }
+
+    #[test]
+    fn test_multi_reference_orientation() {
+        let ref_location = &"test_data/two_references.fa".to_string();
+        let rm = ReferenceManager::from_fa_file(&ref_location, 15, 5);
+        let mut read_structure = known_strand_read_structure();
+        read_structure.known_strand = false;
+        let cas_tag = rm.references.values().find(|r| r.name == "cas_tag".as_bytes().to_vec()).unwrap();
+        let mut read_mat = create_scoring_record_3d(rm.longest_ref + 1, (rm.longest_ref + 1) * 2, AlignmentType::Affine, false);
+
+        for fast_lookup in [true, false] {
+            let forward_read = cas_tag.sequence.clone();
+            let result = align_to_reference_choices(&"forward_read".to_string(), &forward_read, None, &rm, &fast_lookup, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat,
+                                                    &AffineScoring::default_reference_alignment(), &InversionScoring::default(), &false, 2.0, 50, &forward_read.len(), &0).unwrap();
+            assert_eq!(result.ref_name, cas_tag.name);
+            assert!(!result.reverse_strand);
+
+            // the reverse complement is flipped back before we align it
+            let reverse_read = reverse_complement(&cas_tag.sequence);
+            let result = align_to_reference_choices(&"reverse_read".to_string(), &reverse_read, None, &rm, &fast_lookup, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat,
+                                                    &AffineScoring::default_reference_alignment(), &InversionScoring::default(), &false, 2.0, 50, &reverse_read.len(), &0).unwrap();
+            assert_eq!(result.ref_name, cas_tag.name);
+            assert!(result.reverse_strand);
+            assert_eq!(FastaBase::string(&result.alignment.unwrap().read_aligned).replace("-", ""), FastaBase::string(&cas_tag.sequence));
+        }
+    }
+
+    #[test]
+    fn test_min_read_length_gate() {
+        let ref_location = &"test_data/two_references_just_one.fa".to_string();
+        let rm = ReferenceManager::from_fa_file(&ref_location, 8, 4);
+        let read_structure = known_strand_read_structure();
+        let reference = rm.references.get(&0).unwrap().sequence.clone();
+        let mut read_mat = create_scoring_record_3d(rm.longest_ref + 1, (rm.longest_ref + 1) * 2, AlignmentType::Affine, false);
+
+        // a 30 base fragment of the reference is turned away before we try to align it
+        let short_read = reference[0..30].to_vec();
+        let result = align_to_reference_choices(&"short_read".to_string(), &short_read, None, &rm, &false, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat,
+                                                &AffineScoring::default_reference_alignment(), &InversionScoring::default(), &false, 2.0, 50, &short_read.len(), &0);
+        assert_eq!(result.err(), Some(AlignmentFailure::BelowMinReadLength));
+
+        // but it's fine if we lower the minimum
+        let result = align_to_reference_choices(&"short_read".to_string(), &short_read, None, &rm, &false, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat,
+                                                &AffineScoring::default_reference_alignment(), &InversionScoring::default(), &false, 2.0, 20, &short_read.len(), &0);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_max_reference_multiplier_gate() {
+        let ref_location = &"test_data/two_references_just_one.fa".to_string();
+        let rm = ReferenceManager::from_fa_file(&ref_location, 8, 4);
+        let read_structure = known_strand_read_structure();
+        let reference = rm.references.get(&0).unwrap().sequence.clone();
+        let mut read_mat = create_scoring_record_3d(rm.longest_ref + 1, (rm.longest_ref + 1) * 2, AlignmentType::Affine, false);
+
+        // the full reference aligns
+        let result = align_to_reference_choices(&"full_read".to_string(), &reference, None, &rm, &false, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat,
+                                                &AffineScoring::default_reference_alignment(), &InversionScoring::default(), &false, 1.25, 50, &reference.len(), &0);
+        assert!(result.is_ok());
+
+        // a read with an extra 200 bases is ~1.5x the reference, which fails a 1.25 multiplier but passes at 2.0
+        let mut long_read = reference.clone();
+        long_read.extend(reference[0..200].iter());
+        let result = align_to_reference_choices(&"long_read".to_string(), &long_read, None, &rm, &false, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat,
+                                                &AffineScoring::default_reference_alignment(), &InversionScoring::default(), &false, 1.25, 50, &long_read.len(), &0);
+        assert_eq!(result.err(), Some(AlignmentFailure::ExceedsReferenceMultiplier));
+
+        let result = align_to_reference_choices(&"long_read".to_string(), &long_read, None, &rm, &false, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat,
+                                                &AffineScoring::default_reference_alignment(), &InversionScoring::default(), &false, 2.0, 50, &long_read.len(), &0);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_alignment_run_stats() {
+        let mut stats = AlignmentRunStats::default();
+        stats.aligned_read("ref1", 105.0);
+        stats.aligned_read("ref1", 101.0);
+        stats.aligned_read("ref2", -3.0);
+        stats.too_long_read();
+        stats.no_candidate_reference_read();
+        stats.below_min_length_read();
+        stats.unparseable_reads(2);
+
+        assert_eq!(stats.total_reads, 8);
+        assert_eq!(stats.aligned, 3);
+        assert_eq!(stats.reference_hits.get("ref1"), Some(&2));
+        assert_eq!(stats.reference_hits.get("ref2"), Some(&1));
+        assert_eq!(stats.score_distributions.get("ref1").unwrap().get(&100), Some(&2));
+        assert_eq!(stats.score_distributions.get("ref2").unwrap().get(&-10), Some(&1));
+
+        let stats_path = AlignmentRunStats::stats_path(&std::path::PathBuf::from("test_output/aligned.bam"));
+        assert_eq!(stats_path, std::path::PathBuf::from("test_output/aligned.alignment_stats.json"));
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let json_file = temp_dir.path().join("aligned.alignment_stats.json");
+        stats.write_json(&json_file);
+        let written: serde_json::Value = serde_json::from_reader(File::open(&json_file).unwrap()).unwrap();
+        assert_eq!(written["aligned"], 3);
+        assert_eq!(written["reference_hits"]["ref1"], 2);
+    }
+
     #[test]
     fn simplify_cigar_test() {
         let input_cigar = vec![AlignmentTag::MatchMismatch(1), AlignmentTag::MatchMismatch(1), AlignmentTag::MatchMismatch(1)];
@@ -958,17 +1446,19 @@ CTACACGACGCTCTTCCGATCTNNNNNNNNNNNNNNNNNNNNNNNNNNNNTTTTTTTTTTTTTTTTTTTTTTTTTTTTTT
 
         let read_one = FastaBase::from_string(&"TTCCGATCTGTCATAACACCACACTAGAATCACGCGTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTAGCGATGCAATTTCCTCATTTTATTAGGAAAGGACAGTGGGAGTGGCACCTTCCAGGGTCAAGGAAGGCACGGGGGAGGGGCAAACAACAGATGGCTGGCAACTAGAAGGCACAGTGAGCTTGTACATAACTACGCAAGTCCTTGCTAGGACCGGCCTTAAAGCCACGTGGCGGCCGCCGAGCGGTATCAGCTCACTCAAAGGCGGTAATACGGTTATCCACAGAATCGTGGTACAATATGCGTCTCCGAAATTAACCCGGTGCGTTTAAACGAAAAGGACCGACTACTACCTCGCGAAAGCTCTAAGCGTCGTGTCAGCGAAACTTCGCGGAGGTTCGACATCGAAAGACACGCGGGTGTATGTGGCGAAAGCAGCAACCTGATCTGGGGTGAAAAGCCATGGACGCCGGGACGAGAAAGGTCTAGGACTGTTTTGCGAGAAAAGGATTAGAGTTAGAATCGCGAAACGCTCGCGTTCCACCGCTCCGAAAGATCCCGAGGTCGTTTTACCGAAAGCGACGACTTCTGTCATAGTGAAACGATTGGACGTCTCTGGTGCGAAATCGCGGGTTGTACAACATACGAAACCGAGGCTACAACCCCGGACGAAAAGGTATAGGTAGCTAACACGCGAAACCCTAGGGATCGTGCTAGCCGAAAGCCCTATCACGCAGGGGACTGAAAAACATGGGCACGCCCCCGATGAAACGCTGCTTGTCTGGCCTCGCGAAAGAATGAGCAGAGCGTGAGGCGAAAAGCTTAAGCTGTGCACTCTCGAAAGTCGGTGTCCATCAGTGGATGAAACAGCGGGTTCCTGCTCCCGCGAAACGCCACCTGTACGTTACTTCGAAAATGAAGGGACAGCGGCGGACGAAAGTCATATTCCGTTGTGGTACGAAATTGGTCCTGATGCACGCACAGAAAAGATTGACCTCCGTTCGTACGAAAGCTCGGCCTCTGGGAGTCGTGAAAGACTCGGATCCGCACCAGATGAAAGGCACACCCACGCCCGTCACGAAAACCCAAACCTTGTATGTATGGAAATCTTCTGCGTCCGGGCCGCGGAAAAGCGTATACCTATCTCGCATGAAAGTCTCTCACCTCGTCTACGCGAAACGCTCGTACGCGTACGGGCTGAAAGCGATACACCGCTCGCCCCTGAAACCCTCTAGTTACGCGCCAGTGAAAGAGTCGCGTAGAGTACAGTGCAAGGTCGACAATCAACCTCTGGATTACATCCGATTGCCTCACTGTGCGAAAGTACTCGATGGCGTGGCTTAGAAAGCGTACAGTCTCCGTGCCGGGAAAATAAGAGCGCCTGCGGTTATGAAATCGTGGGCTACTCCTGGGTGGAAAGCTATCCTGCACATTAGTACGAAAGGTGCCAGGTTGCTTCGATCGAAAGCCCGAGAGATCACTCGTAGGAAACTACGCCGGTCACGACGGGCGAAACGACATGAACTCATCCGGACGAAAGGTAGTCCTTACGGTGATCTGCTAGGGTCTCTCCTAGCAACGGTTACTCCATCTGGTACACCCCCTGCTCGGGGCAAGTACCTGATGCGGCACAATGTCTAGCAGGTGCTGAAGAAAGTTGTCGGTGTCTTTGTGTTAACCTTAGCAATACGTCTGTCGAAGCAGCTACAA".to_string().to_ascii_uppercase());
 
-        let _read_structure = SequenceLayout {
+        let read_structure = SequenceLayout {
             aligner: None,
             merge: None,
             reads: vec![ReadPosition::Read1 { chain_align: None, orientation: AlignedReadOrientation::Forward }],
             known_strand: true,
             references: BTreeMap::new(),
+            scoring: None,
+            reference_fasta: None,
         };
 
         let mut read_mat = create_scoring_record_3d(read_one.len() + 100, read_one.len() + 100, AlignmentType::Affine, false);
 
-        let _my_score = InversionScoring {
+        let my_score = InversionScoring {
             match_score: 9.0,
             mismatch_score: -21.0,
             gap_open: -25.0,
@@ -984,11 +1474,12 @@ CTACACGACGCTCTTCCGATCTNNNNNNNNNNNNNNNNNNNNNNNNNNNNTTTTTTTTTTTTTTTTTTTTTTTTTTTTTT
             gap_open: -20.0,
             gap_extend: -1.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
 
         };
 
         for i in 0..10 {
-            let best_ref = exhaustive_alignment_search(&"testread".to_string(), &read_one, None, &&rm, &mut read_mat, &my_aff_score);
+            let best_ref = exhaustive_alignment_search(&"testread".to_string(), &read_one, None, &&rm, &read_structure, &AffineAligner::new(AlignmentType::Affine), &mut read_mat, &my_aff_score, &my_score, &false, &read_one.len(), &0);
         }
     }
 
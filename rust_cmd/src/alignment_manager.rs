@@ -7,13 +7,21 @@ use bstr::BString;
 
 use noodles_sam::header::record::value::map::ReferenceSequence;
 use noodles_sam::header::record::value::Map;
+use noodles_sam::header::record::value::map;
+use noodles_sam::header::record::value::map::header::{sort_order, tag as header_tag};
+use noodles_sam::alignment::RecordBuf;
+use noodles_bam as bam;
+use noodles_bam::bai;
+use serde::{Deserialize, Serialize};
+use shardio::{Range, ShardReader, ShardSender, ShardWriter};
+use std::cmp::Ordering;
 use noodles_sam::Header;
 use noodles_util::alignment;
 use std::collections::HashMap;
 
 
 
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
@@ -21,11 +29,14 @@ use indexmap::IndexMap;
 
 use crate::alignment::alignment_matrix::{
     create_scoring_record_3d, perform_3d_global_traceback, perform_affine_alignment,
-    AlignmentResult, AlignmentTag, AlignmentType,
+    unmapped_sam_record, AlignmentResult, AlignmentTag, AlignmentType,
 };
 use crate::alignment::fasta_bit_encoding::FastaBase;
 use noodles_sam;
 use noodles_sam::alignment::record::Name;
+use noodles_sam::alignment::record::Flags;
+use noodles_sam::alignment::record_buf::data::field::Value;
+use itertools::Itertools;
 
 
 
@@ -35,7 +46,62 @@ pub trait OutputAlignmentWriter: Sync + Send {
     fn write_read(
         &mut self,
         read_set_container: &SortingReadSetContainer,
-        additional_tags: &HashMap<[u8; 2], String>,
+        additional_tags: &HashMap<[u8; 2], Value>,
+    ) -> Result<()> {
+        self.write_read_with_flags(read_set_container, additional_tags, Flags::empty(), None)
+    }
+
+    /// write a read with explicit SAM flags and mapping quality, e.g. the supplementary records of an inversion-split
+    /// alignment or the secondary records of an ambiguous read
+    fn write_read_with_flags(
+        &mut self,
+        read_set_container: &SortingReadSetContainer,
+        additional_tags: &HashMap<[u8; 2], Value>,
+        flags: Flags,
+        mapping_quality: Option<u8>,
+    ) -> Result<()>;
+
+    /// write a read whose alignment was split around inversions (see `AlignmentResult::split_inversions`): the primary
+    /// record, then a supplementary record on the opposite strand for each inverted segment, all cross-referenced by SA
+    /// tags
+    fn write_split_read(
+        &mut self,
+        read_set_container: &SortingReadSetContainer,
+        additional_tags: &HashMap<[u8; 2], Value>,
+        inverted_segments: &[AlignmentResult],
+        reverse_strand: bool,
+        mapping_quality: Option<u8>,
+    ) -> Result<()> {
+        let strand_flag = if reverse_strand { Flags::REVERSE_COMPLEMENTED } else { Flags::empty() };
+        let inverted_strand_flag = if reverse_strand { Flags::empty() } else { Flags::REVERSE_COMPLEMENTED };
+        let sa_mapping_quality = mapping_quality.unwrap_or(255);
+
+        let mut primary_tags = additional_tags.clone();
+        if !inverted_segments.is_empty() {
+            primary_tags.insert([b'S', b'A'], Value::from(inverted_segments.iter().map(|s| s.supplementary_alignment_entry(!reverse_strand, sa_mapping_quality)).collect::<Vec<String>>().join("")));
+        }
+        self.write_read_with_flags(read_set_container, &primary_tags, strand_flag, mapping_quality)?;
+
+        for (index, segment) in inverted_segments.iter().enumerate() {
+            let mut other_alignments = vec![read_set_container.aligned_read.supplementary_alignment_entry(reverse_strand, sa_mapping_quality)];
+            other_alignments.extend(inverted_segments.iter().enumerate().filter(|(i, _)| *i != index).map(|(_, s)| s.supplementary_alignment_entry(!reverse_strand, sa_mapping_quality)));
+            let mut segment_tags: HashMap<[u8; 2], Value> = HashMap::new();
+            segment_tags.insert([b'S', b'A'], Value::from(other_alignments.join("")));
+
+            self.write_read_with_flags(&read_set_container.with_new_alignment(segment.clone()),
+                                       &segment_tags,
+                                       Flags::SUPPLEMENTARY | inverted_strand_flag,
+                                       mapping_quality)?;
+        }
+        Ok(())
+    }
+
+    /// write a read we couldn't align as an unmapped record
+    fn write_unmapped_read(
+        &mut self,
+        read_name: &str,
+        read: &[FastaBase],
+        read_quals: &Option<Vec<u8>>,
     ) -> Result<()>;
 
 }
@@ -45,24 +111,42 @@ pub struct BamFileAlignmentWriter<'a> {
     underlying_bam_file: Arc<Mutex<noodles_util::alignment::io::Writer>>,
     header: noodles_sam::Header,
     reference_manager: ReferenceManager<'a, 'a, 'a>,
+    extended_cigar: bool,
+    path: PathBuf,
+    sorting_file: Option<PathBuf>,
+    shard_writer: Option<Box<ShardWriter<CoordinateSortedRecord>>>,
+    shard_sender: Option<Box<ShardSender<CoordinateSortedRecord>>>,
 }
 
 unsafe impl<'a> Send for BamFileAlignmentWriter<'a> {}
 unsafe impl<'a> Sync for BamFileAlignmentWriter<'a> {}
 
 impl<'a> BamFileAlignmentWriter<'a> {
+    /// create a BAM writer. With a `sorting_file`, records are sorted on disk there and the BAM is written in coordinate
+    /// order, with a BAI index, when the writer is finished; otherwise records are written in the order we get them
     pub fn new(
         path: &PathBuf,
         reference_manager: &ReferenceManager<'a, 'a, 'a>,
+        extended_cigar: bool,
+        sorting_file: Option<PathBuf>,
     ) -> BamFileAlignmentWriter<'a> {
 
-        let reference_sequences : IndexMap<BString,Map<ReferenceSequence>> = reference_manager.references.iter().map(|(_k, v)| {
+        // records refer to references by their ID, so the header has to list them in ID order
+        let reference_sequences : IndexMap<BString,Map<ReferenceSequence>> = reference_manager.references.iter().sorted_by_key(|(k, _v)| **k).map(|(_k, v)| {
             (BString::from(v.name.clone()), 
             Map::<ReferenceSequence>::new(NonZeroUsize::try_from(v.sequence_u8.len()).unwrap()))
         }).into_iter().collect();
-        
+
+        let header_map = match sorting_file {
+            None => Map::<map::Header>::default(),
+            Some(_) => Map::<map::Header>::builder()
+                .insert(header_tag::SORT_ORDER, sort_order::COORDINATE)
+                .build()
+                .expect("Unable to create a coordinate-sorted header"),
+        };
+
         let header = Header::builder()
-            .set_header(Default::default())
+            .set_header(header_map)
             .add_comment("Clique processed")
             .set_reference_sequences(reference_sequences)
             .build();
@@ -76,23 +160,66 @@ impl<'a> BamFileAlignmentWriter<'a> {
             .write_header(&header)
             .expect("Unable to write header to output bam file");
 
+        let mut shard_writer: Option<Box<ShardWriter<CoordinateSortedRecord>>> = sorting_file.as_ref().map(|sorting_file| {
+            Box::new(ShardWriter::new(sorting_file, 32, 256, 1 << 16).expect("Unable to create the BAM sorting file"))
+        });
+        let shard_sender = shard_writer.as_mut().map(|shard_writer| Box::new(shard_writer.get_sender()));
+
         BamFileAlignmentWriter {
             underlying_bam_file: Arc::new(Mutex::new(writer)),
             header,
             reference_manager: reference_manager.clone(),
+            extended_cigar,
+            path: path.clone(),
+            sorting_file,
+            shard_writer,
+            shard_sender,
         }
     }
+
+    fn write_record(&self, samrecord: &RecordBuf) -> Result<()> {
+        let mut output = self.underlying_bam_file.lock().unwrap();
+
+        output.write_record(&self.header, samrecord).map_err(|e| {
+            let read_name = samrecord.name().map(|name| String::from_utf8_lossy(name.as_bytes()).to_string()).unwrap_or_default();
+            Error::new(e.kind(), format!("Unable to write read {} to the bam file: {}", read_name, e))
+        })
+    }
+
+    /// write out any sorted records, close the BAM file, and index it if it's coordinate-sorted
+    pub fn finish(&mut self) -> Result<()> {
+        if let (Some(mut shard_writer), Some(mut shard_sender)) = (self.shard_writer.take(), self.shard_sender.take()) {
+            shard_sender.finished().map_err(|e| sorting_error("Unable to flush the reads to the BAM sorting file", e))?;
+            shard_writer.finish().map_err(|e| sorting_error("Unable to finish the BAM sorting file", e))?;
+
+            let reader: ShardReader<CoordinateSortedRecord> = ShardReader::open(self.sorting_file.as_ref().unwrap())
+                .map_err(|e| sorting_error("Unable to open the BAM sorting file", e))?;
+            for record in reader.iter_range(&Range::all()).map_err(|e| sorting_error("Unable to read the BAM sorting file", e))? {
+                let record = record.map_err(|e| sorting_error("Unable to read a record from the BAM sorting file", e))?;
+                self.write_record(&record.to_sam_record(self.extended_cigar))?;
+            }
+
+            self.underlying_bam_file.lock().unwrap().finish(&self.header)?;
+
+            let mut index_path = self.path.clone().into_os_string();
+            index_path.push(".bai");
+            let index = bam::fs::index(&self.path)?;
+            bai::write(index_path, &index)?;
+        } else {
+            self.underlying_bam_file.lock().unwrap().finish(&self.header)?;
+        }
+        Ok(())
+    }
 }
 
 impl<'a> OutputAlignmentWriter for BamFileAlignmentWriter<'a> {
-    fn write_read(
+    fn write_read_with_flags(
         &mut self,
         read_set_container: &SortingReadSetContainer,
-        additional_tags: &HashMap<[u8; 2], String>
+        additional_tags: &HashMap<[u8; 2], Value>,
+        flags: Flags,
+        mapping_quality: Option<u8>,
     ) -> Result<()> {
-        let writer = Arc::clone(&self.underlying_bam_file);
-
-
         let reference_record = self
             .reference_manager
             .reference_name_to_ref
@@ -109,34 +236,146 @@ impl<'a> OutputAlignmentWriter for BamFileAlignmentWriter<'a> {
         read_set_container.ordered_sorting_keys.iter().for_each(|(key, value)| {
             extra_annotations.insert(
                 [b'e', *key as u8],
-                FastaBase::string(value),
+                Value::from(FastaBase::string(value)),
             );
         });
 
-        let samrecord =
-            read_set_container
-                .aligned_read
-                .to_sam_record(&(*reference_record as i32), &extra_annotations, None);
+        match self.shard_sender.as_mut() {
+            Some(sender) => {
+                sender.send(CoordinateSortedRecord::Mapped {
+                    reference_id: *reference_record,
+                    alignment: read_set_container.aligned_read.clone(),
+                    tags: extra_annotations.iter().map(|(k, v)| (*k, SortedTagValue::from(v))).collect(),
+                    flags: u16::from(flags),
+                    mapping_quality,
+                }).map_err(|e| sorting_error("Unable to send a read to the BAM sorting file", e))?;
+            }
+            None => {
+                let samrecord =
+                    read_set_container
+                        .aligned_read
+                        .to_sam_record_with_flags(&(*reference_record as i32), &extra_annotations, None, flags, mapping_quality, self.extended_cigar);
+                self.write_record(&samrecord)?;
+            }
+        }
+        Ok(())
+    }
 
-        let mut output = writer.lock().unwrap();
-        
-        match output
-            .write_record(&self.header, &samrecord) {
-                Ok(_x) => {
-                    //println!("Added read!")
-                },
-                Err(e) => {
-                    println!("Sequence: {} {:?}", String::from_utf8(samrecord.name().unwrap().as_bytes().to_vec()).unwrap(),samrecord);
-                    println!("error kind {} {}", e.kind().to_string(),e.to_string());
-                    panic!("Unable to write record to bam file; error {}", e);
-                },
+    fn write_unmapped_read(
+        &mut self,
+        read_name: &str,
+        read: &[FastaBase],
+        read_quals: &Option<Vec<u8>>,
+    ) -> Result<()> {
+        match self.shard_sender.as_mut() {
+            Some(sender) => {
+                sender.send(CoordinateSortedRecord::Unmapped {
+                    read_name: read_name.to_string(),
+                    read: read.to_vec(),
+                    read_quals: read_quals.clone(),
+                }).map_err(|e| sorting_error("Unable to send an unmapped read to the BAM sorting file", e))?;
             }
-            
+            None => { self.write_record(&unmapped_sam_record(read_name, read, read_quals))?; }
+        }
         Ok(())
     }
 
 }
 
+/// shardio has its own error type, so we carry its message in an io error
+fn sorting_error(context: &str, error: impl std::fmt::Display) -> Error {
+    Error::new(ErrorKind::Other, format!("{}: {}", context, error))
+}
+
+/// an aux. tag value we can store in the sorting shards; these are the value types we write
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SortedTagValue {
+    Int32(i32),
+    UInt32(u32),
+    Float(f32),
+    String(String),
+}
+
+impl From<&Value> for SortedTagValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Int32(x) => SortedTagValue::Int32(*x),
+            Value::UInt32(x) => SortedTagValue::UInt32(*x),
+            Value::Float(x) => SortedTagValue::Float(*x),
+            Value::String(x) => SortedTagValue::String(x.to_string()),
+            _ => panic!("Unable to sort a record with the tag value {:?}", value),
+        }
+    }
+}
+
+impl From<&SortedTagValue> for Value {
+    fn from(value: &SortedTagValue) -> Self {
+        match value {
+            SortedTagValue::Int32(x) => Value::from(*x),
+            SortedTagValue::UInt32(x) => Value::from(*x),
+            SortedTagValue::Float(x) => Value::from(*x),
+            SortedTagValue::String(x) => Value::from(x.clone()),
+        }
+    }
+}
+
+/// a record waiting in the on-disk coordinate sort, holding what we need to make its SAM record. Mapped records sort
+/// by reference ID, position, and read name; unmapped records go at the end of the file, as the SAM spec asks
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CoordinateSortedRecord {
+    Mapped {
+        reference_id: usize,
+        alignment: AlignmentResult,
+        tags: Vec<([u8; 2], SortedTagValue)>,
+        flags: u16,
+        mapping_quality: Option<u8>,
+    },
+    Unmapped {
+        read_name: String,
+        read: Vec<FastaBase>,
+        read_quals: Option<Vec<u8>>,
+    },
+}
+
+impl CoordinateSortedRecord {
+    fn sort_key(&self) -> (usize, usize, &str) {
+        match self {
+            CoordinateSortedRecord::Mapped { reference_id, alignment, .. } => (*reference_id, alignment.reference_start, alignment.read_name.as_str()),
+            CoordinateSortedRecord::Unmapped { read_name, .. } => (usize::MAX, 0, read_name.as_str()),
+        }
+    }
+
+    pub fn to_sam_record(&self, extended_cigar: bool) -> RecordBuf {
+        match self {
+            CoordinateSortedRecord::Mapped { reference_id, alignment, tags, flags, mapping_quality } => {
+                let tags = tags.iter().map(|(k, v)| (*k, Value::from(v))).collect::<HashMap<[u8; 2], Value>>();
+                alignment.to_sam_record_with_flags(&(*reference_id as i32), &tags, None, Flags::from(*flags), *mapping_quality, extended_cigar)
+            }
+            CoordinateSortedRecord::Unmapped { read_name, read, read_quals } => unmapped_sam_record(read_name, read, read_quals),
+        }
+    }
+}
+
+impl Eq for CoordinateSortedRecord {}
+
+impl PartialEq<Self> for CoordinateSortedRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.sort_key() == other.sort_key()
+    }
+}
+
+impl PartialOrd for CoordinateSortedRecord {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CoordinateSortedRecord {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+}
+
 pub fn align_two_strings(
     reference_sequence: &Vec<FastaBase>,
     read_sequence: &Vec<FastaBase>,
@@ -365,3 +604,58 @@ pub fn simplify_cigar_string(cigar_tokens: &Vec<AlignmentTag>) -> Vec<AlignmentT
     }
     new_cigar
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn str_to_fasta_vec(input: &str) -> Vec<FastaBase> {
+        FastaBase::from_vec_u8(&input.as_bytes().to_vec())
+    }
+
+    fn mapped_record(reference_id: usize, reference_start: usize, read_name: &str) -> CoordinateSortedRecord {
+        CoordinateSortedRecord::Mapped {
+            reference_id,
+            alignment: AlignmentResult {
+                reference_name: "ref".to_string(),
+                read_name: read_name.to_string(),
+                reference_aligned: str_to_fasta_vec("ACGT"),
+                read_aligned: str_to_fasta_vec("ACGT"),
+                read_quals: None,
+                cigar_string: vec![AlignmentTag::MatchMismatch(4)],
+                path: vec![],
+                score: 4.0,
+                reference_start,
+                read_start: 0,
+                bounding_box: None,
+            },
+            tags: vec![([b'X', b'S'], SortedTagValue::Float(2.5)), ([b'e', b'1'], SortedTagValue::String("ACGT".to_string()))],
+            flags: u16::from(Flags::REVERSE_COMPLEMENTED),
+            mapping_quality: Some(60),
+        }
+    }
+
+    #[test]
+    fn coordinate_sorted_record_order() {
+        let unmapped = CoordinateSortedRecord::Unmapped { read_name: "a".to_string(), read: str_to_fasta_vec("ACGT"), read_quals: None };
+        let mut records = vec![unmapped.clone(), mapped_record(1, 0, "b"), mapped_record(0, 10, "a"), mapped_record(0, 2, "c"), mapped_record(0, 2, "b")];
+        records.sort();
+
+        let keys = records.iter().map(|r| r.sort_key()).map(|(id, pos, name)| (id, pos, name.to_string())).collect::<Vec<(usize, usize, String)>>();
+        assert_eq!(keys, vec![(0, 2, "b".to_string()), (0, 2, "c".to_string()), (0, 10, "a".to_string()), (1, 0, "b".to_string()), (usize::MAX, 0, "a".to_string())]);
+    }
+
+    #[test]
+    fn coordinate_sorted_record_to_sam() {
+        let record = mapped_record(1, 10, "read").to_sam_record(false);
+        assert_eq!(record.reference_sequence_id(), Some(1));
+        assert_eq!(record.alignment_start().unwrap().get(), 11);
+        assert!(record.flags().is_reverse_complemented());
+        assert_eq!(record.mapping_quality().map(u8::from), Some(60));
+        assert_eq!(record.data().get(&noodles_sam::alignment::record::data::field::Tag::from([b'X', b'S'])), Some(&Value::from(2.5f32)));
+        assert_eq!(SortedTagValue::from(&Value::from(7u32)), SortedTagValue::UInt32(7));
+
+        let unmapped = CoordinateSortedRecord::Unmapped { read_name: "read".to_string(), read: str_to_fasta_vec("ACGT"), read_quals: None }.to_sam_record(false);
+        assert!(unmapped.flags().is_unmapped());
+    }
+}
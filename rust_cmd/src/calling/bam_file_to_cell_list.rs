@@ -172,7 +172,7 @@ fn extract_read_cigar_elements(ref_start: &u32, reference_sequence: &Vec<u8>, re
 
     cigar.iter().for_each(|x| {
         match x {
-            Cigar::Match(ln) => {
+            Cigar::Match(ln) | Cigar::Equal(ln) | Cigar::Diff(ln) => {
                 alignments.extend(breakup_nucleotide_sequences(&reference_sequence[(ref_pos as usize)..((ref_pos + ln) as usize)], &read_seq[(read_pos as usize)..((read_pos + ln) as usize)], &ref_pos));
                 ref_pos += ln;
                 read_pos += ln;
@@ -197,12 +197,6 @@ fn extract_read_cigar_elements(ref_start: &u32, reference_sequence: &Vec<u8>, re
             Cigar::Pad(_ln) => {
                 panic!("Pad Unsupported")
             }
-            Cigar::Equal(_ln) => {
-                panic!("Equal Unsupported")
-            }
-            Cigar::Diff(_ln) => {
-                panic!("Diff Unsupported")
-            }
         }
     });
     assert_eq!(reference_sequence.len(), ref_pos.try_into().unwrap());
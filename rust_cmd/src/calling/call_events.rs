@@ -71,7 +71,8 @@ impl ExtractorTags {
             &[b'a', b'd'] => { Some(ExtractorTags::AC{value: value.parse::<u64>().expect("Unable to parse integer from rc tag")})},
             &[b'a', b'n'] => { Some(ExtractorTags::AC{value: value.parse::<u64>().expect("Unable to parse integer from rc tag")})},
             &[b'a', b'r'] => { None}, // TODO process read names
-            &[b'a', b's'] => { Some(ExtractorTags::AS{value: value.parse::<f64>().expect("Unable to parse integer from rc tag")})},
+            &[b'A', b'S'] => { Some(ExtractorTags::AS{value: value.parse::<f64>().expect("Unable to parse a number from the AS tag")})},
+            &[b'a', b's'] => { Some(ExtractorTags::AS{value: value.parse::<f64>().expect("Unable to parse a number from the as tag")})},
             &[x, _y] if x == b'b' => {
                 // make sure the reference is in the lookup table
                 assert!(sequence_layout.references.contains_key(reference_name));
@@ -354,7 +355,7 @@ fn extract_read_cigar_elements(ref_start: &usize, reference_sequence: &Vec<u8>,
     cigar.iter().enumerate().for_each(|(index,x)| {
         println!("x {} read pos {} ref pos {}",x.to_string(),read_pos, ref_pos);
         match x {
-            Cigar::Match(ln) => {
+            Cigar::Match(ln) | Cigar::Equal(ln) | Cigar::Diff(ln) => {
                 let ref_seq = &reference_sequence[(ref_pos as usize)..((ref_pos + *ln as usize) as usize)];
                 let read_seq = &read_seq[(read_pos as usize)..((read_pos + *ln as usize) as usize)];
                 alignments.extend(breakup_nucleotide_sequences(ref_seq, read_seq, &ref_pos));
@@ -403,12 +404,6 @@ fn extract_read_cigar_elements(ref_start: &usize, reference_sequence: &Vec<u8>,
             Cigar::Pad(_ln) => {
                 panic!("Pad Unsupported")
             }
-            Cigar::Equal(_ln) => {
-                panic!("Equal Unsupported")
-            }
-            Cigar::Diff(_ln) => {
-                panic!("Diff Unsupported")
-            }
             _ => {
                 panic!("Unprocessed cigar element {}", x);
             }
@@ -500,7 +495,11 @@ pub struct BamCallingParser<'a, 's, 't> {
 impl BamCallingParser<'_, '_, '_> {
     pub fn new(sequence_layout_design: &SequenceLayout) -> BamCallingParser {
         let rm = ReferenceManager::from_yaml_input(sequence_layout_design, 12, 6);
+        BamCallingParser::from_reference_manager(sequence_layout_design, rm)
+    }
 
+    /// create a parser over an already loaded set of references, such as one from a reference index
+    pub fn from_reference_manager<'a, 's, 't>(sequence_layout_design: &SequenceLayout, rm: ReferenceManager<'a, 's, 't>) -> BamCallingParser<'a, 's, 't> {
         let mut ordered_target_ranges = HashMap::new();
         let target_positions = sequence_layout_design.references.iter().map(|(name, reference)| {
             let mut ordered_targets: Vec<(TargetRange, String)> = Vec::new();
@@ -531,17 +530,25 @@ impl BamCallingParser<'_, '_, '_> {
         bam_entry.aux_iter().for_each(|c| {
             match c {
                 Ok((aux_tag, aux_enum)) => {
+                    // numeric tags are typed in the BAM, so compare everything as its string value
+                    let value = match aux_enum {
+                        Aux::String(x) => Some(x.to_string()),
+                        Aux::I8(x) => Some(x.to_string()),
+                        Aux::U8(x) => Some(x.to_string()),
+                        Aux::I16(x) => Some(x.to_string()),
+                        Aux::U16(x) => Some(x.to_string()),
+                        Aux::I32(x) => Some(x.to_string()),
+                        Aux::U32(x) => Some(x.to_string()),
+                        Aux::Float(x) => Some(x.to_string()),
+                        Aux::Double(x) => Some(x.to_string()),
+                        _ => None,
+                    };
                     // match the aux tag against our known list of tags
-                    match aux_enum {
-                        Aux::String(x) => {
-                            match ExtractorTags::extract_matching_tag(&[aux_tag[0],aux_tag[1]],&x.to_string(),reference, &self.sequence_layout) {
-                                None => {}
-                                Some(x) => {
-                                    extractors.push(x);
-                                }
-                            }
+                    match value.and_then(|value| ExtractorTags::extract_matching_tag(&[aux_tag[0],aux_tag[1]],&value,reference, &self.sequence_layout)) {
+                        None => {}
+                        Some(x) => {
+                            extractors.push(x);
                         }
-                        _ => {}
                     }
 
                 }
@@ -1,5 +1,5 @@
-use crate::alignment::fasta_bit_encoding::{FASTA_N, FASTA_UNSET, FastaBase};
-use crate::consensus::consensus_builders::write_consensus_reads;
+use crate::alignment::fasta_bit_encoding::{FASTA_UNSET, FastaBase};
+use crate::consensus::consensus_builders::{write_consensus_reads, RealignmentOptions};
 use crate::extractor::{extract_tag_sequences, extract_tagged_sequences, recover_align_sequences, SoftClipResolution, stretch_sequence_to_alignment};
 use crate::read_strategies::read_disk_sorter::SortingReadSetContainer;
 use crate::read_strategies::sequence_layout::{ReferenceRecord, SequenceLayout, UMIConfiguration, UMISortType};
@@ -9,18 +9,22 @@ use crate::umis::known_list::KnownList;
 use crate::InstanceLivedTempDir;
 use indicatif::ProgressBar;
 
-use noodles_bam as bam;
-use noodles_bam::{bai, Record};
+use noodles_fasta as fasta;
+use noodles_sam::alignment::RecordBuf;
+use noodles_sam::alignment::record::cigar::Op;
 use noodles_sam::Header;
+use noodles_util::alignment;
 
-use shardio::{Range, ShardReader, ShardWriter};
+use shardio::{Range, ShardReader, ShardSender, ShardWriter};
 use std::cmp::{min, Ordering};
 use std::collections::{HashMap};
 use std::path::PathBuf;
+use std::sync::Mutex;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
-use noodles_sam::alignment::record::QualityScores;
 use petgraph::visit::Walker;
 use crate::alignment::alignment_matrix::{AlignmentResult, AlignmentTag};
+use crate::alignment::scoring_functions::PHRED_OFFSET;
 use crate::alignment_manager::BamFileAlignmentWriter;
 use crate::umis::degenerate_tags::DegenerateBuffer;
 
@@ -28,10 +32,16 @@ pub fn collapse(
     final_output: &String,
     temp_directory: &mut InstanceLivedTempDir,
     read_structure: &SequenceLayout,
+    rm: &ReferenceManager,
     bam_file: &String,
+    threads: &usize,
+    realignment: &RealignmentOptions,
 ) {
-    // load up the reference files
-    let rm = ReferenceManager::from_yaml_input(read_structure, 8, 4);
+    // one thread budget covers sorting, tag correction, and consensus building
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(*threads)
+        .build()
+        .expect("Unable to create the collapse thread pool");
 
     // validate that each reference has the specified capture groups
     let validated_references = rm
@@ -51,70 +61,75 @@ pub fn collapse(
 
     assert!(validated_references, "The reference sequences do not match the capture groups specified in the read structure file.");
 
-    let mut known_level_lookups = get_known_level_lookups(read_structure);
-
-    let mut writer = BamFileAlignmentWriter::new(&PathBuf::from(final_output), &rm);
-
-    let _levels = 0;
-    let mut read_count = 0;
-
-    // for each reference, we fetch aligned reads, pull the sorting tags, and output the collapsed reads to a BAM file
-    rm.references.iter().for_each(|(_id, reference)| {
-        let ref_name = String::from_utf8(reference.name.clone()).unwrap();
-        info!("processing reads from input BAM file: {}", bam_file);
-
-        let sorted_reads_option=
-            sort_reads_from_bam_file(bam_file, &ref_name, &rm, read_structure, temp_directory);
-        read_count = sorted_reads_option.read_stats.passing_reads();
-
-        let mut levels = 0;
-
-        match sorted_reads_option.bam {
-            None => {
-                warn!("No valid reads found for reference {}", ref_name);
-            }
-            Some(mut sorted_reads) => {
-
-                read_structure
-                    .get_sorted_umi_configurations(&ref_name)
-                    .iter()
-                    .for_each(|tag| {
-
-                        match tag.sort_type {
-                            UMISortType::KnownTag => {
-                                let ret = sort_known_level(
-                                    temp_directory,
-                                    &sorted_reads,
-                                    &tag,
-                                    &read_count,
-                                    &mut known_level_lookups,
-                                );
-                                sorted_reads = ret.1;
-                                read_count = ret.0;
-                            }
-
-                            UMISortType::DegenerateTag => {
-                                let ret = sort_degenerate_level(
-                                    temp_directory,
-                                    &sorted_reads,
-                                    &tag,
-                                    &levels,
-                                    &read_count,
-                                );
-                                sorted_reads = ret.1;
-                                read_count = ret.0;
-                            }
-                        }
-
-                        levels += 1;
-                    });
+    // the lookups are shared by every reference; each caches its corrections behind its own lock
+    let known_level_lookups = get_known_level_lookups(read_structure);
+
+    let writer = Mutex::new(BamFileAlignmentWriter::new(&PathBuf::from(final_output), rm, false, None));
+
+    // one pass over the input sorts the aligned reads for every reference into a single shard file
+    info!("processing reads from input alignment file: {}", bam_file);
+    let sorted_reads = sort_reads_from_bam_file(bam_file, rm, read_structure, temp_directory);
+
+    // each reference is sorted by its tags and collapsed in parallel, with its own temporary files, and the
+    // consensus reads all go to the same output BAM file
+    let temp_path = temp_directory.path().to_path_buf();
+    pool.install(|| sorted_reads.read_stats.par_iter().for_each(|(ref_name, read_stats)| {
+        let mut read_count = read_stats.passing_reads();
+
+        match (&sorted_reads.bam, read_count) {
+            (Some(all_sorted_reads), count) if count > 0 => {
+                let mut reference_temp_directory = InstanceLivedTempDir::new_in(&temp_path)
+                    .expect("Unable to create a temporary directory for a reference");
+
+                // the first level reads this reference's block of the shared file; later levels read their own
+                let reference_range = SortingReadSetContainer::reference_range(ref_name);
+                let mut level_reads: Option<ShardReader<SortingReadSetContainer>> = None;
+                let mut levels = 0;
+
+                for tag in read_structure.get_sorted_umi_configurations(ref_name).iter() {
+                    let (reader, range) = match &level_reads {
+                        None => (all_sorted_reads, reference_range.clone()),
+                        Some(reads) => (reads, Range::all()),
+                    };
+
+                    let ret = match tag.sort_type {
+                        UMISortType::KnownTag => sort_known_level(
+                            &mut reference_temp_directory,
+                            reader,
+                            &range,
+                            &tag,
+                            &read_count,
+                            &known_level_lookups,
+                        ),
+                        UMISortType::DegenerateTag => sort_degenerate_level(
+                            &mut reference_temp_directory,
+                            reader,
+                            &range,
+                            &tag,
+                            &levels,
+                            &read_count,
+                        ),
+                    };
+                    level_reads = Some(ret.1);
+                    read_count = ret.0;
+
+                    levels += 1;
+                }
 
                 info!("writing consensus reads for reference {}", ref_name);
+                let (reader, range) = match &level_reads {
+                    None => (all_sorted_reads, reference_range),
+                    Some(reads) => (reads, Range::all()),
+                };
                 // collapse the final reads down to a single sequence and write everything to the disk
-                write_consensus_reads(&sorted_reads, &mut writer, levels, &rm, &40);
+                write_consensus_reads(reader, &range, &writer, levels, &rm, read_structure, &40, realignment);
+            }
+            _ => {
+                warn!("No valid reads found for reference {}", ref_name);
             }
         }
-    });
+    }));
+    writer.into_inner().unwrap().finish().expect("Unable to finish the output bam file");
 }
 
 #[allow(dead_code)]
@@ -252,7 +267,8 @@ impl AlignmentFilter for AlignmentCheck {
 
 /// We want to be extra confident in the alignments around our 'tags'.
 /// This filters out reads where we have mismatches and gaps around the
-/// degenerate sequences we recover
+/// degenerate sequences we recover. Any IUPAC degenerate reference base (N, or a constrained
+/// base like the V in a VN UMI) is part of a tag
 pub struct FlankingDegenerateBaseFilter {
     min_flanking_indentity: f64,
     flanking_window_size: usize,
@@ -275,7 +291,7 @@ impl AlignmentFilter for FlankingDegenerateBaseFilter {
                     ret = false;
                 }
             }
-            else if !reference_base.identity(&FASTA_UNSET) && !reference_base.identity(&FASTA_N){
+            else if !reference_base.identity(&FASTA_UNSET) && !reference_base.is_degenerate() {
                 count_down_check -= 1;
                 if read_base.identity(reference_base) {
                     pushed_binary_comp.push(1)
@@ -284,7 +300,7 @@ impl AlignmentFilter for FlankingDegenerateBaseFilter {
                 }
             }
                 // lookback case for start of Ns
-            else if reference_base.identity(&FASTA_N) && pushed_binary_comp.len() > 0 {
+            else if reference_base.is_degenerate() && pushed_binary_comp.len() > 0 {
                 let lookback_length = min(pushed_binary_comp.len(),self.flanking_window_size);
                 let sum: u32 = pushed_binary_comp[pushed_binary_comp.len() - lookback_length..pushed_binary_comp.len()].iter().sum();
                 let matching_prop = sum as f64 / lookback_length as f64;
@@ -293,7 +309,7 @@ impl AlignmentFilter for FlankingDegenerateBaseFilter {
                     ret = false;
                 }
             }
-            else if reference_base.identity(&FASTA_N) && pushed_binary_comp.len() == 0 {
+            else if reference_base.is_degenerate() && pushed_binary_comp.len() == 0 {
                 count_down_check = self.flanking_window_size;
             }
         });
@@ -308,6 +324,7 @@ struct BamReadFiltering {
     total_reads: usize,
     unmapped_flag_reads: usize,
     secondary_flag_reads: usize,
+    unknown_reference_reads: usize,
     failed_alignment_filters: usize,
     failed_alignment_creation: usize,
     duplicate_reads: usize,
@@ -316,15 +333,16 @@ struct BamReadFiltering {
 
 impl BamReadFiltering {
     pub fn passing_reads(&self) -> usize {
-        self.total_reads - self.unmapped_flag_reads - self.secondary_flag_reads - self.failed_alignment_filters - self.duplicate_reads - self.invalid_tags
+        self.total_reads - self.unmapped_flag_reads - self.secondary_flag_reads - self.unknown_reference_reads - self.failed_alignment_filters - self.failed_alignment_creation - self.duplicate_reads - self.invalid_tags
     }
 
     pub fn results(&self) {
         info!(
-            "Bam file processed, Total reads: {}, Unmapped reads: {}, Secondary reads: {},  Failed read construction: {}, Failed alignment filters: {}, Duplicate reads: {}, Invalid_tags: {}, Passing reads: {}",
+            "Bam file processed, Total reads: {}, Unmapped reads: {}, Secondary or supplementary reads: {}, Reads on other references: {}, Failed read construction: {}, Failed alignment filters: {}, Duplicate reads: {}, Invalid_tags: {}, Passing reads: {}",
             self.total_reads,
             self.unmapped_flag_reads,
             self.secondary_flag_reads,
+            self.unknown_reference_reads,
             self.failed_alignment_creation,
             self.failed_alignment_filters,
             self.duplicate_reads,
@@ -334,138 +352,160 @@ impl BamReadFiltering {
     }
 }
 
+/// the reads that passed our filters, in one shard file sorted by reference name, with the filtering statistics
+/// for each reference. Use SortingReadSetContainer::reference_range to read back a single reference
 pub struct SortedReadsFromBam {
     pub bam: Option<ShardReader<SortingReadSetContainer>>,
-    pub read_stats: BamReadFiltering,
+    pub read_stats: HashMap<String, BamReadFiltering>,
 }
 
+/// a FASTA repository of our reference sequences, which we need to decode CRAM records
+fn reference_repository(reference_manager: &ReferenceManager) -> fasta::Repository {
+    fasta::Repository::new(reference_manager.references.values().map(|reference| {
+        fasta::Record::new(
+            fasta::record::Definition::new(reference.name.clone(), None),
+            fasta::record::Sequence::from(reference.sequence_u8.clone()),
+        )
+    }).collect::<Vec<fasta::Record>>())
+}
+
+/// Stream every record in an alignment file once, sending the reads that pass our filters into a single shard file
+/// sorted by the reference they aligned to. The input can be SAM, BAM, or CRAM (decoded against our reference sequences),
+/// in any order and without an index, so the output of other aligners can be collapsed directly.
+///
+/// # Returns
+///     * the sorted reads, and the filtering statistics for each of our references by reference name
 pub fn sort_reads_from_bam_file(
     bam_file: &String,
-    reference_name: &String,
     reference_manager: &ReferenceManager,
     read_structure: &SequenceLayout,
     temp_directory: &mut InstanceLivedTempDir,
 ) -> SortedReadsFromBam {
 
-    let aligned_temp = temp_directory.temp_file("bam.reads.sorted.sharded");
-
-    let mut reader = bam::io::reader::Builder::default()
+    let mut reader = alignment::io::reader::Builder::default()
+        .set_reference_sequence_repository(reference_repository(reference_manager))
         .build_from_path(bam_file)
-        .unwrap();
-    let mut bai_file = bam_file.clone();
-    bai_file.push_str(".bai");
-
-    let mut read_stats = BamReadFiltering::default();
+        .expect("Unable to open the input alignment file");
+    let header = reader.read_header().expect("Unable to read the header of the input alignment file");
 
     let filters : Vec<&dyn AlignmentFilter> = vec![&FlankingDegenerateBaseFilter{ min_flanking_indentity: 0.80, flanking_window_size: 10 }, &AlignmentCheck{ min_aligned_bases: 100, min_aligned_identical_proportion: 0.8 }];
 
-    let index = bai::read(bai_file).expect("Unable to open BAM BAI file");
-    let header = reader.read_header().unwrap();
-    {
-        let mut sharded_output: ShardWriter<SortingReadSetContainer> =
-            ShardWriter::new(&aligned_temp, 32, 256, 1 << 16).unwrap();
-        let mut sender = sharded_output.get_sender();
-
-        let reference_sequence_id = reference_manager
-            .reference_name_to_ref
-            .get(reference_name.as_bytes())
-            .unwrap();
-
-        let reference_sequence = reference_manager
-            .references
-            .get(reference_sequence_id)
-            .unwrap()
-            .sequence_u8
-            .clone();
-
-        let reference_config = read_structure.references.get(reference_name).unwrap();
+    // one sorted shard file for every reference; containers sort on their reference name first
+    let aligned_temp = temp_directory.temp_file("bam.reads.sorted.sharded");
+    let mut sharded_output: ShardWriter<SortingReadSetContainer> =
+        ShardWriter::new(&aligned_temp, 32, 256, 1 << 16).unwrap();
+    let mut sender: ShardSender<SortingReadSetContainer> = sharded_output.get_sender();
+    let mut read_stats: HashMap<usize, BamReadFiltering> = reference_manager.references.keys()
+        .map(|reference_id| (*reference_id, BamReadFiltering::default()))
+        .collect();
+
+    // reads that don't belong to any of our references: unmapped reads, and reads aligned to other sequences
+    let mut other_read_stats = BamReadFiltering::default();
+    let mut total_reads: usize = 0;
+
+    for result in reader.records(&header) {
+        total_reads += 1;
+        if total_reads % 1000000 == 0 {
+            info!("Processed {} reads from the input alignment file", total_reads);
+        }
 
-        let region = &reference_name.parse().expect("Unable to parse chromosome");
+        let record = result.expect("Unable to read a record from the input alignment file");
+        let record = RecordBuf::try_from_alignment_record(&header, record.as_ref()).expect("Unable to decode a record from the input alignment file");
 
-        let records = reader
-            .query(&header, &index, &region)
-            .map(Box::new)
-            .expect("Unable to parse out region information");
+        let reference_sequence_id = record.reference_sequence_id()
+            .and_then(|id| header.reference_sequences().get_index(id))
+            .and_then(|(name, _map)| reference_manager.reference_name_to_ref.get(&name.to_vec()));
 
-        for result in records {
-            read_stats.total_reads += 1;
-            if read_stats.total_reads % 1000000 == 0 {
-                read_stats.results();
+        let (reference_sequence_id, stats) = match (reference_sequence_id, record.flags().is_unmapped()) {
+            (Some(id), false) => (id, read_stats.get_mut(id).unwrap()),
+            (_, unmapped) => {
+                other_read_stats.total_reads += 1;
+                if unmapped {
+                    other_read_stats.unmapped_flag_reads += 1;
+                } else {
+                    other_read_stats.unknown_reference_reads += 1;
+                }
+                continue;
             }
+        };
 
-            let record = result.unwrap();
-
-            if !record.flags().is_secondary() && !record.flags().is_unmapped() {
-                let read = create_sorted_read_container(reference_name, &reference_manager, &mut read_stats, &reference_sequence_id, &reference_sequence, reference_config, &record);
+        stats.total_reads += 1;
+        if record.flags().is_secondary() || record.flags().is_supplementary() {
+            stats.secondary_flag_reads += 1;
+            continue;
+        }
 
+        let reference = reference_manager.references.get(reference_sequence_id).unwrap();
+        let reference_name = String::from_utf8(reference.name.clone()).unwrap();
+        let reference_config = read_structure.references.get(&reference_name).unwrap();
 
-                match read {
-                    Some(x) => {
-                        let survives_filtering = filters.iter().map(|t| t.keep(&x)).filter(|b| !*b).count() == 0;
-                        if survives_filtering {
-                            sender.send(x).unwrap();
-                        } else {
-                            read_stats.failed_alignment_filters += 1;
-                        }
-                    },
-                    None => {
-                        read_stats.failed_alignment_creation += 1;
-                    }
-                }
-            } else {
-                if record.flags().is_secondary() {
-                    read_stats.secondary_flag_reads += 1;
-                }
-                if record.flags().is_unmapped() {
-                    read_stats.unmapped_flag_reads += 1;
+        match create_sorted_read_container(&reference_name, &reference.sequence_u8, reference_config, &record) {
+            Some(x) => {
+                let survives_filtering = filters.iter().map(|t| t.keep(&x)).filter(|b| !*b).count() == 0;
+                if survives_filtering {
+                    sender.send(x).unwrap();
+                } else {
+                    stats.failed_alignment_filters += 1;
                 }
+            },
+            None => {
+                stats.failed_alignment_creation += 1;
             }
         }
-        sender.finished().unwrap();
-        sharded_output.finish().unwrap();
-
     }
-    read_stats.results();
-    if read_stats.passing_reads() > 0 {
-        SortedReadsFromBam{
-            bam: Some(ShardReader::open(aligned_temp).unwrap()),
-            read_stats,
-        }
+
+    sender.finished().unwrap();
+    sharded_output.finish().unwrap();
+
+    info!("Processed {} reads from the input alignment file", total_reads);
+    other_read_stats.results();
+
+    let read_stats: HashMap<String, BamReadFiltering> = read_stats.into_iter().map(|(reference_id, read_stats)| {
+        let reference_name = String::from_utf8(reference_manager.references.get(&reference_id).unwrap().name.clone()).unwrap();
+        info!("Reads for reference {}:", reference_name);
+        read_stats.results();
+        (reference_name, read_stats)
+    }).collect();
+
+    let bam = if read_stats.values().any(|stats| stats.passing_reads() > 0) {
+        Some(ShardReader::open(&aligned_temp).unwrap())
     } else {
-        SortedReadsFromBam{
-            bam: None,
-            read_stats,
-        }
-    }
+        None
+    };
+    SortedReadsFromBam { bam, read_stats }
 }
 
+/// the quality BAM stores for each base of a read that has no qualities
+const MISSING_QUALITY: u8 = 0xFF;
+
 /// create a read container from the read and reference sequence,
 /// returning Some(read) if successful, or None if we couldn't
 /// extract the tag
 fn create_sorted_read_container(reference_name: &String,
-                                reference_manager: &&ReferenceManager,
-                                _read_stats: &mut BamReadFiltering,
-                                reference_sequence_id: &&usize,
                                 reference_sequence: &Vec<u8>,
-                                reference_config: &ReferenceRecord, record: &Record) -> Option<SortingReadSetContainer> {
-
-    let seq: Vec<u8> = record.sequence().iter().collect();
-    let start_pos = record.alignment_start().unwrap().unwrap().get();
-    let cigar = record.cigar();
-    let read_name: bam::record::Name<'_> = record.name().unwrap();
-    let read_qual = record.quality_scores().iter().collect();
+                                reference_config: &ReferenceRecord, record: &RecordBuf) -> Option<SortingReadSetContainer> {
+
+    let seq: Vec<u8> = record.sequence().as_ref().to_vec();
+    let start_pos = record.alignment_start().unwrap().get();
+    let cigar: Vec<Op> = record.cigar().as_ref().to_vec();
+    // a record without a name (a '*' QNAME) can't be collapsed, so it's counted as a failed read
+    let read_name = record.name()?;
+    // SAM qualities are raw Phred scores; our alignments carry them Phred+33 encoded, as read from FASTQ. A BAM record
+    // without qualities has 0xFF for each of them (or none at all), which we read as quality zero ('!')
+    let qualities = record.quality_scores().as_ref();
+    let read_qual = if qualities.len() == seq.len() {
+        qualities.iter().map(|q| if *q == MISSING_QUALITY { PHRED_OFFSET } else { q.saturating_add(PHRED_OFFSET) }).collect()
+    } else {
+        vec![PHRED_OFFSET; seq.len()]
+    };
     let ref_slice = reference_sequence.as_slice();
 
     let aligned_read =
-        recover_align_sequences(&seq, start_pos, &cigar.iter().map(|x| x.unwrap()).collect(), &SoftClipResolution::Realign, ref_slice);
+        recover_align_sequences(&seq, start_pos, &cigar, &SoftClipResolution::Realign, ref_slice);
 
     let stretched_alignment = stretch_sequence_to_alignment(
         &aligned_read.aligned_ref,
-        &reference_manager
-            .references
-            .get(reference_sequence_id)
-            .unwrap()
-            .sequence_u8,
+        reference_sequence,
     );
 
     let extracted_tags =
@@ -484,11 +524,11 @@ fn create_sorted_read_container(reference_name: &String,
                 read_quals: Some(read_qual),
                 cigar_string: cigar
                     .iter()
-                    .map(|op| AlignmentTag::from(op.unwrap()))
+                    .map(|op| AlignmentTag::from(*op))
                     .collect(),
                 path: vec![],
                 score: 0.0,
-                reference_start: start_pos,
+                reference_start: start_pos - 1, // our alignments are zero-based
                 read_start: 0,
                 reference_aligned: FastaBase::from_vec_u8_default_ns(
                     &aligned_read.aligned_ref,
@@ -552,6 +592,7 @@ fn get_known_level_lookups(read_structure: &SequenceLayout) -> HashMap<String, K
 pub fn sort_degenerate_level(
     temp_directory: &mut InstanceLivedTempDir,
     reader: &ShardReader<SortingReadSetContainer>,
+    range: &Range<SortingReadSetContainer>,
     tag: &UMIConfiguration,
     iteration: &usize,
     read_count: &usize,
@@ -583,7 +624,7 @@ pub fn sort_degenerate_level(
 
     let mut current_sorting_bin: Option<DegenerateBuffer> = None;
 
-    reader.iter_range(&Range::all()).unwrap().for_each(|current_read| {
+    reader.iter_range(range).unwrap().for_each(|current_read| {
         all_read_count += 1;
         //if all_read_count % 10000 == 0 { // TODO for now to figure out what's going on
             bar.as_mut().map(|b| b.set_position(all_read_count as u64));
@@ -642,9 +683,10 @@ pub fn sort_degenerate_level(
 pub fn sort_known_level(
     temp_directory: &mut InstanceLivedTempDir,
     reader: &ShardReader<SortingReadSetContainer>,
+    range: &Range<SortingReadSetContainer>,
     tag: &UMIConfiguration,
     read_count: &usize,
-    known_lookup_obj: &mut HashMap<String, KnownList>,
+    known_lookup_obj: &HashMap<String, KnownList>,
 ) -> (usize, ShardReader<SortingReadSetContainer>) {
     info!("Sorting known level {}", tag.symbol);
 
@@ -653,7 +695,7 @@ pub fn sort_known_level(
         tag.symbol
     );
     let known_lookup = known_lookup_obj
-        .get_mut(&tag.file.as_ref().unwrap().clone())
+        .get(&tag.file.as_ref().unwrap().clone())
         .expect(
             format!(
                 "Unable to find pre-cached lookup table {}",
@@ -681,7 +723,7 @@ pub fn sort_known_level(
             ShardWriter::new(&aligned_temp, 32, 256, 1 << 16).unwrap();
         let mut sender = sharded_output.get_sender();
 
-        reader.iter_range(&Range::all()).unwrap().for_each(|x| {
+        reader.iter_range(range).unwrap().for_each(|x| {
             processed_reads += 1;
             //if processed_reads % 10000 == 0 {
                 bar.as_mut().map(|b| b.set_position(processed_reads as u64));
@@ -746,7 +788,7 @@ mod tests {
     use std::collections::VecDeque;
     use super::*;
     use crate::alignment::alignment_matrix::AlignmentResult;
-    use crate::alignment::fasta_bit_encoding::{FASTA_A, FASTA_T};
+    use crate::alignment::fasta_bit_encoding::{FASTA_A, FASTA_N, FASTA_T, FASTA_V};
     use crate::utils::read_utils::fake_reads;
 
     pub fn consensus(input: &Vec<Vec<u8>>) -> Vec<u8> {
@@ -945,7 +987,26 @@ mod tests {
 
         assert!(alignment_check.keep(&fake_read_alignment));
 
+        // a VN UMI: the constrained V is part of the tag, not a mismatched flanking base
+        let fake_read_alignment = SortingReadSetContainer{
+            ordered_sorting_keys: vec![],
+            ordered_unsorted_keys: Default::default(),
+            aligned_read: AlignmentResult {
+                reference_name: "".to_string(),
+                read_name: "".to_string(),
+                reference_aligned: vec![FASTA_A,FASTA_A,FASTA_A,FASTA_A,  FASTA_V,FASTA_N,FASTA_V,FASTA_N,  FASTA_A,FASTA_A,FASTA_A,FASTA_A,FASTA_A],
+                read_aligned:      vec![FASTA_A,FASTA_A,FASTA_A,FASTA_A,  FASTA_A,FASTA_T,FASTA_A,FASTA_A,  FASTA_A,FASTA_A,FASTA_A,FASTA_A,FASTA_A],
+                read_quals: None,
+                cigar_string: vec![],
+                path: vec![],
+                score: 0.0,
+                reference_start: 0,
+                read_start: 0,
+                bounding_box: None,
+            },
+        };
 
+        assert!(alignment_check.keep(&fake_read_alignment));
     }
     #[test]
     fn test_consensus() {
@@ -1068,4 +1129,56 @@ mod tests {
             );
         });
     }
+
+    #[test]
+    fn test_sort_reads_from_unsorted_sam() {
+        let read_structure = SequenceLayout::from_yaml(&String::from("test_data/test_layout_fasta.yaml"));
+        let rm = ReferenceManager::from_yaml_input(&read_structure, 8, 4);
+        let mut temp_directory = InstanceLivedTempDir::new().unwrap();
+
+        let mut references = rm.references.values().map(|r| (String::from_utf8(r.name.clone()).unwrap(), String::from_utf8(r.sequence_u8.clone()).unwrap())).collect::<Vec<(String, String)>>();
+        references.sort();
+        let (first_name, first_sequence) = references[0].clone();
+        let (second_name, second_sequence) = references[1].clone();
+
+        // an unsorted, unindexed SAM file: a reference we don't know, and records out of coordinate order
+        let mut sam = String::from("@HD\tVN:1.6\n@SQ\tSN:other\tLN:1000\n");
+        references.iter().for_each(|(name, sequence)| sam.push_str(&format!("@SQ\tSN:{}\tLN:{}\n", name, sequence.len())));
+        sam.push_str(&format!("read1\t0\t{}\t1\t60\t{}M\t*\t0\t0\t{}\t*\n", second_name, second_sequence.len(), second_sequence));
+        sam.push_str(&format!("read2\t0\t{}\t1\t60\t{}M\t*\t0\t0\t{}\t*\n", first_name, first_sequence.len(), first_sequence));
+        sam.push_str(&format!("read3\t256\t{}\t1\t0\t{}M\t*\t0\t0\t{}\t*\n", first_name, first_sequence.len(), first_sequence));
+        sam.push_str(&format!("read4\t0\tother\t1\t60\t{}M\t*\t0\t0\t{}\t*\n", first_sequence.len(), first_sequence));
+        sam.push_str("read5\t4\t*\t0\t0\t*\t*\t0\t0\tACGTACGT\t*\n");
+        // a record with no name can't be collapsed
+        sam.push_str(&format!("*\t0\t{}\t1\t60\t{}M\t*\t0\t0\t{}\t*\n", first_name, first_sequence.len(), first_sequence));
+
+        let sam_file = temp_directory.temp_file("unsorted.sam");
+        std::fs::write(&sam_file, sam).unwrap();
+
+        let sorted = sort_reads_from_bam_file(&sam_file.to_str().unwrap().to_string(), &rm, &read_structure, &mut temp_directory);
+        assert_eq!(sorted.read_stats.len(), references.len());
+
+        let first = sorted.read_stats.get(&first_name).unwrap();
+        assert_eq!(first.total_reads, 3);
+        assert_eq!(first.secondary_flag_reads, 1);
+        assert_eq!(first.failed_alignment_creation, 1);
+        assert_eq!(first.passing_reads(), 1);
+
+        // every reference shares one shard file, and the range for a reference returns only its reads
+        let bam = sorted.bam.as_ref().unwrap();
+        assert_eq!(bam.iter_range(&Range::all()).unwrap().count(), 2);
+        let reads = bam.iter_range(&SortingReadSetContainer::reference_range(&first_name)).unwrap().map(|r| r.unwrap()).collect::<Vec<SortingReadSetContainer>>();
+        assert_eq!(reads.len(), 1);
+        assert_eq!(reads[0].aligned_read.read_name, "read2");
+        assert_eq!(reads[0].aligned_read.reference_start, 0);
+        // the records have no qualities, which we read as quality zero
+        assert_eq!(reads[0].aligned_read.read_quals, Some(vec![b'!'; first_sequence.len()]));
+
+        let reads = bam.iter_range(&SortingReadSetContainer::reference_range(&second_name)).unwrap().map(|r| r.unwrap()).collect::<Vec<SortingReadSetContainer>>();
+        assert_eq!(reads.len(), 1);
+        assert_eq!(reads[0].aligned_read.read_name, "read1");
+
+        assert_eq!(sorted.read_stats.get(&references[2].0).unwrap().passing_reads(), 0);
+        assert_eq!(bam.iter_range(&SortingReadSetContainer::reference_range(&references[2].0)).unwrap().count(), 0);
+    }
 }
@@ -1,14 +1,18 @@
 extern crate spoa;
 
+use crate::alignment::aligner::{Aligner, AnchoredAligner};
 use crate::alignment::alignment_matrix::{
-    create_scoring_record_3d, Alignment, AlignmentTag, AlignmentType as LocalAlignmentType,
+    create_scoring_record_3d, inversion_alignment, Alignment, AlignmentTag, AlignmentType as LocalAlignmentType,
 };
 use crate::alignment::fasta_bit_encoding::{FastaBase, FASTA_N, FASTA_UNSET};
-use crate::alignment::scoring_functions::AffineScoring;
+use crate::alignment::scoring_functions::{AffineScoring, InversionScoring, MAX_PHRED_SCORE, PHRED_OFFSET};
+use crate::alignment_functions::align_two_strings_passed_matrix;
 use crate::alignment_manager::{align_two_strings, simplify_cigar_string, OutputAlignmentWriter};
 use crate::read_strategies::read_disk_sorter::SortingReadSetContainer;
+use crate::read_strategies::sequence_layout::SequenceLayout;
 use crate::reference::fasta_reference::ReferenceManager;
 use counter::Counter;
+use noodles_sam::alignment::record_buf::data::field::Value;
 use ndarray::Ix3;
 use rust_htslib::bam::record::CigarString;
 use shardio::{Range, ShardReader};
@@ -21,30 +25,40 @@ use std::sync::{Arc, Mutex};
 use num_traits::{Pow, ToPrimitive};
 
 
-pub fn write_consensus_reads(
+/// How we realign consensus reads to their reference
+#[derive(Clone, Debug, Default)]
+pub struct RealignmentOptions {
+    /// use the inversion-aware aligner; inverted segments are written as supplementary records
+    pub find_inversions: bool,
+    /// anchor the alignment on exact matches to the reference, and only align the stretches between them
+    pub fast_reference_lookup: bool,
+    /// the largest indel we look for, as the alignment band width; zero aligns the full matrix
+    pub max_deletion: usize,
+}
+
+/// collapse each group of reads with matching sorting keys, within the range of the reader, into a consensus read,
+/// and write it out. The writer is shared, so consensus reads from several references can go to the same output.
+/// Groups are collapsed in parallel on the current rayon thread pool
+pub fn write_consensus_reads<W: OutputAlignmentWriter>(
     reader: &ShardReader<SortingReadSetContainer>,
-    writer: &mut dyn OutputAlignmentWriter,
+    range: &Range<SortingReadSetContainer>,
+    writer: &Mutex<W>,
     levels: usize,
     reference_manager: &ReferenceManager,
+    read_structure: &SequenceLayout,
     maximum_reads_before_downsampling: &usize,
+    realignment: &RealignmentOptions,
 ) {
     let mut last_read: Option<SortingReadSetContainer> = None;
     let mut buffered_reads = VecDeque::new();
 
     let processed_reads = Arc::new(Mutex::new(0));
 
-    let score = AffineScoring::default_dna();
-
-    let arc_output = Arc::new(Mutex::new(writer));
-
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(8)
-        .build()
-        .unwrap();
+    let score = AffineScoring::from_layout(&AffineScoring::default_dna(), read_structure);
 
-    pool.scope(|s| {
+    rayon::scope(|s| {
         let _handled_reads = 0;
-        reader.iter_range(&Range::all()).unwrap().for_each(|x| {
+        reader.iter_range(range).unwrap().for_each(|x| {
             let x = x.unwrap();
             assert_eq!(x.ordered_sorting_keys.len(), levels);
             if !(last_read.is_some() && x.cmp(last_read.as_ref().unwrap()) == Ordering::Equal)
@@ -67,13 +81,15 @@ pub fn write_consensus_reads(
                         reference_manager,
                         maximum_reads_before_downsampling,
                         &my_buffered_reads,
-                        &AffineScoring::default_dna(),
+                        read_structure,
+                        &score,
                         &mut alignment_mat,
+                        realignment,
                     );
 
-                    let arc_writer = arc_output.clone();
-                    let mut arc_writer = arc_writer.lock().expect("Unable to access multi-threaded writer");
-                    arc_writer.write_read(&new_read.read, &new_read.added_tags).expect("Unable to write a read to the arc writer (LOC1)");
+                    let (primary, inverted_segments) = new_read.read.aligned_read.split_inversions();
+                    let mut arc_writer = writer.lock().expect("Unable to access multi-threaded writer");
+                    arc_writer.write_split_read(&new_read.read.with_new_alignment(primary), &new_read.added_tags, &inverted_segments, false, None).expect("Unable to write a read to the arc writer (LOC1)");
 
                     let processed_reads = processed_reads.clone();
                     let mut processed_reads = processed_reads.lock().expect("Unable to lock processed read count");
@@ -101,32 +117,36 @@ pub fn write_consensus_reads(
             reference_manager,
             maximum_reads_before_downsampling,
             &buffered_reads,
+            read_structure,
             &score,
             &mut alignment_mat,
+            realignment,
         );
-        let arc_writer = arc_output.clone();
-        let mut arc_writer = arc_writer.lock().expect("Unable to access multi-threaded writer");
-        arc_writer.write_read(&new_read.read, &new_read.added_tags).expect("Unable to write a read to the arc writer (LOC2)");
+        let (primary, inverted_segments) = new_read.read.aligned_read.split_inversions();
+        let mut arc_writer = writer.lock().expect("Unable to access multi-threaded writer");
+        arc_writer.write_split_read(&new_read.read.with_new_alignment(primary), &new_read.added_tags, &inverted_segments, false, None).expect("Unable to write a read to the arc writer (LOC2)");
     }
 }
 
 pub struct SamReadyOutput {
     pub read: SortingReadSetContainer,
-    pub added_tags: HashMap<[u8; 2], String>,
+    pub added_tags: HashMap<[u8; 2], Value>,
 }
 
 fn create_sam_read(
     reference_manager: &ReferenceManager,
     maximum_reads_before_downsampling: &usize,
     buffered_reads: &VecDeque<SortingReadSetContainer>,
+    read_structure: &SequenceLayout,
     my_aff_score: &AffineScoring,
-    _alignment_mat: &mut Alignment<Ix3>,
+    alignment_mat: &mut Alignment<Ix3>,
+    realignment: &RealignmentOptions,
 ) -> SamReadyOutput {
     let mut added_tags = HashMap::new();
-    added_tags.insert([b'r', b'c'], buffered_reads.len().to_string());
+    added_tags.insert([b'r', b'c'], Value::from(buffered_reads.len() as u32));
     added_tags.insert(
         [b'd', b'c'],
-        cmp::min(*maximum_reads_before_downsampling, buffered_reads.len()).to_string(),
+        Value::from(cmp::min(*maximum_reads_before_downsampling, buffered_reads.len()) as u32),
     );
 
     if buffered_reads.len() > 1 {
@@ -160,16 +180,24 @@ fn create_sam_read(
             .read_name
             .clone();
 
-        let mut new_alignment = align_two_strings(
-            &reference_pointer.sequence,
-            &FastaBase::from_vec_u8(&consensus_reads.0),
-            None, // TODO: fix with quality scores
-            my_aff_score,
-            false,
-            &String::from_utf8(reference_pointer.name.clone()).unwrap(),
-            &read_name,
-            None,
-        );
+        let reference_name = String::from_utf8(reference_pointer.name.clone()).unwrap();
+
+        let consensus_read = FastaBase::from_vec_u8(&consensus_reads.0);
+        let scoring = my_aff_score.for_reference(read_structure, &reference_name);
+        let max_indel = if realignment.max_deletion > 0 { realignment.max_deletion } else { consensus_read.len() };
+
+        let mut new_alignment = if realignment.find_inversions {
+            let inversion_scoring = InversionScoring::from_layout(&InversionScoring::default(), read_structure);
+            inversion_alignment(&reference_pointer.sequence, &consensus_read, &reference_name, &read_name, &inversion_scoring, &scoring, &max_indel, false)
+        } else if realignment.fast_reference_lookup {
+            AnchoredAligner {}.align(reference_pointer, &read_name, &consensus_read, None, &scoring, alignment_mat, &max_indel)
+                .unwrap_or_else(|| align_two_strings(&reference_pointer.sequence, &consensus_read, None, &scoring, false, &reference_name, &read_name, None))
+        } else if consensus_read.len() < alignment_mat.scores.shape()[1] {
+            align_two_strings_passed_matrix(&reference_name, &read_name, &reference_pointer.sequence, &consensus_read, None, &scoring, alignment_mat, &max_indel)
+        } else {
+            // the consensus outgrew the preallocated matrix
+            align_two_strings(&reference_pointer.sequence, &consensus_read, None, &scoring, false, &reference_name, &read_name, None)
+        };
         new_alignment.read_quals = Some(gapless_quals);
 
         //println!("New alignment: \n{}\n{}\n{:?}", FastaBase::string(&new_alignment.read_aligned),FastaBase::string(&new_alignment.reference_aligned),simplify_cigar_string(&new_alignment.cigar_string));
@@ -178,17 +206,7 @@ fn create_sam_read(
             .map(|x| x.aligned_read.read_name.clone())
             .collect::<Vec<String>>();
 
-        added_tags.insert([b'a', b'r'], read_names.join(","));
-        added_tags.insert(
-            [b'r', b'm'],
-            get_reference_alignment_rate(
-                &new_alignment.reference_aligned,
-                &new_alignment.read_aligned,
-            )
-                .to_string(),
-        );
-
-        added_tags.insert([b'a', b's'], new_alignment.score.to_string());
+        added_tags.insert([b'a', b'r'], Value::from(read_names.join(",")));
         let new_sorting_read = buffered_reads
             .get(0)
             .unwrap()
@@ -197,16 +215,7 @@ fn create_sam_read(
         SamReadyOutput { read: new_sorting_read, added_tags }
     } else {
         let single_read = buffered_reads.get(0).unwrap().clone();
-        added_tags.insert([b'a', b'r'], single_read.aligned_read.read_name.clone());
-        added_tags.insert(
-            [b'r', b'm'],
-            get_reference_alignment_rate(
-                &single_read.aligned_read.reference_aligned,
-                &single_read.aligned_read.read_aligned,
-            )
-                .to_string(),
-        );
-        added_tags.insert([b'a', b's'], single_read.aligned_read.score.to_string());
+        added_tags.insert([b'a', b'r'], Value::from(single_read.aligned_read.read_name.clone()));
         SamReadyOutput { read: single_read, added_tags }
     }
 }
@@ -367,7 +376,7 @@ pub fn phred_to_error_prob(phred: &u8, floor_zero_at_33: &bool) -> f64 {
         };
 
 
-    assert!(phred >= 33 && phred <= 98, "{}", format!("Unable to format phred {}", phred)); // the upper bound is a bit arbitrary, but 33 + 93 = 126, the highest possible value reported by Illumina
+    assert!(phred >= 33 && phred <= 126, "{}", format!("Unable to format phred {}", phred)); // 33 + 93 = 126, the highest quality character in a FASTQ file
     // TODO: we dont deal with phred + 64 format data -- at some point there will be legacy data that comes through; we should at least document this
     (10.0_f64).pow((phred.to_f64().unwrap() - 32.99999999999999999) / (-10.0)) // 32.9999 to avoid Inf powers
 }
@@ -385,12 +394,8 @@ pub fn prob_to_phred(prob: &f64) -> u8 {
     assert!(ret >= 0.0_f64 && ret <= 256.0_f64, "{}", format!("Unable to format phred {}", ret));
 
     let ret = ret.round().to_u8().unwrap();
-    let ret = if ret > 40 { // cap PHRED at 40; Noodles doesn't like higher TODO: fix this
-        40_u8
-    } else {
-        ret as u8
-    };
-    assert!(ret >= 33_u8 && ret <= 40_u8);
+    let ret = cmp::min(ret, PHRED_OFFSET + MAX_PHRED_SCORE); // the highest quality we can write as a printable character
+    assert!(ret >= 33_u8);
     ret
 }
 
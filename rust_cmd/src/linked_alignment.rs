@@ -9,7 +9,7 @@ use crate::alignment::alignment_matrix::{Alignment, AlignmentCigar, AlignmentRes
 use crate::alignment::alignment_matrix::AlignmentTag::Del;
 use crate::alignment::fasta_bit_encoding::{FASTA_UNSET, FastaBase};
 use crate::alignment::scoring_functions::{AffineScoring, InversionScoring};
-use crate::alignment_functions::simplify_cigar_string;
+use crate::alignment_functions::{align_two_strings_passed_matrix, simplify_cigar_string};
 use crate::reference::fasta_reference::SuffixTableLookup;
 
 #[allow(dead_code)]
@@ -132,6 +132,28 @@ pub struct AlignmentResults {
 
 /// find a series of exact matches between the search string and the reference, and then align the
 /// sequences between those exact matches using an inversion aware aligner
+/// Globally align a stretch the anchors don't cover, in a matrix of its own. For the f64 aligner that's a matrix the size
+/// of the stretch; the integer kernels (`alignment_type` is IntegerAffine or CheckpointedAffine) keep their own buffers
+fn global_stretch_alignment(ref_slice: &[FastaBase],
+                            read_slice: &[FastaBase],
+                            ref_name: &String,
+                            read_name: &String,
+                            my_aff_score: &AffineScoring,
+                            alignment_type: AlignmentType) -> AlignmentResult {
+    match alignment_type {
+        AlignmentType::IntegerAffine | AlignmentType::CheckpointedAffine => {
+            let mut alignment_mat = create_scoring_record_3d(0, 0, alignment_type, false);
+            align_two_strings_passed_matrix(ref_name, read_name, ref_slice, read_slice, None, my_aff_score, &mut alignment_mat, &cmp::max(ref_slice.len(), read_slice.len()))
+        }
+        _ => {
+            let mut alignment_mat = create_scoring_record_3d(ref_slice.len() + 1, read_slice.len() + 1, AlignmentType::Affine, false);
+            perform_affine_alignment(&mut alignment_mat, ref_slice, read_slice, my_aff_score);
+
+            perform_3d_global_traceback(&mut alignment_mat, None, ref_slice, read_slice, ref_name, read_name, None, None) // TODO fix with quality scores
+        }
+    }
+}
+
 ///
 /// # Arguments
 ///
@@ -168,12 +190,14 @@ pub fn align_string_with_anchors(read_name: &String,
                 (x, y, None) if x < 5 && y < 5 && x == y => {
                     AlignmentResult::from_match_segment(&ref_slice, &read_slice, ref_name, read_name, ref_alignment_last_position, read_alignment_last_position, my_aff_score)
                 }
-                (_x, _y, Some(inv_score)) => inversion_alignment(&ref_slice, &read_slice, ref_name, read_name, inv_score, my_aff_score, false),
-                (_x, _y, None) => {
+                (_x, _y, Some(inv_score)) => inversion_alignment(&ref_slice, &read_slice, ref_name, read_name, inv_score, my_aff_score, &cmp::max(ref_slice.len(), read_slice.len()), false),
+                (_x, _y, None) if alignment_mat.alignment_type == AlignmentType::Affine => {
                     perform_affine_alignment(alignment_mat, &ref_slice, &read_slice, my_aff_score);
 
                     perform_3d_global_traceback(alignment_mat, None, &ref_slice, &read_slice, ref_name, read_name, None, None)
                 }
+                // the integer kernels keep their own buffers, so they don't need a matrix with room for the stretch
+                (_x, _y, None) => align_two_strings_passed_matrix(ref_name, read_name, &ref_slice, &read_slice, None, my_aff_score, alignment_mat, &cmp::max(ref_slice.len(), read_slice.len())),
             };
         read_alignment_last_position += read_slice.len();
         ref_alignment_last_position += ref_slice.len();
@@ -203,13 +227,8 @@ pub fn align_string_with_anchors(read_name: &String,
             let ref_slice = slice_for_alignment(&reference, ref_alignment_last_position, reference.len());
             let alignment =
                 match my_inv_score {
-                    Some(x) => inversion_alignment(&ref_slice, &read_slice, ref_name, read_name, x, my_aff_score, false),
-                    None => {
-                        let mut alignment_mat = create_scoring_record_3d(ref_slice.len() + 1, read_slice.len() + 1, AlignmentType::Affine, false);
-                        perform_affine_alignment(&mut alignment_mat, &ref_slice, &read_slice, my_aff_score);
-
-                        perform_3d_global_traceback(&mut alignment_mat, None, &ref_slice, &read_slice, ref_name, read_name, None, None)// TODO fix with quality scores
-                    }
+                    Some(x) => inversion_alignment(&ref_slice, &read_slice, ref_name, read_name, x, my_aff_score, &cmp::max(ref_slice.len(), read_slice.len()), false),
+                    None => global_stretch_alignment(&ref_slice, &read_slice, ref_name, read_name, my_aff_score, alignment_mat.alignment_type),
                 };
             debug!("Pushing {:?}",alignment.cigar_string.clone());
 
@@ -228,14 +247,9 @@ pub fn align_string_with_anchors(read_name: &String,
         let alignment =
             match my_inv_score {
                 Some(x) => {
-                    inversion_alignment(&reference, &search_string, ref_name, read_name, x, my_aff_score, true)
-                }
-                None => {
-                    let mut alignment_mat = create_scoring_record_3d(reference.len() + 1, search_string.len() + 1, AlignmentType::Affine, false);
-                    perform_affine_alignment(&mut alignment_mat, &reference, &search_string, my_aff_score);
-
-                    perform_3d_global_traceback(&mut alignment_mat, None, &reference, &search_string, ref_name, read_name, None, None) // TODO fix with quality scores
+                    inversion_alignment(&reference, &search_string, ref_name, read_name, x, my_aff_score, &cmp::max(reference.len(), search_string.len()), true)
                 }
+                None => global_stretch_alignment(reference, search_string, ref_name, read_name, my_aff_score, alignment_mat.alignment_type),
             };
         alignment_ref.extend(alignment.reference_aligned);
         alignment_read.extend(alignment.read_aligned);
@@ -480,6 +494,7 @@ mod tests {
             gap_open: -15.0,
             gap_extend: -5.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
 
         };
         let reference_lookup = ReferenceManager::find_seeds(&reference, 20);
@@ -516,6 +531,7 @@ mod tests {
             gap_open: -15.0,
             gap_extend: -5.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
 
         };
         let reference_lookup = ReferenceManager::find_seeds(&reference, 20);
@@ -538,6 +554,7 @@ mod tests {
             gap_open: -15.0,
             gap_extend: -5.0,
             final_gap_multiplier: 1.0,
+            quality_weighting: None,
         };
 
         let reference = String::from("CATGGTNNNNNNNNNNNNNNNNNNCGCCGCCGGGATCACTCTCGGCATGGACGAGCTGTACAAGTAACGAAGAGTAACCGTTGCTAGGAGAGACCATATGTCTAGAGAAAGGTACCCTATCCTTTCGAATGGTCCACGCGTAGAAGAAAGTTAGCTCTTGTGCGA").as_bytes().to_owned();
@@ -34,6 +34,7 @@ extern crate phf;
 extern crate rust_htslib;
 extern crate noodles_bam;
 extern crate noodles_util;
+extern crate noodles_fasta;
 extern crate bstr;
 extern crate rust_starcode;
 extern crate libc;
@@ -50,11 +51,14 @@ use clap::Subcommand;
 use nanoid::nanoid;
 
 use pretty_trace::*;
+use crate::alignment::alignment_matrix::GapPlacement;
 use crate::alignment_functions::align_reads;
 use crate::calling::call_events::BamCallingParser;
 use crate::collapse::collapse;
+use crate::consensus::consensus_builders::RealignmentOptions;
 use crate::read_strategies::sequence_layout::SequenceLayout;
 use crate::reference::fasta_reference::ReferenceManager;
+use crate::reference::reference_index::{ReferenceIndex, write_reference_index};
 
 mod linked_alignment;
 pub mod extractor;
@@ -70,6 +74,8 @@ mod alignment {
     pub mod alignment_matrix;
     pub mod scoring_functions;
     pub mod fasta_bit_encoding;
+    pub mod integer_alignment;
+    pub mod aligner;
 }
 
 mod umis {
@@ -107,6 +113,7 @@ mod alignment_functions;
 
 mod reference {
     pub mod fasta_reference;
+    pub mod reference_index;
 }
 
 #[derive(Subcommand, Debug)]
@@ -118,6 +125,14 @@ enum Cmd {
         #[clap(long)]
         read_structure: String,
 
+        // a FASTA file of reference sequences, replacing the read structure's reference_fasta
+        #[clap(long)]
+        reference: Option<String>,
+
+        // a reference index from the index command, used instead of rebuilding the reference lookups
+        #[clap(long)]
+        reference_index: Option<String>,
+
         #[clap(long, default_value = "1")]
         threads: usize,
 
@@ -140,6 +155,14 @@ enum Cmd {
         #[clap(long)]
         read_structure: String,
 
+        // a FASTA file of reference sequences, replacing the read structure's reference_fasta
+        #[clap(long)]
+        reference: Option<String>,
+
+        // a reference index from the index command, used instead of rebuilding the reference lookups
+        #[clap(long)]
+        reference_index: Option<String>,
+
         #[clap(long)]
         output_bam_file: String,
 
@@ -149,6 +172,14 @@ enum Cmd {
         #[clap(long, default_value = "50")]
         min_read_length: usize,
 
+        // the alignment band width; defaults to the read length, which is a full (unbanded) alignment
+        #[clap(long)]
+        max_indel: Option<usize>,
+
+        // how many near-tie alignments to other references to write as secondary records
+        #[clap(long, default_value = "0")]
+        max_secondary_alignments: usize,
+
         #[clap(long)]
         read1: String,
 
@@ -161,12 +192,44 @@ enum Cmd {
         #[clap(long, default_value = "NONE")]
         index2: String,
 
+        // read1 holds both reads of each pair, alternating read one and read two records
+        #[clap(long)]
+        interleaved: bool,
+
+        // reads longer than this are dropped; longer than twice the longest reference go to the checkpointed aligner
+        #[clap(long)]
+        max_read_length: Option<usize>,
+
+        // align reads semi-globally: the whole read has to align, but it can start and end anywhere in the reference
+        #[clap(long)]
+        semi_global: bool,
+
+        // where to put indels that could shift along a repeat with the same score: none (as aligned), left, or right
+        #[clap(long, default_value = "none", possible_values = &["none", "left", "right"])]
+        gap_placement: String,
+
+        // write match / mismatch runs as '=' and 'X' CIGAR operations instead of 'M'
+        #[clap(long)]
+        extended_cigar: bool,
+
+        // write a coordinate-sorted BAM with a BAI index, ready for collapse, sorting the records on disk
+        #[clap(long)]
+        sorted: bool,
+
+        // where --sorted puts its temporary files; NONE uses the system temporary directory
+        #[clap(long, default_value = "NONE")]
+        temp_dir: String,
+
         #[clap(long, default_value_t = 1)]
         threads: usize,
 
         #[clap(long)]
         find_inversions: bool,
 
+        // only align multi-reference reads to the references their unique k-mers point to, instead of to every reference
+        #[clap(long)]
+        fast_reference_lookup: bool,
+
     },
 
     Call {
@@ -176,6 +239,22 @@ enum Cmd {
         #[clap(long)]
         bam: String,
 
+        // a reference index from the index command, used instead of rebuilding the reference lookups
+        #[clap(long)]
+        reference_index: Option<String>,
+
+        #[clap(long)]
+        output: String,
+    },
+
+    Index {
+        #[clap(long)]
+        read_structure: String,
+
+        // a FASTA file of reference sequences, replacing the read structure's reference_fasta
+        #[clap(long)]
+        reference: Option<String>,
+
         #[clap(long)]
         output: String,
     },
@@ -205,65 +284,126 @@ fn main() {
         Cmd::Collapse {
             outbam,
             read_structure,
-            threads: _,
-            temp_dir: _,
+            reference,
+            reference_index,
+            threads,
+            temp_dir,
             inbam,
-            find_inversions: _,
-            fast_reference_lookup: _,
-            max_deletion: _,
+            find_inversions,
+            fast_reference_lookup,
+            max_deletion,
 
         } => {
-            let my_yaml = SequenceLayout::from_yaml(read_structure);
-
-            let mut tmp = InstanceLivedTempDir::new().unwrap();
+            let my_yaml = SequenceLayout::from_yaml_and_reference_fasta(read_structure, reference);
+            let index = reference_index.as_ref().map(|index| ReferenceIndex::open(Path::new(index)));
+            let rm = match &index {
+                Some(index) => index.reference_manager_for_layout(&my_yaml),
+                None => ReferenceManager::from_yaml_input(&my_yaml, 8, 4),
+            };
+
+            let mut tmp = InstanceLivedTempDir::from_temp_dir_option(temp_dir)
+                .expect("Unable to create a temporary directory in the requested location");
+
+            let realignment = RealignmentOptions {
+                find_inversions: *find_inversions,
+                fast_reference_lookup: *fast_reference_lookup,
+                max_deletion: *max_deletion,
+            };
 
             collapse(outbam,
                      &mut tmp,
                      &my_yaml,
-                     inbam);
+                     &rm,
+                     inbam,
+                     threads,
+                     &realignment);
         },
 
         Cmd::Align {
             read_structure,
+            reference,
+            reference_index,
             output_bam_file: output,
             max_reference_multiplier,
             min_read_length,
+            max_indel,
+            max_secondary_alignments,
             read1,
             read2,
             index1,
             index2,
+            interleaved,
+            max_read_length,
+            semi_global,
+            gap_placement,
+            extended_cigar,
+            sorted,
+            temp_dir,
             threads,
             find_inversions,
+            fast_reference_lookup,
         } => {
-            let my_yaml = SequenceLayout::from_yaml(read_structure);
-            let rm = ReferenceManager::from_yaml_input(&my_yaml, 8, 4);
+            let my_yaml = SequenceLayout::from_yaml_and_reference_fasta(read_structure, reference);
+            let index = reference_index.as_ref().map(|index| ReferenceIndex::open(Path::new(index)));
+            let rm = match &index {
+                Some(index) => index.reference_manager_for_layout(&my_yaml),
+                None => ReferenceManager::from_yaml_input(&my_yaml, 8, 4),
+            };
 
             let output_path = Path::new(&output);
 
+            let mut tmp = InstanceLivedTempDir::from_temp_dir_option(temp_dir)
+                .expect("Unable to create a temporary directory in the requested location");
+            let sorting_file = if *sorted { Some(tmp.temp_file("aligned.reads.sorted.sharded")) } else { None };
+
             align_reads(&my_yaml,
                         &rm,
                         &output_path,
                         max_reference_multiplier,
                         min_read_length,
+                        max_indel,
+                        max_secondary_alignments,
                         read1,
                         read2,
                         index1,
                         index2,
+                        interleaved,
+                        max_read_length,
+                        semi_global,
+                        &GapPlacement::from_name(gap_placement),
+                        extended_cigar,
+                        sorting_file,
                         threads,
-                        find_inversions);
+                        find_inversions,
+                        fast_reference_lookup);
         },
         Cmd::Call {
             read_structure,
             bam,
+            reference_index,
             output,
         } => {
             let my_yaml = SequenceLayout::from_yaml(read_structure);
 
-            let parser = BamCallingParser::new(&my_yaml);
+            let index = reference_index.as_ref().map(|index| ReferenceIndex::open(Path::new(index)));
+            let parser = match &index {
+                Some(index) => BamCallingParser::from_reference_manager(&my_yaml, index.reference_manager_for_layout(&my_yaml)),
+                None => BamCallingParser::new(&my_yaml),
+            };
 
             parser.output_bam_file_entries(bam.as_str(), output.as_str() ).expect("Unable to process events");
 
         }
+        Cmd::Index {
+            read_structure,
+            reference,
+            output,
+        } => {
+            let my_yaml = SequenceLayout::from_yaml_and_reference_fasta(read_structure, reference);
+            let rm = ReferenceManager::from_yaml_input(&my_yaml, 8, 4);
+
+            write_reference_index(&rm, Path::new(output));
+        }
     }
 }
 
@@ -283,6 +423,14 @@ impl InstanceLivedTempDir {
     pub fn new() -> Result<InstanceLivedTempDir>
     { ActualTempDir::new().map(Some).map(InstanceLivedTempDir) }
 
+    pub fn new_in<P: AsRef<Path>>(dir: P) -> Result<InstanceLivedTempDir>
+    { ActualTempDir::new_in(dir).map(Some).map(InstanceLivedTempDir) }
+
+    /// a temporary directory inside the one given on the command line, or the system's when that's NONE
+    pub fn from_temp_dir_option(temp_dir: &str) -> Result<InstanceLivedTempDir> {
+        if temp_dir == "NONE" { InstanceLivedTempDir::new() } else { InstanceLivedTempDir::new_in(temp_dir) }
+    }
+
     pub fn temp_file(&mut self, name: &str) -> PathBuf
     {
         self.0.as_ref().unwrap().path().join(name).clone()
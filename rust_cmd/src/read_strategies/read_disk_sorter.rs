@@ -1,6 +1,7 @@
 use std::cmp::Ordering;
 use std::collections::{VecDeque};
 use serde::{Serialize, Deserialize};
+use shardio::Range;
 use crate::alignment::alignment_matrix::{AlignmentResult};
 use crate::alignment::fasta_bit_encoding::{FastaBase};
 
@@ -28,6 +29,24 @@ impl SortingReadSetContainer {
         }
     }
 
+    /// the range of a shard file, sorted before any tags are added, that holds the reads aligned to one
+    /// reference. Containers order on their reference name first, so each reference is a contiguous block
+    pub fn reference_range(reference_name: &str) -> Range<SortingReadSetContainer> {
+        let bound = |name: String| SortingReadSetContainer::empty_tags(AlignmentResult {
+            reference_name: name,
+            read_name: "".to_string(),
+            reference_aligned: vec![],
+            read_aligned: vec![],
+            read_quals: None,
+            cigar_string: vec![],
+            path: vec![],
+            score: 0.0,
+            reference_start: 0,
+            read_start: 0,
+            bounding_box: None,
+        });
+        Range::interval(bound(reference_name.to_string()), bound(format!("{}\0", reference_name)))
+    }
 }
 impl Eq for SortingReadSetContainer {}
 
@@ -1,9 +1,12 @@
 use bio::io::fastq::{Record, Records};
 use bio::io::fastq::Reader as FqReader;
 use serde::{Serialize, Deserialize};
-use std::io::{BufReader};
+use std::fs::File;
+use std::io::{BufReader, Read};
 use std::path::PathBuf;
+use flate2::read::MultiGzDecoder;
 use rust_htslib::bgzf::Reader;
+use crate::read_strategies::sequence_layout::{ReadPosition, SequenceLayout};
 
 /// holds a set of reads for reading and writing to disk
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -53,15 +56,67 @@ impl std::fmt::Display for ReadSetContainer {
     }
 }
 
-unsafe impl Send for ReadIterator {}
+/// we skip the odd unparseable record, but this many in a row means the file is broken (or the reader is stuck on it)
+pub const MAX_CONSECUTIVE_UNPARSEABLE_RECORDS: usize = 10;
 
-unsafe impl Sync for ReadIterator {}
+/// the FASTQ record stream from one input file, whatever its compression
+pub type FastqRecords = Records<BufReader<Box<dyn Read + Send>>>;
+
+/// rust_htslib's BGZF reader holds a raw htslib handle, so it isn't Send. We own that handle outright and only use it
+/// from the thread holding the iterator, so it's safe to move between threads
+struct BgzfReader(Reader);
+
+unsafe impl Send for BgzfReader {}
+
+impl Read for BgzfReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+/// how an input FASTQ file is compressed, detected from its first bytes rather than its extension
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FastqCompression {
+    Plain,
+    Gzip,
+    Bgzip,
+}
+
+/// Look at the magic bytes of a file to find its compression. BGZF files are gzip files whose header has an extra
+/// field holding a 'BC' subfield, so we check for that before falling back to plain gzip
+pub fn detect_compression(path: &PathBuf) -> FastqCompression {
+    let mut header = Vec::new();
+    File::open(path).unwrap_or_else(|_x| panic!("Unable to open input file {}", path.display()))
+        .take(18).read_to_end(&mut header).unwrap_or_else(|_x| panic!("Unable to read input file {}", path.display()));
+
+    if header.len() >= 2 && header[0] == 0x1f && header[1] == 0x8b {
+        if header.len() >= 14 && header[3] & 0x04 != 0 && header[12] == b'B' && header[13] == b'C' {
+            FastqCompression::Bgzip
+        } else {
+            FastqCompression::Gzip
+        }
+    } else {
+        FastqCompression::Plain
+    }
+}
+
+/// Our command line uses NONE for a read file that wasn't provided
+pub fn optional_read_path(path: &String) -> Option<PathBuf> {
+    if path == "NONE" { None } else { Some(PathBuf::from(path)) }
+}
 
 pub struct ReadIterator {
-    read_one: Option<Records<BufReader<Reader>>>,
-    read_two: Option<Records<BufReader<Reader>>>,
-    index_one: Option<Records<BufReader<Reader>>>,
-    index_two: Option<Records<BufReader<Reader>>>,
+    read_one: Option<FastqRecords>,
+    read_two: Option<FastqRecords>,
+    index_one: Option<FastqRecords>,
+    index_two: Option<FastqRecords>,
+
+    // read one's file holds both reads of each pair, alternating read one and read two
+    interleaved: bool,
+
+    // the path behind each file's name, for error messages
+    paths: Vec<(&'static str, PathBuf)>,
+    consecutive_unparseable: usize,
 
     pub reads_processed: usize,
     pub broken_reads: usize,
@@ -75,78 +130,345 @@ impl ReadIterator
                index_1: Option<PathBuf>,
                index_2: Option<PathBuf>,
     ) -> ReadIterator {
-        let r_one = ReadIterator::open_reader(&Some(&read_1));
-        if r_one.is_none() { panic!("Unable to open input file"); }
-
-        let read2 = if read_2.is_some() { ReadIterator::open_reader(&Some(&read_2.clone().unwrap())) } else { None };
-        let index1 = if index_1.is_some() { ReadIterator::open_reader(&Some(&index_1.clone().unwrap())) } else { None };
-        let index2 = if index_2.is_some() { ReadIterator::open_reader(&Some(&index_2.clone().unwrap())) } else { None };
-
+        let paths = [("read one", Some(&read_1)), ("read two", read_2.as_ref()), ("index one", index_1.as_ref()), ("index two", index_2.as_ref())]
+            .into_iter().filter_map(|(file, path)| path.map(|path| (file, path.clone()))).collect();
         ReadIterator {
-            read_one: r_one,
-            read_two: read2,
-            index_one: index1,
-            index_two: index2,
+            read_one: Some(ReadIterator::open_reader(&read_1)),
+            read_two: read_2.as_ref().map(|path| ReadIterator::open_reader(path)),
+            index_one: index_1.as_ref().map(|path| ReadIterator::open_reader(path)),
+            index_two: index_2.as_ref().map(|path| ReadIterator::open_reader(path)),
+            interleaved: false,
+            paths,
+            consecutive_unparseable: 0,
             reads_processed: 0,
             broken_reads: 0,
         }
     }
 
-    fn open_reader(check_path: &Option<&PathBuf>) -> Option<Records<BufReader<Reader>>> {
-        if check_path.is_some() && check_path.as_ref().unwrap().exists() {
-            info!("Opening file: {:?}", check_path);
-            Some(FqReader::new(Reader::from_path(check_path.unwrap()).unwrap()).records())
+    /// Read pairs from a single interleaved FASTQ, where each read one record is immediately followed by its read two
+    pub fn new_interleaved(reads: PathBuf,
+                           index_1: Option<PathBuf>,
+                           index_2: Option<PathBuf>,
+    ) -> ReadIterator {
+        let mut iterator = ReadIterator::new(reads.clone(), None, index_1, index_2);
+        iterator.interleaved = true;
+        iterator.paths.push(("interleaved read two", reads));
+        iterator
+    }
+
+    /// Open the read files for a sequence layout, making sure every read the layout declares has a file to come from.
+    /// Read files are given as paths or NONE, and read two comes from the read one file when it's interleaved
+    pub fn from_layout(read_structure: &SequenceLayout,
+                       read_1: &String,
+                       read_2: &String,
+                       index_1: &String,
+                       index_2: &String,
+                       interleaved: bool,
+    ) -> ReadIterator {
+        let read_1 = optional_read_path(read_1).expect("Read one must be provided");
+        let read_2 = optional_read_path(read_2);
+        let index_1 = optional_read_path(index_1);
+        let index_2 = optional_read_path(index_2);
+
+        if interleaved && read_2.is_some() {
+            panic!("Read two can't be provided as a separate file when reads are interleaved");
+        }
+
+        read_structure.reads.iter().for_each(|read| {
+            match read {
+                ReadPosition::Read2 { .. } => assert!(interleaved || read_2.is_some(), "The read structure includes read two, but no read two file was provided"),
+                ReadPosition::Index1 { .. } => assert!(index_1.is_some(), "The read structure includes index one, but no index one file was provided"),
+                ReadPosition::Index2 { .. } => assert!(index_2.is_some(), "The read structure includes index two, but no index two file was provided"),
+                ReadPosition::Read1 { .. } | ReadPosition::Spacer { .. } => {}
+            }
+        });
+
+        if interleaved {
+            ReadIterator::new_interleaved(read_1, index_1, index_2)
+        } else {
+            ReadIterator::new(read_1, read_2, index_1, index_2)
+        }
+    }
+
+    fn open_reader(path: &PathBuf) -> FastqRecords {
+        if !path.exists() {
+            panic!("Unable to find input file {}", path.display());
+        }
+        let compression = detect_compression(path);
+        info!("Opening file: {:?} ({:?})", path, compression);
+
+        let reader: Box<dyn Read + Send> = match compression {
+            FastqCompression::Plain => Box::new(File::open(path).unwrap()),
+            FastqCompression::Gzip => Box::new(MultiGzDecoder::new(File::open(path).unwrap())),
+            FastqCompression::Bgzip => Box::new(BgzfReader(Reader::from_path(path).unwrap())),
+        };
+        FqReader::new(reader).records()
+    }
+
+    /// Pull the next set of reads from every file, checking that they're still in step: each file has to have a
+    /// record for this position, and every record has to carry read one's name. Failures are counted in `broken_reads`
+    pub fn next_read_set(&mut self) -> Option<Result<ReadSetContainer, ReadSyncError>> {
+        let read_one = self.read_one.as_mut().unwrap().next();
+        if read_one.is_none() {
+            return self.check_exhausted();
+        }
+
+        // pull from every other file even if read one is broken, so the files stay in step
+        let read_two = if self.interleaved {
+            next_mate(&mut self.read_one, "interleaved read two")
         } else {
-            info!("Unable to open file: {:?}", check_path);
-            None
+            next_mate(&mut self.read_two, "read two")
+        };
+        let index_one = next_mate(&mut self.index_one, "index one");
+        let index_two = next_mate(&mut self.index_two, "index two");
+
+        let read_set = ReadIterator::synchronized_read_set(read_one.unwrap(), read_two, index_one, index_two);
+        match &read_set {
+            Ok(_) => self.reads_processed += 1,
+            Err(_) => self.broken_reads += 1,
+        }
+        Some(read_set)
+    }
+
+    fn synchronized_read_set(read_one: Result<Record, bio::io::fastq::Error>,
+                             read_two: Result<Option<Record>, ReadSyncError>,
+                             index_one: Result<Option<Record>, ReadSyncError>,
+                             index_two: Result<Option<Record>, ReadSyncError>) -> Result<ReadSetContainer, ReadSyncError> {
+        let read_one = read_one.map_err(|e| ReadSyncError::UnparseableRecord { file: "read one", message: format!("{:?}", e) })?;
+        let read_set = ReadSetContainer { read_one, read_two: read_two?, index_one: index_one?, index_two: index_two? };
+
+        let name = normalized_read_name(read_set.read_one.id());
+        for (file, mate) in [("read two", &read_set.read_two), ("index one", &read_set.index_one), ("index two", &read_set.index_two)] {
+            if let Some(mate) = mate {
+                if normalized_read_name(mate.id()) != name {
+                    return Err(ReadSyncError::NameMismatch { file, read_one: read_set.read_one.id().to_string(), mate: mate.id().to_string() });
+                }
+            }
         }
+        Ok(read_set)
+    }
+
+    fn path(&self, file: &str) -> String {
+        self.paths.iter().find(|(name, _path)| *name == file).map_or(String::from("(unknown)"), |(_name, path)| path.display().to_string())
     }
 
+    /// read one is done, so every other file should be too
+    fn check_exhausted(&mut self) -> Option<Result<ReadSetContainer, ReadSyncError>> {
+        for (file, records) in [("read two", &mut self.read_two), ("index one", &mut self.index_one), ("index two", &mut self.index_two)] {
+            if records.as_mut().map_or(false, |records| records.next().is_some()) {
+                self.broken_reads += 1;
+                return Some(Err(ReadSyncError::UnequalLength { file: "read one", longer_file: file }));
+            }
+        }
+        info!("Done processing reads");
+        None
+    }
 }
+
 impl Iterator for ReadIterator {
     type Item = ReadSetContainer;
 
+    /// Unparseable records are skipped, up to `MAX_CONSECUTIVE_UNPARSEABLE_RECORDS` in a row, but reads that are out of
+    /// step can't be paired back up, so we stop there
     fn next(&mut self) -> Option<ReadSetContainer> {
-        let next_read_one = self.read_one.as_mut().unwrap().next();
-        if next_read_one.is_some() {
-            match next_read_one.unwrap() {
-                Ok(v) => {
-                    Some(ReadSetContainer {
-                        read_one: v,
-                        read_two: unwrap_reader(&mut self.read_two),
-                        index_one: unwrap_reader(&mut self.index_one),
-                        index_two: unwrap_reader(&mut self.index_two),
-                    })
+        loop {
+            match self.next_read_set() {
+                Some(Ok(read_set)) => {
+                    self.consecutive_unparseable = 0;
+                    return Some(read_set);
                 }
-                Err(e) => {
-                    println!("error parsing header: {:?}", e);
-                    None
+                Some(Err(ReadSyncError::UnparseableRecord { file, message })) => {
+                    self.consecutive_unparseable += 1;
+                    if self.consecutive_unparseable >= MAX_CONSECUTIVE_UNPARSEABLE_RECORDS {
+                        panic!("Unable to parse {} records in a row, the last from {} file {}: {}; is it a valid FASTQ file?",
+                               self.consecutive_unparseable, file, self.path(file), message);
+                    }
+                    warn!("Unable to parse a {} record from {}, skipping the read set: {}", file, self.path(file), message);
                 }
+                Some(Err(e)) => panic!("{}", e),
+                None => return None,
             }
-        } else {
-            info!("Done processing reads");
-            None
         }
     }
+}
 
+/// Reasons the records from our read files can't be put together into a read set
+#[derive(Debug, PartialEq, Clone)]
+pub enum ReadSyncError {
+    /// a record in one of the files couldn't be parsed
+    UnparseableRecord { file: &'static str, message: String },
+    /// the records at the same position in read one and another file have different names
+    NameMismatch { file: &'static str, read_one: String, mate: String },
+    /// one file ran out of records before another
+    UnequalLength { file: &'static str, longer_file: &'static str },
 }
 
-/// A helper function to manage unwrapping reads, which has a lot of layers
-pub fn unwrap_reader(read: &mut Option<Records<BufReader<Reader>>>) -> Option<Record> {
-    match read
-    {
-        Some(ref mut read_pointer) => {
-            let next = read_pointer.next();
-            match next {
-                Some(rp) => {
-                    match rp {
-                        Ok(x) => Some(x),
-                        Err(_) => None,
-                    }
-                }
-                None => None,
+impl std::fmt::Display for ReadSyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReadSyncError::UnparseableRecord { file, message } => write!(f, "Unable to parse a {} record: {}", file, message),
+            ReadSyncError::NameMismatch { file, read_one, mate } => write!(f, "The read files are out of sync: read one {} was paired with {} record {}", read_one, file, mate),
+            ReadSyncError::UnequalLength { file, longer_file } => write!(f, "The read files are out of sync: {} ran out of records before {}", file, longer_file),
+        }
+    }
+}
+
+impl std::error::Error for ReadSyncError {}
+
+/// The name a read shares with its mates: the read ID without any comment (such as Illumina's '1:N:0:ATCACG') or
+/// trailing /1, /2 (etc) mate number
+pub fn normalized_read_name(id: &str) -> &str {
+    let name = id.split_whitespace().next().unwrap_or("");
+    match name.rsplit_once('/') {
+        Some((base, mate)) if !mate.is_empty() && mate.chars().all(|c| c.is_ascii_digit()) => base,
+        _ => name,
+    }
+}
+
+/// The next record from an optional file: nothing if we don't have the file, and an error if the file is broken here
+/// or has already run out of records
+fn next_mate(records: &mut Option<FastqRecords>, file: &'static str) -> Result<Option<Record>, ReadSyncError> {
+    match records {
+        None => Ok(None),
+        Some(records) => {
+            match records.next() {
+                Some(Ok(record)) => Ok(Some(record)),
+                Some(Err(e)) => Err(ReadSyncError::UnparseableRecord { file, message: format!("{:?}", e) }),
+                None => Err(ReadSyncError::UnequalLength { file, longer_file: "read one" }),
             }
         }
-        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    const INTERLEAVED_READS: &str = "@read_a/1\nACGTACGT\n+\nIIIIIIII\n@read_a/2\nTTTTGGGG\n+\nIIIIIIII\n@read_b/1\nCCCCAAAA\n+\nIIIIIIII\n@read_b/2\nGGGGCCCC\n+\nIIIIIIII\n";
+
+    fn write_plain(path: &PathBuf) {
+        File::create(path).unwrap().write_all(INTERLEAVED_READS.as_bytes()).unwrap();
+    }
+
+    fn write_gzip(path: &PathBuf) {
+        let mut encoder = GzEncoder::new(File::create(path).unwrap(), Compression::default());
+        encoder.write_all(INTERLEAVED_READS.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    fn write_bgzip(path: &PathBuf) {
+        let mut writer = rust_htslib::bgzf::Writer::from_path(path).unwrap();
+        writer.write_all(INTERLEAVED_READS.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_compression_detection() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let plain = temp_dir.path().join("reads.fastq");
+        let gzip = temp_dir.path().join("reads_gzip.fastq.gz");
+        let bgzip = temp_dir.path().join("reads_bgzip.fastq.gz");
+        write_plain(&plain);
+        write_gzip(&gzip);
+        write_bgzip(&bgzip);
+
+        assert_eq!(detect_compression(&plain), FastqCompression::Plain);
+        assert_eq!(detect_compression(&gzip), FastqCompression::Gzip);
+        assert_eq!(detect_compression(&bgzip), FastqCompression::Bgzip);
+
+        // whatever the compression, we should get the same reads back
+        for path in vec![plain, gzip, bgzip] {
+            let names = ReadIterator::new(path, None, None, None).map(|x| x.read_one.id().to_string()).collect::<Vec<String>>();
+            assert_eq!(names, vec!["read_a/1", "read_a/2", "read_b/1", "read_b/2"]);
+        }
+    }
+
+    #[test]
+    fn test_interleaved_reads() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let interleaved = temp_dir.path().join("interleaved.fastq.gz");
+        write_gzip(&interleaved);
+
+        let pairs = ReadIterator::new_interleaved(interleaved, None, None).collect::<Vec<ReadSetContainer>>();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].read_one.id(), "read_a/1");
+        assert_eq!(pairs[0].read_two.as_ref().unwrap().id(), "read_a/2");
+        assert_eq!(pairs[1].read_one.seq(), b"CCCCAAAA");
+        assert_eq!(pairs[1].read_two.as_ref().unwrap().seq(), b"GGGGCCCC");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_missing_declared_read() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let reads = temp_dir.path().join("reads.fastq");
+        write_plain(&reads);
+
+        let read_structure = SequenceLayout::from_yaml(&String::from("test_data/test_layout.yaml"));
+        assert!(read_structure.reads.iter().any(|x| matches!(x, ReadPosition::Read2 {..})));
+
+        ReadIterator::from_layout(&read_structure,
+                                  &reads.to_str().unwrap().to_string(),
+                                  &String::from("NONE"),
+                                  &String::from("NONE"),
+                                  &String::from("NONE"),
+                                  false);
+    }
+
+    #[test]
+    fn test_normalized_read_names() {
+        assert_eq!(normalized_read_name("M00123:55:000000000-A1B2C:1:1101:15589:1331"), "M00123:55:000000000-A1B2C:1:1101:15589:1331");
+        assert_eq!(normalized_read_name("M00123:55:000000000-A1B2C:1:1101:15589:1331 1:N:0:ATCACG"), "M00123:55:000000000-A1B2C:1:1101:15589:1331");
+        assert_eq!(normalized_read_name("read_a/1"), "read_a");
+        assert_eq!(normalized_read_name("read_a/2"), "read_a");
+        assert_eq!(normalized_read_name("read_a/b"), "read_a/b");
+    }
+
+    #[test]
+    fn test_paired_read_sync() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let read_one = temp_dir.path().join("r1.fastq");
+        let read_two = temp_dir.path().join("r2.fastq");
+        File::create(&read_one).unwrap().write_all(b"@read_a/1\nACGT\n+\nIIII\n@read_b/1\nACGT\n+\nIIII\n@read_c/1\nACGT\n+\nIIII\n").unwrap();
+        File::create(&read_two).unwrap().write_all(b"@read_a/2\nTTTT\n+\nIIII\n@read_x/2\nTTTT\n+\nIIII\n").unwrap();
+
+        let mut iterator = ReadIterator::new(read_one, Some(read_two), None, None);
+        assert_eq!(iterator.next_read_set().unwrap().unwrap().read_two.unwrap().id(), "read_a/2");
+        assert_eq!(iterator.next_read_set().unwrap(), Err(ReadSyncError::NameMismatch { file: "read two", read_one: "read_b/1".to_string(), mate: "read_x/2".to_string() }));
+        assert_eq!(iterator.next_read_set().unwrap(), Err(ReadSyncError::UnequalLength { file: "read two", longer_file: "read one" }));
+        assert!(iterator.next_read_set().is_none());
+
+        assert_eq!(iterator.reads_processed, 1);
+        assert_eq!(iterator.broken_reads, 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_truncated_read_two() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let read_one = temp_dir.path().join("r1.fastq");
+        let read_two = temp_dir.path().join("r2.fastq");
+        File::create(&read_one).unwrap().write_all(b"@read_a/1\nACGT\n+\nIIII\n").unwrap();
+        File::create(&read_two).unwrap().write_all(b"@read_a/2\nTTTT\n+\nIIII\n@read_b/2\nTTTT\n+\nIIII\n").unwrap();
+
+        ReadIterator::new(read_one, Some(read_two), None, None).count();
+    }
+
+    #[test]
+    #[should_panic(expected = "records in a row")]
+    fn test_unparseable_read_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let read_one = temp_dir.path().join("r1.fastq");
+        File::create(&read_one).unwrap().write_all("not a fastq record\n".repeat(100).as_bytes()).unwrap();
+
+        ReadIterator::new(read_one, None, None, None).count();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_mistyped_read_file() {
+        ReadIterator::new(PathBuf::from("test_data/no_such_reads.fastq.gz"), None, None, None);
     }
 }
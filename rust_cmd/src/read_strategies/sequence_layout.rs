@@ -1,8 +1,12 @@
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 use serde::{Serialize,Deserialize};
 use std::collections::{BTreeMap};
+use crate::alignment::aligner::ALIGNER_NAMES;
+use crate::alignment::integer_alignment::IntegerAffineScoring;
+use crate::alignment::scoring_functions::AffineScoring;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
 pub enum UMISortType {
@@ -14,6 +18,7 @@ pub enum MergeStrategy {
     Align,
     Concatenate,
     ConcatenateBothForward,
+    ReferenceGap,
 }
 
 
@@ -23,7 +28,22 @@ impl SequenceLayout {
     ///
     /// # Supported base tags
     ///
-    /// *merge*: (optional) * - contains one optional member, which can be _align_ or _concatenate_
+    /// *merge*: (optional) * - contains one optional member, which can be _align_ or _concatenate_, or _ReferenceGap_,
+    /// which places each mate of a non-overlapping pair on its reference and fills the space between them with Ns
+    /// *aligner*: (optional) - the read to reference aligner: _affine_ (the default), _integer_affine_, which produces the
+    /// same alignments using integer scores, a striped vector kernel, and a compact traceback, and needs much less memory for long references,
+    /// _checkpointed_affine_, which traces back in linear memory for long (e.g. Nanopore) reads, _anchors_, which
+    /// only aligns between exact reference matches, _sigalign_ for sigalign's local aligner, or _bio_ for rust-bio's
+    /// pairwise aligner
+    /// *scoring*: (optional) - alignment scoring overrides (_match_score_, _mismatch_score_, _special_character_score_,
+    /// _gap_open_, _gap_extend_, _final_gap_multiplier_, and an _inversion_ sub-section). Any value not set falls back to
+    /// the default of the stage using it (reference alignment, read merging, or consensus realignment). Each reference can
+    /// carry its own *scoring* section, which is applied on top of this one. A _quality_weighting_ sub-section scales the
+    /// match and mismatch scores of each read base by its Phred quality: the _curve_ (_Linear_, the default, or
+    /// _ErrorProbability_) sets the weight from _min_weight_ at or below _min_quality_ up to full weight at _max_quality_
+    /// The global section can also set _min_merge_overlap_ (default 12) and _max_merge_mismatch_rate_ (default 0.1), how
+    /// many bases read pairs merged by alignment have to overlap, and how many of those can disagree, before we believe
+    /// the overlap rather than joining the mates with Ns
     /// *reads* - contains the read positions that are required for this configuration. The values, on individual lines, are _READ1, _READ2_, _INDEX1_, _INDEX2_
     /// *umi_configurations* - contains one section per UMI, each with:
     /// - *name* - the base of each UMI section
@@ -31,10 +51,20 @@ impl SequenceLayout {
     ///   - *start* - the starting position, if align = true is set this this is in relation to the reference, otherwise the offset into the read
     ///   - *length* - how long this sequence is
     ///   - *file* - (optional) which file contains known sequences that we should match to. One sequence per line, no header
+    /// *reference_fasta*: (optional) - a FASTA file of reference sequences, relative to the YAML file. Each FASTA sequence is
+    /// configured by the *references* entry with the same name, or else the most specific entry with a _name_pattern_
+    /// that matches it, where `*` matches any run of characters (so `"*"` is a template for every sequence). Named
+    /// entries can leave out their _sequence_, but every one of them has to exist in the FASTA file
     ///
     /// an example of this format is the *test_layout.yaml* file in the test_data directory
     ///
     pub fn from_yaml(yaml_file: &str) -> SequenceLayout {
+        SequenceLayout::from_yaml_and_reference_fasta(yaml_file, &None)
+    }
+
+    /// Load a YAML layout (see `from_yaml`), taking the reference sequences from `reference_fasta` if it's set, which
+    /// replaces any *reference_fasta* in the YAML (the command line `--reference` option)
+    pub fn from_yaml_and_reference_fasta(yaml_file: &str, reference_fasta: &Option<String>) -> SequenceLayout {
 
         let mut file = File::open(yaml_file).unwrap_or_else(|_x | panic!("Unable to open YAML configuration file: {}",yaml_file));
 
@@ -45,6 +75,25 @@ impl SequenceLayout {
 
         let mut deserialized_map: SequenceLayout = serde_yaml::from_str(&yaml_contents).expect("Unable to de-yaml your input file");
 
+        let fasta_file = match (reference_fasta, &deserialized_map.reference_fasta) {
+            (Some(fasta), _) => Some(PathBuf::from(fasta)),
+            (None, Some(fasta)) => Some(Path::new(yaml_file).parent().unwrap_or(Path::new("")).join(fasta)),
+            (None, None) => None,
+        };
+
+        match fasta_file {
+            Some(fasta_file) => {
+                deserialized_map.references = SequenceLayout::references_from_fasta(&fasta_file, &deserialized_map.references);
+                deserialized_map.reference_fasta = Some(fasta_file.to_string_lossy().to_string());
+            }
+            None => {
+                deserialized_map.references.iter().for_each(|(name, reference)| {
+                    assert!(reference.name_pattern.is_none(), "Reference {} has a name_pattern, which needs a reference_fasta to match against", name);
+                    assert!(!reference.sequence.is_empty(), "Reference {} has no sequence, and there's no reference_fasta to load it from", name);
+                });
+            }
+        }
+
         for reference in deserialized_map.references.values_mut() {
 
             let mut ordering = reference.umi_configurations.values().map(|umi_config| {
@@ -60,8 +109,28 @@ impl SequenceLayout {
             assert_eq!(reference.target_types.len(), reference.targets.len(), "Target sequences and target type lists must be the same length");
 
             reference.fill_and_validate_target_positions();
+
+            if let Some(scoring) = &reference.scoring {
+                scoring.validate();
+            }
         }
 
+        if let Some(scoring) = &deserialized_map.scoring {
+            scoring.validate();
+        }
+
+        if let Some(aligner) = &deserialized_map.aligner {
+            assert!(ALIGNER_NAMES.contains(&aligner.as_str()), "Unknown aligner {} in the layout, we support {}", aligner, ALIGNER_NAMES.join(", "));
+        }
+
+        // reads too long for the preallocated alignment matrix go to the checkpointed aligner, which has to scale the
+        // scores to integers; catch scoring it can't use here, rather than when the first long read turns up
+        let layout_scoring = AffineScoring::from_layout(&AffineScoring::default_reference_alignment(), &deserialized_map);
+        deserialized_map.references.keys().for_each(|name| {
+            assert!(IntegerAffineScoring::from_affine(&layout_scoring.for_reference(&deserialized_map, name)).is_some(),
+                    "The scoring for reference {} can't be scaled to integers for long read alignment: the scores, and the gap scores times the final_gap_multiplier, have to be multiples of 1/256 (and with quality_weighting, the match, mismatch, and special character scores multiples of 1/16)", name);
+        });
+
         deserialized_map
     }
 
@@ -79,6 +148,55 @@ impl SequenceLayout {
         }).all(|x| x)
     }
 
+    ///
+    /// Create a reference record for every sequence in a FASTA file, configured by the declared reference with the same
+    /// name or the most specific name_pattern match (the pattern with the most non-wildcard characters, ties going to
+    /// the first entry by name). Sequences are upper-cased to match our target lookups
+    ///
+    /// # Arguments
+    ///    * fasta_file - the FASTA file of reference sequences
+    ///    * declared - the references section of the YAML
+    ///
+    pub fn references_from_fasta(fasta_file: &Path, declared: &BTreeMap<String,ReferenceRecord>) -> BTreeMap<String,ReferenceRecord> {
+        let reader = bio::io::fasta::Reader::from_file(fasta_file)
+            .unwrap_or_else(|_x| panic!("Unable to open reference FASTA file: {}", fasta_file.display()));
+
+        let mut references = BTreeMap::new();
+        for record in reader.records() {
+            let record = record.unwrap_or_else(|x| panic!("Unable to read a record from reference FASTA file {}: {}", fasta_file.display(), x));
+            let name = record.id().to_string();
+            let sequence = String::from_utf8(record.seq().to_ascii_uppercase())
+                .unwrap_or_else(|_x| panic!("Reference {} in {} isn't a valid sequence", name, fasta_file.display()));
+
+            let template = match declared.get(&name) {
+                Some(reference) if reference.name_pattern.is_none() => {
+                    assert!(reference.sequence.is_empty() || reference.sequence.to_ascii_uppercase() == sequence,
+                            "The YAML sequence for reference {} doesn't match the sequence in {}", name, fasta_file.display());
+                    reference
+                }
+                _ => declared.values().filter(|reference| {
+                    reference.name_pattern.as_ref().map_or(false, |pattern| name_matches_pattern(&name, pattern))
+                }).fold(None, |best: Option<&ReferenceRecord>, reference| {
+                    match best {
+                        Some(best) if pattern_specificity(best) >= pattern_specificity(reference) => Some(best),
+                        _ => Some(reference),
+                    }
+                }).unwrap_or_else(|| panic!("Reference {} from {} has no entry in the YAML references, and doesn't match any name_pattern", name, fasta_file.display())),
+            };
+
+            let mut reference = template.clone();
+            reference.sequence = sequence;
+            reference.name_pattern = None;
+            assert!(references.insert(name.clone(), reference).is_none(), "Reference {} is in {} more than once", name, fasta_file.display());
+        }
+
+        declared.iter().filter(|(_name, reference)| reference.name_pattern.is_none()).for_each(|(name, _reference)| {
+            assert!(references.contains_key(name), "Reference {} is in the YAML references but not in the reference FASTA file {}", name, fasta_file.display());
+        });
+
+        references
+    }
+
 }
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum AlignedReadOrientation {
@@ -146,13 +264,122 @@ pub enum TargetType {
     Cas9Homing,
 }
 
+/// Alignment scoring values from the YAML layout. Everything is optional: unset values fall back to the
+/// defaults of whichever stage is aligning (see `AffineScoring::from_layout`)
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct ScoringConfiguration {
+    pub match_score: Option<f64>,
+    pub mismatch_score: Option<f64>,
+    pub special_character_score: Option<f64>,
+    pub gap_open: Option<f64>,
+    pub gap_extend: Option<f64>,
+    pub final_gap_multiplier: Option<f64>,
+    pub inversion: Option<InversionScoringConfiguration>,
+    pub quality_weighting: Option<QualityWeightingConfiguration>,
+    pub min_merge_overlap: Option<usize>,
+    pub max_merge_mismatch_rate: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct InversionScoringConfiguration {
+    pub match_score: Option<f64>,
+    pub mismatch_score: Option<f64>,
+    pub gap_open: Option<f64>,
+    pub gap_extend: Option<f64>,
+    pub inversion_penalty: Option<f64>,
+    pub min_inversion_length: Option<usize>,
+}
+
+/// How much a read base's Phred quality scales its match and mismatch scores. The weight rises from _min_weight_ at
+/// _min_quality_ (and below) to 1.0 at _max_quality_ (and above), following the _curve_ in between
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct QualityWeightingConfiguration {
+    pub curve: Option<QualityCurve>,
+    pub min_quality: Option<u8>,
+    pub max_quality: Option<u8>,
+    pub min_weight: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub enum QualityCurve {
+    /// the weight climbs in a straight line between the min and max quality
+    Linear,
+    /// the weight follows the probability the base call is correct, 1 - 10^(-Q/10)
+    ErrorProbability,
+}
+
+impl ScoringConfiguration {
+
+    /// check that the values we were given make sense as alignment scores: matches are rewarded, while mismatches and gaps
+    /// are penalized (or free). We panic on bad values like the rest of the YAML parsing
+    pub fn validate(&self) {
+        let values = [self.match_score, self.mismatch_score, self.special_character_score, self.gap_open, self.gap_extend, self.final_gap_multiplier];
+        assert!(values.iter().flatten().all(|x| x.is_finite()), "Scoring values must be finite numbers");
+
+        if let Some(x) = self.match_score { assert!(x > 0.0, "The scoring match_score must be positive, not {}", x); }
+        if let Some(x) = self.mismatch_score { assert!(x <= 0.0, "The scoring mismatch_score must be zero or negative, not {}", x); }
+        if let Some(x) = self.gap_open { assert!(x <= 0.0, "The scoring gap_open must be zero or negative, not {}", x); }
+        if let Some(x) = self.gap_extend { assert!(x <= 0.0, "The scoring gap_extend must be zero or negative, not {}", x); }
+        if let Some(x) = self.final_gap_multiplier { assert!(x >= 0.0, "The scoring final_gap_multiplier can't be negative, not {}", x); }
+        if let Some(x) = self.min_merge_overlap { assert!(x > 0, "The scoring min_merge_overlap must be at least 1"); }
+        if let Some(x) = self.max_merge_mismatch_rate { assert!((0.0..=1.0).contains(&x), "The scoring max_merge_mismatch_rate must be between 0 and 1, not {}", x); }
+
+        if let Some(inversion) = &self.inversion {
+            let values = [inversion.match_score, inversion.mismatch_score, inversion.gap_open, inversion.gap_extend, inversion.inversion_penalty];
+            assert!(values.iter().flatten().all(|x| x.is_finite()), "Inversion scoring values must be finite numbers");
+
+            if let Some(x) = inversion.match_score { assert!(x > 0.0, "The inversion match_score must be positive, not {}", x); }
+            if let Some(x) = inversion.mismatch_score { assert!(x <= 0.0, "The inversion mismatch_score must be zero or negative, not {}", x); }
+            if let Some(x) = inversion.gap_open { assert!(x <= 0.0, "The inversion gap_open must be zero or negative, not {}", x); }
+            if let Some(x) = inversion.gap_extend { assert!(x <= 0.0, "The inversion gap_extend must be zero or negative, not {}", x); }
+            if let Some(x) = inversion.inversion_penalty { assert!(x <= 0.0, "The inversion_penalty must be zero or negative, not {}", x); }
+            if let Some(x) = inversion.min_inversion_length { assert!(x > 0, "The min_inversion_length must be at least 1"); }
+        }
+
+        if let Some(weighting) = &self.quality_weighting {
+            if let Some(x) = weighting.min_weight { assert!((0.0..=1.0).contains(&x), "The quality weighting min_weight must be between 0 and 1, not {}", x); }
+            if let (Some(low), Some(high)) = (weighting.min_quality, weighting.max_quality) {
+                assert!(low < high, "The quality weighting min_quality ({}) must be below the max_quality ({})", low, high);
+            }
+        }
+    }
+}
+
+/// how specific a reference's name_pattern is: the number of characters that aren't wildcards
+fn pattern_specificity(reference: &ReferenceRecord) -> usize {
+    reference.name_pattern.as_ref().map_or(0, |pattern| pattern.chars().filter(|c| *c != '*').count())
+}
+
+/// does a reference name match a name_pattern, where `*` matches any run of characters
+fn name_matches_pattern(name: &str, pattern: &str) -> bool {
+    let parts = pattern.split('*').collect::<Vec<&str>>();
+    if parts.len() == 1 {
+        return name == pattern;
+    }
+
+    let mut remaining = match name.strip_prefix(parts[0]) {
+        Some(remaining) => remaining,
+        None => return false,
+    };
+    for part in &parts[1..(parts.len() - 1)] {
+        match remaining.find(part) {
+            Some(position) => remaining = &remaining[(position + part.len())..],
+            None => return false,
+        }
+    }
+    remaining.ends_with(parts[parts.len() - 1])
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct ReferenceRecord {
+    #[serde(default)]
     pub sequence: String,
     pub umi_configurations: BTreeMap<String,UMIConfiguration>,
     pub targets: Vec<String>,
     pub target_types: Vec<TargetType>,
     pub target_locations: Option<Vec<usize>>,
+    pub scoring: Option<ScoringConfiguration>,
+    pub name_pattern: Option<String>,
 }
 
 impl ReferenceRecord {
@@ -178,6 +405,8 @@ pub struct SequenceLayout {
     pub reads: Vec<ReadPosition>,
     pub known_strand: bool,
     pub references: BTreeMap<String,ReferenceRecord>,
+    pub scoring: Option<ScoringConfiguration>,
+    pub reference_fasta: Option<String>,
 }
 
 impl SequenceLayout {
@@ -199,6 +428,7 @@ impl SequenceLayout {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::alignment::scoring_functions::{AffineScoring, InversionScoring, QualityWeighting};
 
     #[test]
     fn test_basic_yaml_readback() {
@@ -222,6 +452,107 @@ mod tests {
         SequenceLayout::from_yaml(&String::from("test_data/test_layout_invalid2.yaml"));
     }
 
+    #[test]
+    fn test_scoring_yaml_readback() {
+        let configuration =
+            SequenceLayout::from_yaml(&String::from("test_data/test_layout_scoring.yaml"));
+        let scoring = configuration.scoring.as_ref().unwrap();
+        assert_eq!(scoring.match_score, Some(8.0));
+        assert_eq!(scoring.gap_open, Some(-12.0));
+        assert_eq!(scoring.mismatch_score, None);
+        assert_eq!(scoring.inversion.as_ref().unwrap().inversion_penalty, Some(-30.0));
+
+        let reference_scoring = configuration.references.get("shorter_reference").unwrap().scoring.as_ref().unwrap();
+        assert_eq!(reference_scoring.gap_open, Some(-30.0));
+        assert_eq!(reference_scoring.match_score, None);
+
+        // global values replace the stage defaults, and the reference values are layered on top of that
+        let global = AffineScoring::from_layout(&AffineScoring::default_reference_alignment(), &configuration);
+        assert_eq!(global.match_score, 8.0);
+        assert_eq!(global.mismatch_score, -9.0);
+        assert_eq!(global.gap_open, -12.0);
+
+        assert_eq!(scoring.quality_weighting.as_ref().unwrap().curve, Some(QualityCurve::ErrorProbability));
+        assert_eq!(global.quality_weighting, Some(QualityWeighting::new(QualityCurve::ErrorProbability, 2, 30, 0.5)));
+
+        let per_reference = global.for_reference(&configuration, "shorter_reference");
+        assert_eq!(per_reference.match_score, 8.0);
+        assert_eq!(per_reference.gap_open, -30.0);
+        assert_eq!(per_reference.quality_weighting, global.quality_weighting);
+
+        let inversion = InversionScoring::from_layout(&InversionScoring::default(), &configuration);
+        assert_eq!(inversion.inversion_penalty, -30.0);
+        assert_eq!(inversion.min_inversion_length, 20);
+
+        // no scoring section at all is fine
+        let configuration =
+            SequenceLayout::from_yaml(&String::from("test_data/test_layout.yaml"));
+        assert!(configuration.scoring.is_none());
+    }
+
+    #[test]
+    fn test_name_patterns() {
+        assert!(name_matches_pattern("ref_1", "ref_1"));
+        assert!(!name_matches_pattern("ref_12", "ref_1"));
+        assert!(name_matches_pattern("ref_12", "*"));
+        assert!(name_matches_pattern("ref_12", "ref_*"));
+        assert!(name_matches_pattern("ref_12", "*_12"));
+        assert!(name_matches_pattern("ref_12", "r*_*2"));
+        assert!(!name_matches_pattern("ref_12", "*_13"));
+        assert!(!name_matches_pattern("a", "a*a"));
+    }
+
+    #[test]
+    fn test_reference_fasta_readback() {
+        let configuration =
+            SequenceLayout::from_yaml(&String::from("test_data/test_layout_fasta.yaml"));
+        assert_eq!(configuration.references.len(), 4);
+
+        // the named entry keeps its own targets, and takes its sequence from the FASTA
+        let first = configuration.references.get("1_AAACCCCGGG_GGTAGCAAACGTTTGGACGTG").unwrap();
+        assert_eq!(first.targets, vec!["GGTAGCAAACGTTTGGACGTG".to_string()]);
+        assert!(first.sequence.starts_with("ATGGACTATCATATGC"));
+        assert_eq!(first.target_locations.as_ref().unwrap().len(), 1);
+
+        // the 2_ pattern is more specific than the catch-all template
+        let second = configuration.references.get("2_AACGCCCTAC_GGTGCCCTTACTCTCACCTGATTACTTAATCCGTG").unwrap();
+        assert_eq!(second.target_types, vec![TargetType::Cas9WT]);
+        assert!(second.name_pattern.is_none());
+
+        let third = configuration.references.get("3_AACTATCGGC_GGTAAAGGGCATATGAGGGAGGGAATCGATGTGTG").unwrap();
+        assert_eq!(third.targets, vec!["GGGGTTAGAGCTAGAAATAGCAAGTT".to_string()]);
+        assert_eq!(third.target_types, vec![TargetType::Static]);
+
+        // a reference FASTA passed in directly replaces the one in the YAML
+        let configuration =
+            SequenceLayout::from_yaml_and_reference_fasta(&String::from("test_data/test_layout_fasta.yaml"), &Some("test_data/test_best_alignment.fasta".to_string()));
+        assert_eq!(configuration.references.len(), 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_reference_fasta_missing_reference() {
+        SequenceLayout::from_yaml(&String::from("test_data/test_layout_fasta_missing.yaml"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_scoring_yaml_readback_positive_gap() {
+        SequenceLayout::from_yaml(&String::from("test_data/test_layout_scoring_invalid.yaml"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown aligner blast")]
+    fn test_unknown_aligner_yaml() {
+        SequenceLayout::from_yaml(&String::from("test_data/test_layout_unknown_aligner.yaml"));
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be scaled to integers")]
+    fn test_scoring_yaml_unscalable() {
+        SequenceLayout::from_yaml(&String::from("test_data/test_layout_scoring_unscalable.yaml"));
+    }
+
 
     /*
     TODO: figure out how to get SERDE to panic here or something else reasonable
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
 use bio::io::fasta::*;
 use itertools::Itertools;
 
@@ -54,14 +55,89 @@ impl Hash for Reference<'_, '_> {
     }
 }
 
+/// the size of each k-mer record in a sorted k-mer table, after the k-mer itself: a u64 reference number and a u8
+/// forward flag
+pub const KMER_RECORD_TRAILER: usize = 9;
+
+/// k-mers unique to one strand of one reference, with the reference's index in the `ReferenceManager` and true if
+/// they're from the reference's forward strand. We build the table in memory from the references, or search the sorted
+/// table of a reference index in place, so loading an index doesn't rebuild it
+#[derive(Clone, Debug)]
+pub enum KmerTable<'t> {
+    Owned(HashMap<Vec<u8>, (usize, bool)>),
+    /// records of the k-mer, its little-endian u64 reference number, and a forward flag byte, sorted by k-mer
+    Sorted { records: &'t [u8], kmer_size: usize },
+}
+
+impl<'t> KmerTable<'t> {
+    pub fn get(&self, kmer: &[u8]) -> Option<(usize, bool)> {
+        match self {
+            KmerTable::Owned(table) => table.get(kmer).cloned(),
+            KmerTable::Sorted { records, kmer_size } => {
+                let record_size = kmer_size + KMER_RECORD_TRAILER;
+                let (mut low, mut high) = (0, records.len() / record_size);
+                while low < high {
+                    let middle = (low + high) / 2;
+                    let record = &records[(middle * record_size)..((middle + 1) * record_size)];
+                    match record[..*kmer_size].cmp(kmer) {
+                        std::cmp::Ordering::Less => low = middle + 1,
+                        std::cmp::Ordering::Greater => high = middle,
+                        std::cmp::Ordering::Equal => return Some(KmerTable::decode_record(record, *kmer_size).1),
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    pub fn contains_key(&self, kmer: &[u8]) -> bool {
+        self.get(kmer).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            KmerTable::Owned(table) => table.len(),
+            KmerTable::Sorted { records, kmer_size } => records.len() / (kmer_size + KMER_RECORD_TRAILER),
+        }
+    }
+
+    /// every k-mer in the table; a sorted table comes back in k-mer order
+    pub fn iter(&self) -> Box<dyn Iterator<Item=(&[u8], (usize, bool))> + '_> {
+        match self {
+            KmerTable::Owned(table) => Box::new(table.iter().map(|(kmer, hit)| (kmer.as_slice(), *hit))),
+            KmerTable::Sorted { records, kmer_size } => Box::new(records.chunks_exact(kmer_size + KMER_RECORD_TRAILER)
+                .map(move |record| KmerTable::decode_record(record, *kmer_size))),
+        }
+    }
+
+    fn decode_record(record: &[u8], kmer_size: usize) -> (&[u8], (usize, bool)) {
+        let reference = u64::from_le_bytes(record[kmer_size..(kmer_size + 8)].try_into().unwrap()) as usize;
+        (&record[..kmer_size], (reference, record[kmer_size + 8] == 1))
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Clone)]
 pub struct UniqueKmerLookup<'s, 't> {
     pub kmer_length: usize,
-    pub kmer_to_reference: HashMap<Vec<u8>,Reference<'s, 't>>,
-    pub reference_to_kmer: HashMap<Reference<'s, 't>,Vec<Vec<u8>>>,
+    pub kmer_to_reference: KmerTable<'t>,
     pub all_have_unique_mappings: bool,
+    // the table can borrow from the same reference index as the references' suffix tables
+    references: PhantomData<Reference<'s, 't>>,
+}
 
+impl<'s, 't> UniqueKmerLookup<'s, 't> {
+    pub fn new(kmer_to_reference: KmerTable<'t>, all_have_unique_mappings: bool) -> UniqueKmerLookup<'s, 't> {
+        UniqueKmerLookup { kmer_length: 0, kmer_to_reference, all_have_unique_mappings, references: PhantomData }
+    }
+
+    /// the unique forward-strand k-mers of a reference, by its index in the `ReferenceManager`
+    pub fn reference_kmers(&self, reference: usize) -> Vec<Vec<u8>> {
+        self.kmer_to_reference.iter()
+            .filter(|(_kmer, (kmer_reference, forward))| *kmer_reference == reference && *forward)
+            .map(|(kmer, _hit)| kmer.to_vec())
+            .collect()
+    }
 }
 
 #[allow(dead_code)]
@@ -102,11 +178,8 @@ impl <'a, 's, 't>ReferenceManager<'a, 's, 't> {
            }
         }).collect();
 
-        let longest_ref = references.iter().map(|r| r.sequence.len()).max().unwrap_or(0);
         let unique_kmers = ReferenceManager::unique_kmers(&references, &kmer_size, &kmer_spacing);
-        let references = references.into_iter().enumerate().collect::<HashMap<usize,Reference>>();
-        let reference_name_to_ref = references.iter().map(|(i,r)| (r.name.clone(),*i)).collect();
-        ReferenceManager{ references, reference_name_to_ref, unique_kmers, kmer_size: kmer_size.clone(), kmer_skip: kmer_spacing.clone(), longest_ref }
+        ReferenceManager::from_parts(references, unique_kmers, kmer_size, kmer_spacing)
     }
 
     pub fn from_fa_file(fasta_file: &String, kmer_size: usize, kmer_spacing: usize) -> ReferenceManager {
@@ -115,8 +188,14 @@ impl <'a, 's, 't>ReferenceManager<'a, 's, 't> {
     }
 
     pub fn from_fasta_vec(references: Vec<Reference<'a, 'a>>, kmer_size: usize, kmer_spacing: usize) -> ReferenceManager<'a, 'a, 'a> {
-        let longest_ref = references.iter().map(|r| r.sequence.len()).max().unwrap_or(0);
         let unique_kmers = ReferenceManager::unique_kmers(&references, &kmer_size, &kmer_spacing);
+        ReferenceManager::from_parts(references, unique_kmers, kmer_size, kmer_spacing)
+    }
+
+    /// assemble a manager from references and their already computed unique k-mers, where the k-mer table refers to
+    /// references by their position in `references` (e.g. when loading a saved reference index)
+    pub fn from_parts(references: Vec<Reference<'a, 'a>>, unique_kmers: UniqueKmerLookup<'a, 'a>, kmer_size: usize, kmer_spacing: usize) -> ReferenceManager<'a, 'a, 'a> {
+        let longest_ref = references.iter().map(|r| r.sequence.len()).max().unwrap_or(0);
         let references = references.into_iter().enumerate().collect::<HashMap<usize,Reference>>();
         let reference_name_to_ref = references.iter().map(|(i,r)| (r.name.clone(),*i)).collect();
 
@@ -143,42 +222,61 @@ impl <'a, 's, 't>ReferenceManager<'a, 's, 't> {
 
     pub fn sequence_to_kmers(reference: &Vec<u8>, kmer_size: &usize, kmer_spacing: &usize) -> Vec<(Vec<u8>, usize)> {
         let kmers: Vec<(Vec<u8>,usize)> = reference.to_ascii_uppercase().windows(*kmer_size).step_by(*kmer_spacing).dedup_with_count().map(|(c,w)| (w.to_vec(),c)).collect::<Vec<(Vec<u8>,usize)>>();
-        //kmers.extend(reference.reverse_complement().to_ascii_uppercase().windows(*kmer_size).step_by(*kmer_spacing).dedup_with_count().map(|(c,w)| (w.to_vec(),c)).collect::<Vec<(Vec<u8>,usize)>>());
         kmers
     }
 
-    /// find a list of unique kmers per reference
+    /// the k-mers from both strands of a sequence, each with its count and true if it's from the forward strand
+    pub fn sequence_to_stranded_kmers(reference: &Vec<u8>, kmer_size: &usize, kmer_spacing: &usize) -> Vec<(Vec<u8>, usize, bool)> {
+        let mut kmers = ReferenceManager::sequence_to_kmers(reference, kmer_size, kmer_spacing).into_iter().map(|(k, c)| (k, c, true)).collect::<Vec<(Vec<u8>, usize, bool)>>();
+        kmers.extend(ReferenceManager::sequence_to_kmers(&bio::alphabets::dna::revcomp(reference), kmer_size, kmer_spacing).into_iter().map(|(k, c)| (k, c, false)));
+        kmers
+    }
+
+    /// find a list of unique kmers per reference; a k-mer has to be unique across both strands of every reference
     pub fn unique_kmers(references: &Vec<Reference<'s, 't>>, kmer_size: &usize, kmer_spacing: &usize) -> UniqueKmerLookup<'s, 't> {
         let mut kmer_counts = HashMap::new();
 
         for reference in references {
-            let kmers = ReferenceManager::sequence_to_kmers(&reference.sequence_u8, kmer_size, kmer_spacing);
+            let kmers = ReferenceManager::sequence_to_stranded_kmers(&reference.sequence_u8, kmer_size, kmer_spacing);
             for kmer in kmers {
                 kmer_counts.insert(kmer.0.clone(),if kmer_counts.contains_key(&kmer.0) {kmer_counts.get(&kmer.0).unwrap()} else {&0} + kmer.1);
             }
         }
 
-        let mut reference_to_unique: HashMap<Reference,Vec<Vec<u8>>> = HashMap::new();
         let mut unique_kmer_to_reference = HashMap::new();
 
         let mut all_unique = true;
 
-        for reference in references {
-            let kmers = ReferenceManager::sequence_to_kmers(&reference.sequence_u8, kmer_size, kmer_spacing);
-            let unique_kmers = kmers.iter().filter(|(k,_c)| kmer_counts.contains_key(k) && *kmer_counts.get(k).unwrap() == 1).collect_vec();
+        for (reference_index, reference) in references.iter().enumerate() {
+            let kmers = ReferenceManager::sequence_to_stranded_kmers(&reference.sequence_u8, kmer_size, kmer_spacing);
+            let unique_kmers = kmers.iter().filter(|(k,_c,_f)| kmer_counts.contains_key(k) && *kmer_counts.get(k).unwrap() == 1).collect_vec();
 
             if unique_kmers.is_empty() {
                 warn!("Unique kmer count for reference {} is empty!", String::from_utf8(reference.name.clone()).unwrap());
                 all_unique = false;
             }
-            for (kmer,_count) in &unique_kmers {
-                unique_kmer_to_reference.insert(kmer.clone(),reference.clone());
+            for (kmer,_count,forward) in &unique_kmers {
+                unique_kmer_to_reference.insert(kmer.clone(),(reference_index, *forward));
             }
-            reference_to_unique.insert(reference.clone(), unique_kmers.into_iter().map(|(v,_c)|v.clone()).collect());
 
         }
 
-        UniqueKmerLookup{ kmer_length: 0, kmer_to_reference: unique_kmer_to_reference, reference_to_kmer: reference_to_unique, all_have_unique_mappings: all_unique }
+        UniqueKmerLookup::new(KmerTable::Owned(unique_kmer_to_reference), all_unique)
+    }
+
+    /// Vote on which reference, and which strand of it, a read comes from using the unique k-mer index. Each reference
+    /// sharing unique k-mers with the read is returned with its forward and reverse strand hit counts, best supported first
+    pub fn kmer_hits(&self, read: &Vec<u8>) -> Vec<(&Reference<'a, 'a>, usize, usize)> {
+        let mut votes: HashMap<usize, (usize, usize)> = HashMap::new();
+        for (kmer, _count) in ReferenceManager::sequence_to_kmers(read, &self.kmer_size, &self.kmer_skip) {
+            if let Some((reference, forward)) = self.unique_kmers.kmer_to_reference.get(&kmer) {
+                let hits = votes.entry(reference).or_insert((0, 0));
+                if forward { hits.0 += 1 } else { hits.1 += 1 }
+            }
+        }
+        let mut ranked = votes.into_iter().map(|(reference, (forward, reverse))| (self.references.get(&reference).unwrap(), forward, reverse)).collect::<Vec<(&Reference<'a, 'a>, usize, usize)>>();
+        ranked.sort_by(|a, b| (b.1 + b.2).cmp(&(a.1 + a.2)));
+        ranked
     }
 
     #[allow(dead_code)]
@@ -189,7 +287,7 @@ impl <'a, 's, 't>ReferenceManager<'a, 's, 't> {
         let mut votes = HashMap::new();
         for (kmer,_count) in read_kmers {
             if self.unique_kmers.kmer_to_reference.contains_key(&kmer) {
-                let reference= self.unique_kmers.kmer_to_reference.get(&*kmer).unwrap();
+                let reference= self.references.get(&self.unique_kmers.kmer_to_reference.get(&kmer).unwrap().0).unwrap();
                 *votes.entry(reference).or_insert(0) += 1;
 
             }
@@ -212,8 +310,8 @@ mod tests {
         let order_fastas = String::from("test_data/18guide1_pcr_sequence.fasta");
         let rm = ReferenceManager::from_fa_file(&order_fastas, 15, 5 );
 
-        for (reference,kmers) in rm.unique_kmers.reference_to_kmer {
-            println!("Reference name {} and count {}",String::from_utf8(reference.name).unwrap(),kmers.len());
+        for (reference_index,reference) in &rm.references {
+            println!("Reference name {} and count {}",String::from_utf8(reference.name.clone()).unwrap(),rm.unique_kmers.reference_kmers(*reference_index).len());
         }
         assert_eq!(rm.references.len(),180);
     }
@@ -225,7 +323,8 @@ mod tests {
         let rm = ReferenceManager::from_fa_file(&order_fastas, 15, 5 );
         assert_eq!(rm.references.len(),1);
 
-        for (reference,kmers) in rm.unique_kmers.reference_to_kmer {
+        for (reference_index,reference) in &rm.references {
+            let kmers = rm.unique_kmers.reference_kmers(*reference_index);
             println!("kmers {} {}",String::from_utf8(reference.name.clone()).unwrap(),kmers.iter().map(|c| String::from_utf8(c.clone()).unwrap()).join(","));
             assert!(kmers.contains(&"GGGCGAGATCAAGCA".as_bytes().to_vec()));
         }
@@ -233,17 +332,37 @@ mod tests {
         let rm = ReferenceManager::from_fa_file(&order_fastas, 15, 5 );
         assert_eq!(rm.references.len(),2);
 
-        for (_reference_index,kmers) in rm.unique_kmers.reference_to_kmer.iter().enumerate() {
-            println!("kmers {} {}",String::from_utf8(kmers.0.name.clone()).unwrap(),kmers.1.iter().map(|c| String::from_utf8(c.clone()).unwrap()).join(","));
-            assert!(!kmers.1.contains(&"TCACCTATTAGCGGCTAA".as_bytes().to_vec()));
-            if kmers.0.name == "cas_tag".as_bytes().to_vec() {
-                assert!(kmers.1.contains(&"TTTTTTTTTTTTTTC".as_bytes().to_vec()));
+        for (reference_index,reference) in &rm.references {
+            let kmers = rm.unique_kmers.reference_kmers(*reference_index);
+            println!("kmers {} {}",String::from_utf8(reference.name.clone()).unwrap(),kmers.iter().map(|c| String::from_utf8(c.clone()).unwrap()).join(","));
+            assert!(!kmers.contains(&"TCACCTATTAGCGGCTAA".as_bytes().to_vec()));
+            if reference.name == "cas_tag".as_bytes().to_vec() {
+                assert!(kmers.contains(&"TTTTTTTTTTTTTTC".as_bytes().to_vec()));
             } else {
-                assert!(kmers.1.contains(&"AAAAAAAAAAAATTC".as_bytes().to_vec()));
+                assert!(kmers.contains(&"AAAAAAAAAAAATTC".as_bytes().to_vec()));
             }
         }
     }
 
+    #[test]
+    fn test_stranded_kmer_hits() {
+        let order_fastas = String::from("test_data/two_references.fa");
+        let rm = ReferenceManager::from_fa_file(&order_fastas, 15, 5 );
+        let cas_tag = rm.references.values().find(|r| r.name == "cas_tag".as_bytes().to_vec()).unwrap();
+
+        // the forward strand only hits forward k-mers
+        let hits = rm.kmer_hits(&cas_tag.sequence_u8);
+        assert_eq!(hits[0].0.name, cas_tag.name);
+        assert!(hits[0].1 > 0);
+        assert_eq!(hits[0].2, 0);
+
+        // and the reverse complement only hits the reverse strand of the same reference
+        let hits = rm.kmer_hits(&bio::alphabets::dna::revcomp(&cas_tag.sequence_u8));
+        assert_eq!(hits[0].0.name, cas_tag.name);
+        assert_eq!(hits[0].1, 0);
+        assert!(hits[0].2 > 0);
+    }
+
     #[test]
     fn test_alignment_to_large_library() {
         let order_fastas = String::from("test_data/18guide1_pcr_sequence.fasta");
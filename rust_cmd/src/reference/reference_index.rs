@@ -0,0 +1,324 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use memmap2::Mmap;
+use suffix::SuffixTable;
+
+use crate::alignment::fasta_bit_encoding::FastaBase;
+use crate::read_strategies::sequence_layout::SequenceLayout;
+use crate::reference::fasta_reference::{KMER_RECORD_TRAILER, KmerTable, Reference, ReferenceManager, SuffixTableLookup, UniqueKmerLookup};
+
+/// the first bytes of every reference index file
+pub const REFERENCE_INDEX_MAGIC: &[u8; 8] = b"CLQREFIX";
+
+/// bump this whenever the layout below changes; we refuse to load indexes written with a different version
+pub const REFERENCE_INDEX_VERSION: u32 = 2;
+
+/// A reference index: the reference sequences, their suffix tables, and the unique k-mer table, written to disk by
+/// `write_reference_index` and memory-mapped back in so we don't rebuild them every run. All values are little-endian:
+///
+/// - the magic bytes and a u32 version
+/// - u64 k-mer size and spacing, and the u64 number of references
+/// - for each reference: the u64-length-prefixed name and sequence, the u64 suffix table seed size, and the u64 length
+///   and u64 file offset of its suffix table
+/// - the u64 number of unique k-mers, then each k-mer (k-mer size bytes), its u64 reference number, and a u8 forward
+///   flag, sorted by k-mer
+/// - the suffix tables, as u32 arrays aligned to four bytes
+///
+/// The suffix tables, their text, and the k-mer table are borrowed straight from the mapped file (we binary search the
+/// sorted k-mers in place), so a large library costs one pass over the k-mer table to check it on load and its pages are
+/// shared between threads (and processes)
+pub struct ReferenceIndex {
+    mmap: Mmap,
+    kmer_size: usize,
+    kmer_spacing: usize,
+    references: Vec<IndexedReference>,
+    kmers: (usize, usize),
+    all_have_unique_mappings: bool,
+}
+
+/// where a reference's pieces live within the mapped index
+struct IndexedReference {
+    name: (usize, usize),
+    sequence: (usize, usize),
+    seed_size: usize,
+    table_offset: usize,
+    table_length: usize,
+}
+
+/// Write the references, suffix tables, and unique k-mers of a `ReferenceManager` to an index file
+pub fn write_reference_index(rm: &ReferenceManager, output: &Path) {
+    let references = (0..rm.references.len()).map(|i| rm.references.get(&i).unwrap()).collect::<Vec<&Reference>>();
+    let mut kmers = rm.unique_kmers.kmer_to_reference.iter().collect::<Vec<(&[u8], (usize, bool))>>();
+    kmers.sort_by(|(kmer1, _hit1), (kmer2, _hit2)| kmer1.cmp(kmer2));
+
+    // the header doesn't depend on the table offsets, so size it with placeholders, then lay the tables out after it
+    let placeholder_offsets = vec![0; references.len()];
+    let header_length = encode_header(rm, &references, &kmers, &placeholder_offsets).len();
+
+    let mut offsets = Vec::new();
+    let mut offset = align_to_four(header_length);
+    for reference in &references {
+        offsets.push(offset);
+        offset += reference.suffix_table.suffix_table.table().len() * 4;
+    }
+
+    let header = encode_header(rm, &references, &kmers, &offsets);
+    assert_eq!(header.len(), header_length);
+
+    let mut writer = BufWriter::new(File::create(output).unwrap_or_else(|_x| panic!("Unable to create reference index file {}", output.display())));
+    writer.write_all(&header).expect("Unable to write the reference index header");
+    writer.write_all(&vec![0u8; align_to_four(header_length) - header_length]).expect("Unable to write the reference index header");
+    for reference in &references {
+        for position in reference.suffix_table.suffix_table.table() {
+            writer.write_all(&position.to_le_bytes()).expect("Unable to write a reference index suffix table");
+        }
+    }
+    writer.flush().expect("Unable to write the reference index");
+}
+
+fn align_to_four(position: usize) -> usize {
+    (position + 3) / 4 * 4
+}
+
+fn encode_header(rm: &ReferenceManager, references: &Vec<&Reference>, kmers: &Vec<(&[u8], (usize, bool))>, table_offsets: &Vec<usize>) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(REFERENCE_INDEX_MAGIC);
+    header.extend_from_slice(&REFERENCE_INDEX_VERSION.to_le_bytes());
+    header.extend_from_slice(&(rm.kmer_size as u64).to_le_bytes());
+    header.extend_from_slice(&(rm.kmer_skip as u64).to_le_bytes());
+    header.extend_from_slice(&(references.len() as u64).to_le_bytes());
+
+    for (reference, table_offset) in references.iter().zip(table_offsets.iter()) {
+        header.extend_from_slice(&(reference.name.len() as u64).to_le_bytes());
+        header.extend_from_slice(&reference.name);
+        header.extend_from_slice(&(reference.sequence_u8.len() as u64).to_le_bytes());
+        header.extend_from_slice(&reference.sequence_u8);
+        header.extend_from_slice(&(reference.suffix_table.seed_size as u64).to_le_bytes());
+        header.extend_from_slice(&(reference.suffix_table.suffix_table.table().len() as u64).to_le_bytes());
+        header.extend_from_slice(&(*table_offset as u64).to_le_bytes());
+    }
+
+    header.extend_from_slice(&(kmers.len() as u64).to_le_bytes());
+    for (kmer, (reference, forward)) in kmers {
+        assert_eq!(kmer.len(), rm.kmer_size, "Unique k-mer {} isn't {} bases long", String::from_utf8_lossy(kmer), rm.kmer_size);
+        header.extend_from_slice(kmer);
+        header.extend_from_slice(&(*reference as u64).to_le_bytes());
+        header.push(if *forward { 1 } else { 0 });
+    }
+    header
+}
+
+/// reads the header fields in order, panicking with the index file name if it's truncated
+struct HeaderCursor<'i> {
+    bytes: &'i [u8],
+    position: usize,
+    path: &'i Path,
+}
+
+impl<'i> HeaderCursor<'i> {
+    fn take(&mut self, length: usize) -> (usize, usize) {
+        assert!(self.position + length <= self.bytes.len(), "Reference index {} is truncated", self.path.display());
+        let start = self.position;
+        self.position += length;
+        (start, length)
+    }
+
+    fn u64(&mut self) -> usize {
+        let (start, length) = self.take(8);
+        u64::from_le_bytes(self.bytes[start..(start + length)].try_into().unwrap()) as usize
+    }
+
+    fn u32(&mut self) -> u32 {
+        let (start, length) = self.take(4);
+        u32::from_le_bytes(self.bytes[start..(start + length)].try_into().unwrap())
+    }
+}
+
+impl ReferenceIndex {
+    /// Memory-map a reference index written by `write_reference_index`
+    pub fn open(path: &Path) -> ReferenceIndex {
+        let file = File::open(path).unwrap_or_else(|_x| panic!("Unable to open reference index {}", path.display()));
+        // the index is read-only to us; like any mapped file it shouldn't be rewritten while we're running
+        let mmap = unsafe { Mmap::map(&file) }.unwrap_or_else(|_x| panic!("Unable to memory-map reference index {}", path.display()));
+
+        let mut cursor = HeaderCursor { bytes: &mmap, position: 0, path };
+        let (magic_start, magic_length) = cursor.take(REFERENCE_INDEX_MAGIC.len());
+        assert_eq!(&mmap[magic_start..(magic_start + magic_length)], REFERENCE_INDEX_MAGIC, "{} isn't a reference index", path.display());
+        let version = cursor.u32();
+        assert_eq!(version, REFERENCE_INDEX_VERSION, "Reference index {} is version {}, but we need version {}; please rebuild it with the index command",
+                   path.display(), version, REFERENCE_INDEX_VERSION);
+
+        let kmer_size = cursor.u64();
+        let kmer_spacing = cursor.u64();
+        let reference_count = cursor.u64();
+
+        let references = (0..reference_count).map(|_i| {
+            let name_length = cursor.u64();
+            let name = cursor.take(name_length);
+            let sequence_length = cursor.u64();
+            let sequence = cursor.take(sequence_length);
+            let seed_size = cursor.u64();
+            let table_length = cursor.u64();
+            let table_offset = cursor.u64();
+            assert!(table_offset % 4 == 0 && table_offset + table_length * 4 <= mmap.len(), "Reference index {} has a corrupt suffix table", path.display());
+            IndexedReference { name, sequence, seed_size, table_offset, table_length }
+        }).collect::<Vec<IndexedReference>>();
+
+        let kmer_count = cursor.u64();
+        let kmers = cursor.take(kmer_count * (kmer_size + KMER_RECORD_TRAILER));
+
+        // one pass to check the records we'll search in place, and to see which references have a forward k-mer
+        let kmer_table = KmerTable::Sorted { records: &mmap[kmers.0..(kmers.0 + kmers.1)], kmer_size };
+        let mut previous: Option<&[u8]> = None;
+        let mut with_forward_kmers = HashSet::new();
+        for (kmer, (reference, forward)) in kmer_table.iter() {
+            assert!(reference < reference_count, "Reference index {} has a k-mer for an unknown reference", path.display());
+            assert!(previous.map_or(true, |previous| previous < kmer), "Reference index {} has unsorted k-mers; please rebuild it with the index command", path.display());
+            if forward {
+                with_forward_kmers.insert(reference);
+            }
+            previous = Some(kmer);
+        }
+        let all_have_unique_mappings = with_forward_kmers.len() == reference_count;
+
+        ReferenceIndex { mmap, kmer_size, kmer_spacing, references, kmers, all_have_unique_mappings }
+    }
+
+    fn bytes(&self, (start, length): (usize, usize)) -> &[u8] {
+        &self.mmap[start..(start + length)]
+    }
+
+    fn suffix_table(&self, reference: &IndexedReference) -> SuffixTable<'_, '_> {
+        let text = std::str::from_utf8(self.bytes(reference.sequence)).expect("Reference index sequences must be valid text");
+        let table_bytes = &self.mmap[reference.table_offset..(reference.table_offset + reference.table_length * 4)];
+
+        // the mapping is page-aligned and tables start on four-byte boundaries, so on little-endian machines we can use
+        // the table in place; otherwise we decode a copy
+        let table: Cow<[u32]> = if cfg!(target_endian = "little") && (table_bytes.as_ptr() as usize) % std::mem::align_of::<u32>() == 0 {
+            // SAFETY: the bytes are aligned for u32, in bounds, live as long as the mapping, and stored little-endian
+            Cow::Borrowed(unsafe { std::slice::from_raw_parts(table_bytes.as_ptr() as *const u32, reference.table_length) })
+        } else {
+            Cow::Owned(table_bytes.chunks_exact(4).map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap())).collect())
+        };
+        SuffixTable::from_parts(Cow::Borrowed(text), table)
+    }
+
+    /// Build a `ReferenceManager` over the index; the suffix tables borrow from the mapped file
+    pub fn reference_manager(&self) -> ReferenceManager<'_, '_, '_> {
+        let references = self.references.iter().map(|reference| {
+            let sequence_u8 = self.bytes(reference.sequence).to_vec();
+            Reference {
+                sequence: FastaBase::from_vec_u8_default_ns(&sequence_u8),
+                sequence_u8,
+                name: self.bytes(reference.name).to_vec(),
+                suffix_table: SuffixTableLookup { suffix_table: self.suffix_table(reference), seed_size: reference.seed_size },
+            }
+        }).collect::<Vec<Reference>>();
+
+        let kmer_to_reference = KmerTable::Sorted { records: self.bytes(self.kmers), kmer_size: self.kmer_size };
+        let unique_kmers = UniqueKmerLookup::new(kmer_to_reference, self.all_have_unique_mappings);
+        ReferenceManager::from_parts(references, unique_kmers, self.kmer_size, self.kmer_spacing)
+    }
+
+    /// Build a `ReferenceManager` over the index, checking that the index holds exactly the layout's references with the
+    /// same sequences, so a stale index can't silently change our results
+    pub fn reference_manager_for_layout(&self, layout: &SequenceLayout) -> ReferenceManager<'_, '_, '_> {
+        let indexed = self.references.iter().map(|reference| (self.bytes(reference.name), self.bytes(reference.sequence))).collect::<HashMap<&[u8], &[u8]>>();
+        layout.references.iter().for_each(|(name, reference)| {
+            match indexed.get(name.as_bytes()) {
+                None => panic!("Reference {} isn't in the reference index; please rebuild it with the index command", name),
+                Some(sequence) => assert_eq!(*sequence, reference.sequence.as_bytes(), "Reference {} has a different sequence in the reference index; please rebuild it with the index command", name),
+            }
+        });
+        // reads can be assigned to any reference in the index, so extra references would change our results too
+        indexed.keys().for_each(|name| {
+            assert!(layout.references.contains_key(&*String::from_utf8_lossy(name)),
+                    "The reference index has reference {}, which isn't in the layout; the index and reference sets differ, please rebuild it with the index command",
+                    String::from_utf8_lossy(name));
+        });
+        self.reference_manager()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_index_round_trip() {
+        let ref_location = &"test_data/test_best_alignment.fasta".to_string();
+        let rm = ReferenceManager::from_fa_file(&ref_location, 8, 4);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let index_file = temp_dir.path().join("references.idx");
+        write_reference_index(&rm, &index_file);
+
+        let index = ReferenceIndex::open(&index_file);
+        let loaded = index.reference_manager();
+
+        assert_eq!(loaded.kmer_size, rm.kmer_size);
+        assert_eq!(loaded.kmer_skip, rm.kmer_skip);
+        assert_eq!(loaded.longest_ref, rm.longest_ref);
+        assert_eq!(loaded.references, rm.references);
+        assert_eq!(loaded.reference_name_to_ref, rm.reference_name_to_ref);
+        assert_eq!(loaded.unique_kmers.kmer_to_reference.len(), rm.unique_kmers.kmer_to_reference.len());
+        rm.unique_kmers.kmer_to_reference.iter().for_each(|(kmer, hit)| assert_eq!(loaded.unique_kmers.kmer_to_reference.get(kmer), Some(hit)));
+        assert_eq!(loaded.unique_kmers.kmer_to_reference.get(b"NNNNNNNN"), None);
+        assert_eq!(loaded.unique_kmers.all_have_unique_mappings, rm.unique_kmers.all_have_unique_mappings);
+
+        // and the mapped suffix tables still find our seeds
+        let reference = loaded.references.get(&0).unwrap();
+        assert_eq!(reference.suffix_table.suffix_table.positions("GGGGTTAGAGCTAGAAATAGC"), rm.references.get(&0).unwrap().suffix_table.suffix_table.positions("GGGGTTAGAGCTAGAAATAGC"));
+    }
+
+    #[test]
+    fn test_reference_index_for_layout() {
+        let layout = SequenceLayout::from_yaml_and_reference_fasta(&String::from("test_data/test_layout_fasta.yaml"), &Some("test_data/test_best_alignment.fasta".to_string()));
+        let rm = ReferenceManager::from_yaml_input(&layout, 8, 4);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let index_file = temp_dir.path().join("references.idx");
+        write_reference_index(&rm, &index_file);
+
+        let index = ReferenceIndex::open(&index_file);
+        assert_eq!(index.reference_manager_for_layout(&layout).references.len(), layout.references.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't in the layout")]
+    fn test_reference_index_with_extra_references() {
+        let layout = SequenceLayout::from_yaml_and_reference_fasta(&String::from("test_data/test_layout_fasta.yaml"), &Some("test_data/test_best_alignment.fasta".to_string()));
+        let layout_rm = ReferenceManager::from_yaml_input(&layout, 8, 4);
+        let extra_location = &"test_data/two_references.fa".to_string();
+        let mut references = (0..layout_rm.references.len()).map(|i| layout_rm.references.get(&i).unwrap().clone()).collect::<Vec<Reference>>();
+        references.extend(crate::reference::fasta_reference::reference_file_to_structs(extra_location, 8));
+        let rm = ReferenceManager::from_fasta_vec(references, 8, 4);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let index_file = temp_dir.path().join("references.idx");
+        write_reference_index(&rm, &index_file);
+
+        ReferenceIndex::open(&index_file).reference_manager_for_layout(&layout);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_reference_index_wrong_version() {
+        let ref_location = &"test_data/two_references.fa".to_string();
+        let rm = ReferenceManager::from_fa_file(&ref_location, 8, 4);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let index_file = temp_dir.path().join("references.idx");
+        write_reference_index(&rm, &index_file);
+
+        let mut contents = std::fs::read(&index_file).unwrap();
+        contents[REFERENCE_INDEX_MAGIC.len()..(REFERENCE_INDEX_MAGIC.len() + 4)].copy_from_slice(&(REFERENCE_INDEX_VERSION + 1).to_le_bytes());
+        std::fs::write(&index_file, contents).unwrap();
+
+        ReferenceIndex::open(&index_file);
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::sync::RwLock;
 
 use log::info;
 
@@ -42,7 +43,9 @@ impl MetricSpace for FastaString {
 pub struct KnownList {
     vantage_tree: Tree<FastaString>,
     //, (), Owned<HashMap<FastaString,bool>>>,
-    exact_matches: HashMap<FastaString, BestF32Hits>,
+    /// the known list itself, plus each barcode we've already corrected. Lookups only take the lock to read or insert, so
+    /// several threads can correct barcodes against the same list
+    exact_matches: RwLock<HashMap<FastaString, BestF32Hits>>,
     input_list: Vec<FastaString>,
 }
 
@@ -68,7 +71,7 @@ impl KnownList {
 
         KnownList {
             vantage_tree,
-            exact_matches,
+            exact_matches: RwLock::new(exact_matches),
             input_list,
         }
     }
@@ -87,32 +90,30 @@ impl KnownList {
     }
 
     pub fn correct_to_known_list(
-        &mut self,
+        &self,
         barcode: &Vec<FastaBase>,
         max_distance: &u32,
     ) -> BestF32Hits {
         let string_rep = FastaString::new(barcode.clone());
 
-        match self.exact_matches.get(&string_rep) {
-            None => {
-                let nearest = self.vantage_tree.find_nearest_custom(
-                    &string_rep,
-                    &(),
-                    RadiusBasedNeighborhood::new(*max_distance),
-                );
-
-                let ret = BestF32Hits {
-                    hits: nearest.iter().map(|(id, _dist)| self.input_list.get(*id as usize).unwrap().fa.clone()).collect(),
-                    distance: nearest.iter().map(|(_id, dist)| *dist).next().unwrap_or(max_distance + 1),
-                };
-
-                self.exact_matches.insert(string_rep.clone(), ret.clone());
-                ret
-            }
-            Some(x) => {
-                x.clone()
-            }
+        if let Some(x) = self.exact_matches.read().unwrap().get(&string_rep) {
+            return x.clone();
         }
+
+        // search the tree without holding the lock
+        let nearest = self.vantage_tree.find_nearest_custom(
+            &string_rep,
+            &(),
+            RadiusBasedNeighborhood::new(*max_distance),
+        );
+
+        let ret = BestF32Hits {
+            hits: nearest.iter().map(|(id, _dist)| self.input_list.get(*id as usize).unwrap().fa.clone()).collect(),
+            distance: nearest.iter().map(|(_id, dist)| *dist).next().unwrap_or(max_distance + 1),
+        };
+
+        self.exact_matches.write().unwrap().insert(string_rep, ret.clone());
+        ret
     }
 }
 
@@ -153,7 +154,7 @@ mod tests {
         };
 
         println!("Creating vantage point tree");
-        let mut known_lookup = KnownList::new(&known_5p_list, &8);
+        let known_lookup = KnownList::new(&known_5p_list, &8);
         let file = File::open("test_data/subset_barcode_list_500.txt".to_string()).unwrap();
         let reader = BufReader::new(file);
 